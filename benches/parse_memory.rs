@@ -0,0 +1,87 @@
+//! Compares the peak memory of the server parsing a large generated file
+//! with `ParseMany`, once read whole into `code` and once streamed, with
+//! `stream` set. Reads the peak resident set from `/proc`, so Linux only.
+//!
+//! Run with `cargo bench --bench parse_memory`.
+
+use std::{
+    env, fs,
+    io::{BufRead, BufReader, Read, Write},
+    path::Path,
+    process::{ChildStdout, Command, Stdio},
+};
+
+fn main() {
+    let dir = env::temp_dir().join(format!("ast-rs-bench-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("generated.py");
+    fs::write(&path, "def f(x):\n    return x + 1\n\n".repeat(500_000)).unwrap();
+    let size = fs::metadata(&path).unwrap().len();
+    println!("{} KiB of Python", size / 1024);
+    for stream in [false, true] {
+        let peak = peak_memory(&dir, &path, stream);
+        println!("stream: {stream:<5}  server peak resident set: {peak} KiB");
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// The server's peak resident set in KiB, after parsing the file at `path`.
+fn peak_memory(dir: &Path, path: &Path, stream: bool) -> u64 {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_ast-rs"))
+        .env("AST_RS_ALLOWED_PATHS", dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = server.stdin.take().unwrap();
+    let mut stdout = BufReader::new(server.stdout.take().unwrap());
+
+    let request = serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "ParseMany",
+        "params": {
+            "files": [{ "id": "generated", "language": "python", "filePath": path }],
+            "stream": stream,
+        },
+    })
+    .to_string();
+    write!(stdin, "Content-Length: {}\r\n\r\n{request}", request.len()).unwrap();
+    stdin.flush().unwrap();
+    loop {
+        let response = read_message(&mut stdout);
+        if response["id"] == 1 {
+            assert!(response.get("error").is_none(), "{response}");
+            break;
+        }
+    }
+
+    let status = fs::read_to_string(format!("/proc/{}/status", server.id())).unwrap();
+    let peak = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+        .unwrap();
+    server.kill().unwrap();
+    server.wait().unwrap();
+    peak
+}
+
+fn read_message(stdout: &mut BufReader<ChildStdout>) -> serde_json::Value {
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        stdout.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length: ") {
+            size = value.parse().unwrap();
+        }
+    }
+    let mut body = vec![0; size];
+    stdout.read_exact(&mut body).unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
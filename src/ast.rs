@@ -0,0 +1,849 @@
+use std::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+use tree_sitter::{Node, Point};
+
+use crate::describe::schema;
+use crate::handlers::error;
+use crate::hash::{Fnv1a128, Fnv1a64};
+use crate::msg::ResponseError;
+use crate::position::Position;
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AstBlock {
+    /// The node's sexp, left out in ranges-only mode.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ast_result: Option<String>,
+    pub start_point: Position,
+    pub end_point: Position,
+    /// Whether the node is an extra, such as a comment, that the grammar
+    /// allows anywhere rather than as part of the structure.
+    pub is_extra: bool,
+    /// Whether the node spans no bytes, as the `MISSING` nodes error recovery
+    /// inserts for absent tokens do.
+    pub zero_width: bool,
+    /// Whether the subtree rooted at the node contains an `ERROR` or
+    /// `MISSING` node, which a node of a valid kind still can.
+    pub has_error: bool,
+    /// Hash of the node's kind and tokens, see `content_hash`. Only filled in
+    /// when the client asks for it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<u64>,
+    /// Size of the subtree, see `descendant_count`. Only filled in when the
+    /// client asks for it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub descendant_count: Option<usize>,
+    /// Direct children, anonymous ones included, so tree views can tell a
+    /// leaf without asking for its children. Filled in with
+    /// `descendant_count`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub child_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub named_child_count: Option<usize>,
+}
+
+schema!(result AstBlock {
+    ast_result?, start_point, end_point, is_extra, zero_width, has_error, content_hash?,
+    descendant_count?, child_count?, named_child_count?
+});
+
+/// A node produced by error recovery, either an `ERROR` node wrapping
+/// unparseable input or a zero-width `MISSING` node the parser inserted.
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorRange {
+    /// `ERROR`, or the kind of the missing token.
+    pub kind: String,
+    pub is_missing: bool,
+    pub start_point: Position,
+    pub end_point: Position,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Comment {
+    pub text: String,
+    pub start_point: Position,
+    pub end_point: Position,
+}
+
+schema!(result Comment { text, start_point, end_point });
+
+/// A named node and its named descendants.
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AstNode {
+    pub kind: String,
+    /// The field this node fills in its parent, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field_name: Option<String>,
+    pub start_point: Position,
+    pub end_point: Position,
+    pub children: Vec<AstNode>,
+}
+
+schema!(result AstNode { kind, field_name?, start_point, end_point, children });
+
+/// Which nodes the tree-building methods return, by kind.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KindFilter {
+    /// When non-empty, only nodes of these kinds are returned. The nodes
+    /// kept below any other node take its place in its parent.
+    #[serde(default)]
+    pub include_kinds: Vec<String>,
+    /// Nodes of these kinds are left out along with their descendants.
+    #[serde(default)]
+    pub exclude_kinds: Vec<String>,
+}
+
+schema!(flattened KindFilter { include_kinds?, exclude_kinds? });
+
+impl KindFilter {
+    fn excludes(&self, kind: &str) -> bool {
+        self.exclude_kinds.iter().any(|excluded| excluded == kind)
+    }
+
+    fn includes(&self, kind: &str) -> bool {
+        self.include_kinds.is_empty() || self.include_kinds.iter().any(|included| included == kind)
+    }
+
+    /// Logs a warning for every kind `language` has no node of, which most
+    /// likely is a typo that silently filters nothing.
+    pub fn warn_unknown(&self, language: tree_sitter::Language) {
+        for kind in self.include_kinds.iter().chain(&self.exclude_kinds) {
+            let known = language.id_for_node_kind(kind, true) != 0
+                || language.id_for_node_kind(kind, false) != 0;
+            if !known {
+                log::warn!("ignoring unknown node kind in kind filter: {kind}");
+            }
+        }
+    }
+}
+
+/// A single token, such as a delimiter.
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Token {
+    pub kind: String,
+    pub start_point: Position,
+    pub end_point: Position,
+}
+
+/// One node of a `Disassemble` dump.
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DisassembleEntry {
+    pub depth: usize,
+    pub kind: String,
+    /// The source the node spans.
+    pub text: String,
+}
+
+schema!(result DisassembleEntry { depth, kind, text });
+
+/// The source `node` spans. Fails rather than returning garbage if the
+/// node's range doesn't fall on character boundaries, which valid input
+/// never causes but odd edits can.
+pub fn node_text<'a>(node: Node, source: &'a [u8]) -> Result<&'a str, ResponseError> {
+    node.utf8_text(source).map_err(|_| {
+        error(&format!(
+            "node text not valid UTF-8 at bytes {}..{}",
+            node.start_byte(),
+            node.end_byte()
+        ))
+    })
+}
+
+pub fn format_token(node: Node) -> Token {
+    Token {
+        kind: node.kind().to_string(),
+        start_point: node.start_position().into(),
+        end_point: node.end_position().into(),
+    }
+}
+
+pub fn format_node(node: Node) -> AstBlock {
+    AstBlock { ast_result: Some(node.to_sexp()), ..format_range(node) }
+}
+
+/// Like `format_node`, without serializing the sexp, for clients that only
+/// need the structure.
+pub fn format_range(node: Node) -> AstBlock {
+    AstBlock {
+        ast_result: None,
+        start_point: node.start_position().into(),
+        end_point: node.end_position().into(),
+        is_extra: node.is_extra(),
+        zero_width: node.start_byte() == node.end_byte(),
+        has_error: node.has_error(),
+        content_hash: None,
+        descendant_count: None,
+        child_count: None,
+        named_child_count: None,
+    }
+}
+
+/// A 128-bit FNV-1a hash of the shape of the tree under `root`: the kind and
+/// depth of every node in preorder, plus the field each fills if
+/// `include_field_names` is set.
+///
+/// Token text doesn't count, and neither do comments or other extras, so
+/// reformatting or recommenting leaves the fingerprint unchanged.
+pub fn fingerprint(root: Node, include_field_names: bool) -> u128 {
+    let mut hash = Fnv1a128::new();
+    // A separator keeps `ab` `c` and `a` `bc` apart.
+    let mut write = |bytes: &[u8]| {
+        hash.write(bytes);
+        hash.write(&[0]);
+    };
+    let mut cursor = root.walk();
+    let mut depth: usize = 0;
+    loop {
+        let node = cursor.node();
+        let counts = !node.is_extra();
+        if counts {
+            write(&depth.to_le_bytes());
+            if include_field_names {
+                write(cursor.field_name().unwrap_or("").as_bytes());
+            }
+            write(node.kind().as_bytes());
+        }
+        if counts && cursor.goto_first_child() {
+            depth += 1;
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                return hash.finish();
+            }
+            depth -= 1;
+        }
+    }
+}
+
+/// The number of nodes in the subtree rooted at `node`, itself included.
+pub fn descendant_count(node: Node) -> usize {
+    let mut count = 0;
+    walk_tree(node, |_, _| count += 1);
+    count
+}
+
+/// A 64-bit FNV-1a hash of the node's kind and the text of its leaf tokens.
+///
+/// Whitespace between tokens isn't part of any token, so reindenting or
+/// rewrapping a subtree keeps its hash, while any change to a token does not.
+pub fn content_hash(node: Node, source: &[u8]) -> u64 {
+    let mut hash = Fnv1a64::new();
+    let mut feed = |bytes: &[u8]| {
+        hash.write(bytes);
+        // A separator keeps `ab` `c` and `a` `bc` apart.
+        hash.write(&[0]);
+    };
+    feed(node.kind().as_bytes());
+    walk_tree(node, |node, _| {
+        if node.child_count() == 0 {
+            // Tokens past the end of a `source` the tree wasn't parsed from
+            // are left out rather than panicking.
+            if let Some(text) = source.get(node.byte_range()) {
+                feed(text);
+            }
+        }
+    });
+    hash.finish()
+}
+
+/// Builds the tree of named nodes rooted at `root` that `filter` keeps,
+/// mapping every point through `to_position`. Fails if the tree is deeper
+/// than `max_depth`.
+pub fn build_tree(
+    root: Node,
+    to_position: &dyn Fn(Point) -> Position,
+    max_depth: usize,
+    filter: &KindFilter,
+) -> Result<AstNode, ResponseError> {
+    let new_node = |node: Node, field_name: Option<&str>| AstNode {
+        kind: node.kind().to_string(),
+        field_name: field_name.map(str::to_string),
+        start_point: to_position(node.start_position()),
+        end_point: to_position(node.end_position()),
+        children: Vec::new(),
+    };
+    // The nodes from the root down to the one being built, each with the
+    // children still to visit.
+    let mut stack = vec![(new_node(root, None), kept_children(root, filter).into_iter())];
+    loop {
+        let (_, pending) = stack.last_mut().unwrap();
+        match pending.next() {
+            Some((child, field_name)) => {
+                if stack.len() > max_depth {
+                    return Err(depth_exceeded(max_depth));
+                }
+                let grandchildren = kept_children(child, filter).into_iter();
+                stack.push((new_node(child, field_name), grandchildren));
+            }
+            None => {
+                let (done, _) = stack.pop().unwrap();
+                match stack.last_mut() {
+                    Some((parent, _)) => parent.children.push(done),
+                    None => return Ok(done),
+                }
+            }
+        }
+    }
+}
+
+/// The named descendants of `node` that `filter` keeps without keeping a
+/// node in between, in order. Only direct children keep their field, since
+/// a field names a slot in the node's actual parent.
+fn kept_children<'tree>(
+    node: Node<'tree>,
+    filter: &KindFilter,
+) -> Vec<(Node<'tree>, Option<&'static str>)> {
+    let mut kept = Vec::new();
+    // Walked depth-first, so the next node to visit is at the end.
+    let mut pending = children(node, Node::is_named);
+    pending.reverse();
+    while let Some((child, field_name)) = pending.pop() {
+        if filter.excludes(child.kind()) {
+            continue;
+        }
+        if filter.includes(child.kind()) {
+            kept.push((child, field_name));
+        } else {
+            let grandchildren = children(child, Node::is_named).into_iter().rev();
+            pending.extend(grandchildren.map(|(grandchild, _)| (grandchild, None)));
+        }
+    }
+    kept
+}
+
+/// The children of `node` that satisfy `keep`, with the field each fills.
+fn children<'tree>(
+    node: Node<'tree>,
+    keep: impl Fn(&Node<'tree>) -> bool,
+) -> Vec<(Node<'tree>, Option<&'static str>)> {
+    let mut children = Vec::new();
+    let mut cursor = node.walk();
+    if cursor.goto_first_child() {
+        loop {
+            let child = cursor.node();
+            if keep(&child) {
+                children.push((child, cursor.field_name()));
+            }
+            if !cursor.goto_next_sibling() {
+                break;
+            }
+        }
+    }
+    children
+}
+
+fn depth_exceeded(max_depth: usize) -> ResponseError {
+    error(&format!("tree is nested deeper than the maximum depth of {max_depth}"))
+}
+
+/// Collects every `ERROR` and `MISSING` node below `root` in document order.
+///
+/// Subtrees without `has_error` are skipped, so a clean tree costs a single
+/// check on the root.
+pub fn collect_errors(root: Node) -> Vec<ErrorRange> {
+    error_nodes(root)
+        .into_iter()
+        .map(|node| ErrorRange {
+            kind: node.kind().to_string(),
+            is_missing: node.is_missing(),
+            start_point: node.start_position().into(),
+            end_point: node.end_position().into(),
+        })
+        .collect()
+}
+
+/// The nodes `collect_errors` reports, in the same order.
+pub fn error_nodes<'tree>(root: Node<'tree>) -> Vec<Node<'tree>> {
+    let mut errors = Vec::new();
+    let mut cursor = root.walk();
+    loop {
+        let node = cursor.node();
+        if node.is_error() || node.is_missing() {
+            errors.push(node);
+        }
+        if node.has_error() && cursor.goto_first_child() {
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                return errors;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SexpOptions<'a> {
+    /// Append the `[row,col]-[row,col]` range to `ERROR` and `MISSING` nodes.
+    pub mark_errors: bool,
+    /// Append the range to every node, so clients get the structure and the
+    /// positions in one string.
+    pub with_positions: bool,
+    /// Write the quoted source text of literal nodes.
+    pub literals: Option<SexpLiterals<'a>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SexpLiterals<'a> {
+    /// The kinds to write text for, usually `LanguageInfo::literal_kinds`.
+    pub kinds: &'a [&'a str],
+    pub source: &'a [u8],
+}
+
+impl SexpOptions<'_> {
+    fn is_literal(&self, node: &Node) -> bool {
+        self.literals.as_ref().is_some_and(|literals| literals.kinds.contains(&node.kind()))
+    }
+}
+
+/// Serializes `root` in the same shape as `Node::to_sexp`, with the
+/// annotations selected by `options`. Fails if the tree is deeper than
+/// `max_depth`.
+///
+/// Literals written with their text stand for their whole subtree, so e.g.
+/// a Python string's `string_content` child is left out.
+pub fn write_sexp<'tree>(
+    root: Node<'tree>,
+    options: &SexpOptions,
+    max_depth: usize,
+) -> Result<String, ResponseError> {
+    let mut out = String::new();
+    write_sexp_open(root, None, options, &mut out);
+    let sexp_children = |node: Node<'tree>| {
+        if options.is_literal(&node) {
+            Vec::new()
+        } else {
+            children(node, is_sexp_child)
+        }
+    };
+    // The children still to write for every open node.
+    let mut stack = vec![sexp_children(root).into_iter()];
+    while let Some(pending) = stack.last_mut() {
+        match pending.next() {
+            Some((child, field_name)) => {
+                if stack.len() > max_depth {
+                    return Err(depth_exceeded(max_depth));
+                }
+                write_sexp_open(child, field_name, options, &mut out);
+                stack.push(sexp_children(child).into_iter());
+            }
+            None => {
+                out.push(')');
+                stack.pop();
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Writes the sexp of the nodes under `root` that `filter` keeps, nested as
+/// in the tree, with every other node collapsed into its parent. Fails if
+/// the kept nodes nest deeper than `max_depth`.
+pub fn write_pruned_sexp(
+    root: Node,
+    filter: &KindFilter,
+    max_depth: usize,
+) -> Result<String, ResponseError> {
+    let options = SexpOptions::default();
+    let mut out = String::new();
+    write_sexp_open(root, None, &options, &mut out);
+    let mut stack = vec![kept_children(root, filter).into_iter()];
+    while let Some(pending) = stack.last_mut() {
+        match pending.next() {
+            Some((child, field_name)) => {
+                if stack.len() > max_depth {
+                    return Err(depth_exceeded(max_depth));
+                }
+                write_sexp_open(child, field_name, &options, &mut out);
+                stack.push(kept_children(child, filter).into_iter());
+            }
+            None => {
+                out.push(')');
+                stack.pop();
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Writes the tree under `root` as a Graphviz DOT digraph, one vertex per
+/// node labeled by its kind and one edge per parent and child, labeled by
+/// the child's field when `field_names` is set. Anonymous tokens are drawn
+/// without a border, or left out with `named_only`.
+pub fn write_dot(root: Node, named_only: bool, field_names: bool) -> String {
+    let mut out = String::from("digraph ast {\n");
+    let mut cursor = root.walk();
+    // The ids of the nodes from the root down to the current one's parent.
+    let mut parents: Vec<usize> = Vec::new();
+    let mut next_id = 0;
+    loop {
+        let node = cursor.node();
+        if node.is_named() || !named_only {
+            let id = next_id;
+            next_id += 1;
+            let shape = if node.is_named() { "" } else { ", shape=plaintext" };
+            writeln!(out, "  n{id} [label={:?}{shape}];", node.kind()).unwrap();
+            if let Some(parent) = parents.last() {
+                match cursor.field_name().filter(|_| field_names) {
+                    Some(field_name) => {
+                        writeln!(out, "  n{parent} -> n{id} [label={field_name:?}];").unwrap()
+                    }
+                    None => writeln!(out, "  n{parent} -> n{id};").unwrap(),
+                }
+            }
+            if cursor.goto_first_child() {
+                parents.push(id);
+                continue;
+            }
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                out.push_str("}\n");
+                return out;
+            }
+            parents.pop();
+        }
+    }
+}
+
+/// Whether `node` appears in sexps, which leave out anonymous tokens unless
+/// error recovery inserted them.
+fn is_sexp_child(node: &Node) -> bool {
+    node.is_named() || node.is_missing()
+}
+
+/// Writes everything of `node` up to its children.
+fn write_sexp_open(node: Node, field_name: Option<&str>, options: &SexpOptions, out: &mut String) {
+    if !out.is_empty() {
+        out.push(' ');
+    }
+    if let Some(field_name) = field_name {
+        write!(out, "{field_name}: ").unwrap();
+    }
+    if node.is_missing() {
+        if node.is_named() {
+            write!(out, "(MISSING {}", node.kind()).unwrap();
+        } else {
+            write!(out, "(MISSING {:?}", node.kind()).unwrap();
+        }
+    } else {
+        write!(out, "({}", node.kind()).unwrap();
+    }
+    if let Some(literals) = &options.literals {
+        if literals.kinds.contains(&node.kind()) {
+            let text = String::from_utf8_lossy(&literals.source[node.byte_range()]);
+            write!(out, " {text:?}").unwrap();
+        }
+    }
+    let is_error = node.is_error() || node.is_missing();
+    if options.with_positions || (options.mark_errors && is_error) {
+        let start = node.start_position();
+        let end = node.end_position();
+        write!(out, " [{},{}]-[{},{}]", start.row, start.column, end.row, end.column).unwrap();
+    }
+}
+
+/// Calls `f` with every node below and including `root` in document order,
+/// along with its depth relative to `root`.
+pub fn walk_tree<'tree>(root: Node<'tree>, mut f: impl FnMut(Node<'tree>, usize)) {
+    let mut cursor = root.walk();
+    let mut depth = 0;
+    loop {
+        f(cursor.node(), depth);
+
+        if cursor.goto_first_child() {
+            depth += 1;
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                return;
+            }
+            depth -= 1;
+        }
+    }
+}
+
+/// Whether `node` is an identifier-like token (`identifier`,
+/// `field_identifier`, `type_identifier`, ...).
+pub fn is_identifier(node: Node) -> bool {
+    node.is_named() && node.child_count() == 0 && node.kind().contains("identifier")
+}
+
+/// Whether `node` is a keyword such as `if` or `return`: an anonymous leaf
+/// whose token is a word rather than punctuation.
+pub fn is_keyword(node: Node) -> bool {
+    let kind = node.kind();
+    !node.is_named()
+        && node.child_count() == 0
+        && kind.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && kind.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Finds the delimiter pairing with `bracket` among its siblings, skipping
+/// nested pairs of the same kind. `bracket` must be either `open` or `close`.
+pub fn matching_bracket<'tree>(
+    bracket: Node<'tree>,
+    open: &str,
+    close: &str,
+) -> Option<Node<'tree>> {
+    let forward = bracket.kind() == open;
+    let (nest, unnest) = if forward { (open, close) } else { (close, open) };
+    let step = |node: Node<'tree>| if forward { node.next_sibling() } else { node.prev_sibling() };
+    let mut depth = 0;
+    let mut sibling = step(bracket);
+    while let Some(node) = sibling {
+        if node.kind() == nest {
+            depth += 1;
+        } else if node.kind() == unnest {
+            if depth == 0 {
+                return Some(node);
+            }
+            depth -= 1;
+        }
+        sibling = step(node);
+    }
+    None
+}
+
+/// Lists every node below `root` in document order, paired with the source it
+/// spans. Text longer than `max_text_len` characters is cut off with `...`.
+pub fn disassemble(
+    root: Node,
+    source: &[u8],
+    max_text_len: Option<usize>,
+) -> Result<Vec<DisassembleEntry>, ResponseError> {
+    let mut nodes = Vec::new();
+    walk_tree(root, |node, depth| nodes.push((node, depth)));
+    nodes
+        .into_iter()
+        .map(|(node, depth)| {
+            let text = node_text(node, source)?;
+            let text = match max_text_len {
+                Some(max) if text.chars().count() > max => {
+                    format!("{}...", text.chars().take(max).collect::<String>())
+                }
+                _ => text.to_string(),
+            };
+            Ok(DisassembleEntry { depth, kind: node.kind().to_string(), text })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tree_sitter::{Parser, Tree};
+
+    use super::*;
+    use crate::language::Language;
+
+    fn parse_as(language: Language, code: &str) -> Tree {
+        let mut parser = Parser::new();
+        parser.set_language(language.to_tree_sitter()).unwrap();
+        parser.parse(code, None).unwrap()
+    }
+
+    fn parse(code: &str) -> Tree {
+        parse_as(Language::JavaScript, code)
+    }
+
+    fn hash(code: &str) -> u64 {
+        content_hash(parse(code).root_node(), code.as_bytes())
+    }
+
+    #[test]
+    fn content_hash_ignores_whitespace_between_tokens() {
+        assert_eq!(hash("if (a) { b(); }"), hash("if (a) {\n    b();\n}"));
+    }
+
+    #[test]
+    fn content_hash_changes_with_any_token() {
+        assert_ne!(hash("if (a) { b(); }"), hash("if (a) { c(); }"));
+        assert_ne!(hash("ab + c"), hash("a + bc"));
+    }
+
+    #[test]
+    fn content_hash_skips_tokens_past_the_end_of_the_source() {
+        let tree = parse("let a = 1;");
+        content_hash(tree.root_node(), b"let");
+    }
+
+    fn disassembled(code: &str, max_text_len: Option<usize>) -> Vec<(usize, String, String)> {
+        disassemble(parse(code).root_node(), code.as_bytes(), max_text_len)
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.depth, entry.kind, entry.text))
+            .collect()
+    }
+
+    #[test]
+    fn disassemble_pairs_every_node_with_its_source() {
+        let entries = disassembled("a;", None);
+        let expected = [
+            (0, "program", "a;"),
+            (1, "expression_statement", "a;"),
+            (2, "identifier", "a"),
+            (2, ";", ";"),
+        ];
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(depth, kind, text)| (depth, kind.to_string(), text.to_string()))
+            .collect();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn disassemble_truncates_text_past_max_text_len() {
+        let entries = disassembled("abc;", Some(2));
+        assert_eq!(entries[0].2, "ab...");
+        assert_eq!(entries[3].2, ";");
+    }
+
+    #[test]
+    fn format_range_flags_zero_width_missing_nodes() {
+        let tree = parse_as(Language::C, "int x = 1\n");
+        let mut missing = Vec::new();
+        walk_tree(tree.root_node(), |node, _| {
+            if node.is_missing() {
+                missing.push(format_range(node));
+            }
+        });
+        assert_eq!(missing.len(), 1);
+        assert!(missing[0].zero_width && missing[0].start_point == missing[0].end_point);
+        let root = format_range(tree.root_node());
+        assert!(!root.zero_width && root.has_error);
+    }
+
+    #[test]
+    fn node_text_fails_on_a_range_splitting_a_character() {
+        let tree = parse("ab;");
+        let identifier = tree.root_node().descendant_for_byte_range(0, 1).unwrap();
+        assert_eq!(node_text(identifier, b"ab;").unwrap(), "ab");
+        // Source that changed under the tree, leaving `é` cut in half.
+        let err = node_text(identifier, "aé;".as_bytes()).unwrap_err();
+        assert_eq!(err.message, "node text not valid UTF-8 at bytes 0..2");
+    }
+
+    #[test]
+    fn descendant_count_counts_every_node_in_the_subtree() {
+        let tree = parse("a; b(c);");
+        let root = tree.root_node();
+        assert_eq!(descendant_count(root.child(0).unwrap()), 3);
+        // `program`, the 3 nodes above, and `expression_statement`, `call_expression`,
+        // `b`, `arguments`, `(`, `c`, `)` and `;` for the second statement.
+        assert_eq!(descendant_count(root), 12);
+    }
+
+    #[test]
+    fn traversals_fail_on_trees_deeper_than_max_depth() {
+        // The number is 6 levels below `program`.
+        let tree = parse("[[[[1]]]];");
+        let root = tree.root_node();
+        let to_position = |point: Point| Position::from(point);
+        let filter = KindFilter::default();
+        assert!(build_tree(root, &to_position, 6, &filter).is_ok());
+        let err = build_tree(root, &to_position, 5, &filter).unwrap_err();
+        assert_eq!(err.message, "tree is nested deeper than the maximum depth of 5");
+        assert_eq!(write_sexp(root, &SexpOptions::default(), 6).unwrap(), root.to_sexp());
+        assert!(write_sexp(root, &SexpOptions::default(), 5).is_err());
+    }
+
+    #[test]
+    fn with_positions_annotates_every_node_of_the_plain_sexp() {
+        let tree = parse("f(a, 1);");
+        let root = tree.root_node();
+        let options = SexpOptions { with_positions: true, ..SexpOptions::default() };
+        let annotated = write_sexp(root, &options, 100).unwrap();
+        assert!(annotated.starts_with("(program [0,0]-[0,8] (expression_statement [0,0]-[0,8] "));
+        // Dropping every ` [row,col]-[row,col]` leaves the plain sexp.
+        let mut plain = String::new();
+        let mut rest = annotated.as_str();
+        let mut annotations = 0;
+        while let Some(start) = rest.find(" [") {
+            plain.push_str(&rest[..start]);
+            let range = &rest[start..];
+            let dash = range.find("]-[").unwrap();
+            let close = dash + 3 + range[dash + 3..].find(']').unwrap();
+            rest = &range[close + 1..];
+            annotations += 1;
+        }
+        plain.push_str(rest);
+        assert_eq!(plain, root.to_sexp());
+        assert_eq!(annotations, plain.matches('(').count());
+    }
+
+    #[test]
+    fn kind_filters_hoist_included_kinds_and_prune_excluded_ones() {
+        let tree = parse("function f(a) { return g(a); }");
+        let to_position = |point: Point| Position::from(point);
+        let built = |include_kinds: &[&str], exclude_kinds: &[&str]| {
+            let filter = KindFilter {
+                include_kinds: include_kinds.iter().map(|kind| kind.to_string()).collect(),
+                exclude_kinds: exclude_kinds.iter().map(|kind| kind.to_string()).collect(),
+            };
+            build_tree(tree.root_node(), &to_position, 100, &filter).unwrap()
+        };
+        let kinds = |node: &AstNode| -> Vec<String> {
+            node.children.iter().map(|child| child.kind.clone()).collect()
+        };
+
+        let identifiers = built(&["identifier"], &[]);
+        assert_eq!(kinds(&identifiers), vec!["identifier"; 4]);
+        let starts: Vec<_> =
+            identifiers.children.iter().map(|child| child.start_point.character).collect();
+        assert_eq!(starts, [9, 11, 23, 25]);
+        assert!(identifiers.children.iter().all(|child| child.field_name.is_none()));
+
+        let pruned = built(&[], &["statement_block"]);
+        let function = &pruned.children[0];
+        assert_eq!(kinds(function), vec!["identifier", "formal_parameters"]);
+        assert_eq!(function.children[0].field_name.as_deref(), Some("name"));
+    }
+
+    #[test]
+    fn has_error_flags_only_the_subtrees_holding_the_error() {
+        let tree = parse("function f() { if (a) { b = ; } }\nlet c = 1;");
+        let root = tree.root_node();
+        assert!(format_range(root).has_error);
+        let function = root.named_child(0).unwrap();
+        assert!(format_range(function).has_error);
+        assert!(!format_range(function.child_by_field_name("name").unwrap()).has_error);
+        assert!(!format_range(root.named_child(1).unwrap()).has_error);
+    }
+
+    #[test]
+    fn write_dot_links_every_node_to_its_parent() {
+        let dot = write_dot(parse("a;").root_node(), false, false);
+        let expected = concat!(
+            "digraph ast {\n",
+            "  n0 [label=\"program\"];\n",
+            "  n1 [label=\"expression_statement\"];\n",
+            "  n0 -> n1;\n",
+            "  n2 [label=\"identifier\"];\n",
+            "  n1 -> n2;\n",
+            "  n3 [label=\";\", shape=plaintext];\n",
+            "  n1 -> n3;\n",
+            "}\n",
+        );
+        assert_eq!(dot, expected);
+    }
+
+    #[test]
+    fn write_dot_can_skip_tokens_and_label_fields() {
+        let dot = write_dot(parse("f(a);").root_node(), true, true);
+        assert!(!dot.contains("plaintext"));
+        assert!(dot.contains("  n2 [label=\"call_expression\"];\n"));
+        assert!(dot.contains("  n2 -> n3 [label=\"function\"];\n"));
+        assert!(dot.contains("  n2 -> n4 [label=\"arguments\"];\n"));
+        assert!(dot.contains("  n5 [label=\"identifier\"];\n  n4 -> n5;\n"));
+        assert!(dot.ends_with("n4 -> n5;\n}\n"));
+    }
+}
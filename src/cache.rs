@@ -0,0 +1,110 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use tree_sitter::Tree;
+
+use crate::hash::Fnv1a128;
+use crate::language::Language;
+use crate::query::QueryMatch;
+
+/// Identifies `code` parsed as `language`, as 32 lowercase hex digits.
+pub fn fingerprint(language: Language, code: &[u8]) -> String {
+    let mut hash = Fnv1a128::new();
+    hash.write(language.name().as_bytes());
+    hash.write(&[0]);
+    hash.write(code);
+    format!("{:032x}", hash.finish())
+}
+
+/// The most recently parsed trees, keyed by `fingerprint`, so clients that
+/// send the same code again can skip the parse.
+pub struct TreeCache {
+    capacity: usize,
+    /// Oldest first.
+    entries: Mutex<VecDeque<(String, Tree)>>,
+}
+
+impl TreeCache {
+    pub fn new(capacity: usize) -> TreeCache {
+        TreeCache { capacity, entries: Mutex::new(VecDeque::new()) }
+    }
+
+    pub fn get(&self, fingerprint: &str) -> Option<Tree> {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().find(|(key, _)| key == fingerprint).map(|(_, tree)| tree.clone())
+    }
+
+    /// Adds `tree`, evicting the oldest entry if the cache is full.
+    pub fn insert(&self, fingerprint: String, tree: Tree) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|(key, _)| *key != fingerprint);
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back((fingerprint, tree));
+    }
+
+    /// Empties the cache, returning how many trees were dropped.
+    pub fn clear(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let evicted = entries.len();
+        entries.clear();
+        evicted
+    }
+}
+
+/// Identifies a `RunQuery` entry: the client's document URI, the language
+/// and the query source.
+pub type QueryKey = (String, Language, String);
+
+/// What `RunQuery` keeps of its last run on a document, to rerun the query
+/// only where the next version changed.
+pub struct QueryEntry {
+    pub code: String,
+    pub tree: Tree,
+    pub matches: Vec<QueryMatch>,
+}
+
+/// The last `RunQuery` result for the most recently queried documents.
+pub struct QueryCache {
+    capacity: usize,
+    /// Oldest first.
+    entries: Mutex<VecDeque<(QueryKey, QueryEntry)>>,
+}
+
+impl QueryCache {
+    pub fn new(capacity: usize) -> QueryCache {
+        QueryCache { capacity, entries: Mutex::new(VecDeque::new()) }
+    }
+
+    /// Removes and returns the entry for `key`, so the request can update it
+    /// without holding the lock.
+    pub fn take(&self, key: &QueryKey) -> Option<QueryEntry> {
+        let mut entries = self.entries.lock().unwrap();
+        let position = entries.iter().position(|(k, _)| k == key)?;
+        entries.remove(position).map(|(_, entry)| entry)
+    }
+
+    /// Adds `entry`, evicting the oldest entry if the cache is full.
+    pub fn insert(&self, key: QueryKey, entry: QueryEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|(k, _)| *k != key);
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back((key, entry));
+    }
+
+    /// Empties the cache, returning how many entries were dropped.
+    pub fn clear(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let evicted = entries.len();
+        entries.clear();
+        evicted
+    }
+}
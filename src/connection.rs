@@ -1,18 +1,90 @@
 
 use std::{
-    io::{self, BufReader, stdin, stdout},
+    io::{self, BufReader, Read, Write, stdin, stdout},
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     thread,
     fmt,
     net::{TcpListener, TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
 };
-use crossbeam_channel::{bounded, Receiver, Sender};
+use crossbeam_channel::{bounded, never, unbounded, Receiver, RecvTimeoutError, Sender};
+use socket2::{SockRef, TcpKeepalive};
 
-use crate::msg::{Message, Request, Response};
+use crate::msg::{Lifecycle, Message, ReadMode, Request, RequestId, RequestIdGen, Response};
 
 
 pub struct Connection {
     pub sender: Sender<Message>,
     pub receiver: Receiver<Message>,
+    /// Receives an error when the reader or writer thread fails or panics,
+    /// so the main loop can stop instead of waiting on a dead connection.
+    /// Never ready for in-memory connections, which have no such threads.
+    pub errors: Receiver<ProtocolError>,
+    pub compression: OutgoingCompression,
+    /// Messages `request_timeout` received while waiting for a different
+    /// response, see `take_unclaimed`.
+    unclaimed: Mutex<VecDeque<Message>>,
+}
+
+/// Switch for gzip-compressing large outgoing messages, negotiated through
+/// `initialize`. Only socket transports support it; stdio and in-memory
+/// connections always write plain messages.
+#[derive(Debug, Clone, Default)]
+pub struct OutgoingCompression(Option<Arc<AtomicBool>>);
+
+impl OutgoingCompression {
+    fn supported() -> OutgoingCompression {
+        OutgoingCompression(Some(Arc::new(AtomicBool::new(false))))
+    }
+
+    pub fn is_supported(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Turns compression on, returning whether the transport supports it.
+    pub fn enable(&self) -> bool {
+        match &self.0 {
+            Some(enabled) => {
+                enabled.store(true, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        matches!(&self.0, Some(enabled) if enabled.load(Ordering::SeqCst))
+    }
+}
+
+/// Options applied to a tcp stream before the reader and writer threads start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Set `TCP_NODELAY`. Nagle's algorithm holds back small writes, which
+    /// adds latency to every JSON-RPC message in interactive use.
+    pub nodelay: bool,
+    /// Idle time before keepalive probes are sent, or `None` to disable
+    /// keepalive so dead peers are never detected.
+    pub keepalive: Option<Duration>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> SocketOptions {
+        SocketOptions { nodelay: true, keepalive: Some(Duration::from_secs(60)) }
+    }
+}
+
+fn configure_stream(stream: &TcpStream, options: &SocketOptions) -> io::Result<()> {
+    stream.set_nodelay(options.nodelay)?;
+    let socket = SockRef::from(stream);
+    match options.keepalive {
+        Some(time) => socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time)),
+        None => socket.set_keepalive(false),
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -26,6 +98,32 @@ impl fmt::Display for ProtocolError {
     }
 }
 
+/// Reports the failure of the I/O thread it was created in on `errors`.
+///
+/// Create it first thing in the thread, so that on a panic it is dropped,
+/// and reports, before the thread's channel ends are closed.
+struct ThreadMonitor {
+    name: &'static str,
+    errors: Sender<ProtocolError>,
+}
+
+impl ThreadMonitor {
+    fn finish(self, result: io::Result<()>) -> io::Result<()> {
+        if let Err(err) = &result {
+            let _ = self.errors.send(ProtocolError(format!("{} thread failed: {err}", self.name)));
+        }
+        result
+    }
+}
+
+impl Drop for ThreadMonitor {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let _ = self.errors.send(ProtocolError(format!("{} thread panicked", self.name)));
+        }
+    }
+}
+
 pub struct IoThreads {
     reader: thread::JoinHandle<io::Result<()>>,
     writer: thread::JoinHandle<io::Result<()>>,
@@ -47,88 +145,157 @@ impl IoThreads {
                 std::panic::panic_any(err)
             }
         }
-        match self.writer.join() {
-            Ok(r) => r,
-            Err(err) => {
-                println!("writer panicked!");
-                std::panic::panic_any(err);
-            }
+        join_writer(self.writer)
+    }
+
+    /// Waits for the writer thread only, leaving the reader running. For
+    /// when the reader may be blocked on input that never comes; returning
+    /// from `main` ends it with the process.
+    pub fn join_writer(self) -> io::Result<()> {
+        join_writer(self.writer)
+    }
+}
+
+fn join_writer(writer: thread::JoinHandle<io::Result<()>>) -> io::Result<()> {
+    match writer.join() {
+        Ok(r) => r,
+        Err(err) => {
+            println!("writer panicked!");
+            std::panic::panic_any(err);
         }
     }
 }
 
-pub(crate) fn stdio_transport() -> (Sender<Message>, Receiver<Message>, IoThreads) {
+pub(crate) fn stdio_transport(
+    mode: ReadMode,
+) -> (Sender<Message>, Receiver<Message>, Receiver<ProtocolError>, IoThreads) {
+    let (error_sender, error_receiver) = unbounded();
     let (writer_sender, writer_receiver) = bounded::<Message>(0);
+    let writer_errors = error_sender.clone();
     let writer = thread::spawn(move || {
+        let monitor = ThreadMonitor { name: "writer", errors: writer_errors };
         let stdout = stdout();
         let mut stdout = stdout.lock();
-        writer_receiver.into_iter().try_for_each(|it| it.write(&mut stdout))?;
-        Ok(())
+        let result = writer_receiver.into_iter().try_for_each(|it| it.write(&mut stdout));
+        monitor.finish(result)
     });
     let (reader_sender, reader_receiver) = bounded::<Message>(0);
     let reader = thread::spawn(move || {
-        let stdin = stdin();
-        let mut stdin = stdin.lock();
-        while let Some(msg) = Message::read(&mut stdin)? {
-            let is_exit = match &msg {
-                Message::Notification(n) => n.is_exit(),
-                _ => false,
-            };
+        let monitor = ThreadMonitor { name: "reader", errors: error_sender };
+        let result = (|| -> io::Result<()> {
+            let stdin = stdin();
+            let mut stdin = stdin.lock();
+            while let Some(msg) = Message::read_with_mode(&mut stdin, mode)? {
+                let is_exit = match &msg {
+                    Message::Notification(n) => n.is_lifecycle() == Some(Lifecycle::Exit),
+                    _ => false,
+                };
 
-            reader_sender.send(msg).unwrap();
+                reader_sender.send(msg).unwrap();
 
-            if is_exit {
-                break;
+                if is_exit {
+                    break;
+                }
             }
-        }
-        Ok(())
+            Ok(())
+        })();
+        monitor.finish(result)
     });
     let threads = IoThreads { reader, writer };
-    (writer_sender, reader_receiver, threads)
+    (writer_sender, reader_receiver, error_receiver, threads)
 }
 
 pub(crate) fn socket_transport(
     stream: TcpStream,
-) -> (Sender<Message>, Receiver<Message>, IoThreads) {
-    let (reader_receiver, reader) = make_reader(stream.try_clone().unwrap());
-    let (writer_sender, writer) = make_write(stream);
+    mode: ReadMode,
+) -> (Sender<Message>, Receiver<Message>, Receiver<ProtocolError>, IoThreads, OutgoingCompression) {
+    io_transport(stream.try_clone().unwrap(), stream, mode)
+}
+
+pub(crate) fn io_transport<R, W>(
+    reader: R,
+    writer: W,
+    mode: ReadMode,
+) -> (Sender<Message>, Receiver<Message>, Receiver<ProtocolError>, IoThreads, OutgoingCompression)
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    let compression = OutgoingCompression::supported();
+    let (error_sender, error_receiver) = unbounded();
+    let (reader_receiver, reader) = make_reader(reader, mode, error_sender.clone());
+    let (writer_sender, writer) = make_write(writer, compression.clone(), error_sender);
     let io_threads = make_io_threads(reader, writer);
-    (writer_sender, reader_receiver, io_threads)
+    (writer_sender, reader_receiver, error_receiver, io_threads, compression)
 }
 
-fn make_reader(stream: TcpStream) -> (Receiver<Message>, thread::JoinHandle<io::Result<()>>) {
+fn make_reader<R: Read + Send + 'static>(
+    reader: R,
+    mode: ReadMode,
+    errors: Sender<ProtocolError>,
+) -> (Receiver<Message>, thread::JoinHandle<io::Result<()>>) {
     let (reader_sender, reader_receiver) = bounded::<Message>(0);
     let reader = thread::spawn(move || {
-        let mut buf_read = BufReader::new(stream);
-        while let Some(msg) = Message::read(&mut buf_read).unwrap() {
-            let is_exit = matches!(&msg, Message::Notification(n) if n.is_exit());
-            reader_sender.send(msg).unwrap();
-            if is_exit {
-                break;
+        let monitor = ThreadMonitor { name: "reader", errors };
+        let result = (|| -> io::Result<()> {
+            let mut buf_read = BufReader::new(reader);
+            while let Some(msg) = Message::read_with_mode(&mut buf_read, mode)? {
+                let is_exit = match &msg {
+                    Message::Notification(n) => n.is_lifecycle() == Some(Lifecycle::Exit),
+                    _ => false,
+                };
+                reader_sender.send(msg).unwrap();
+                if is_exit {
+                    break;
+                }
             }
-        }
-        Ok(())
+            Ok(())
+        })();
+        monitor.finish(result)
     });
     (reader_receiver, reader)
 }
 
-fn make_write(mut stream: TcpStream) -> (Sender<Message>, thread::JoinHandle<io::Result<()>>) {
+fn make_write<W: Write + Send + 'static>(
+    mut writer: W,
+    compression: OutgoingCompression,
+    errors: Sender<ProtocolError>,
+) -> (Sender<Message>, thread::JoinHandle<io::Result<()>>) {
     let (writer_sender, writer_receiver) = bounded::<Message>(0);
     let writer = thread::spawn(move || {
-        writer_receiver.into_iter().try_for_each(|it| it.write(&mut stream)).unwrap();
-        Ok(())
+        let monitor = ThreadMonitor { name: "writer", errors };
+        let result = writer_receiver
+            .into_iter()
+            .try_for_each(|it| it.write_with_compression(&mut writer, compression.is_enabled()));
+        monitor.finish(result)
     });
     (writer_sender, writer)
 }
 
 
 impl Connection {
+    fn new(
+        sender: Sender<Message>,
+        receiver: Receiver<Message>,
+        errors: Receiver<ProtocolError>,
+        compression: OutgoingCompression,
+    ) -> Connection {
+        Connection { sender, receiver, errors, compression, unclaimed: Mutex::default() }
+    }
+
     /// Create connection over standard in/standard out.
     ///
     /// Use this to create a real language server.
     pub fn stdio() -> (Connection, IoThreads) {
-        let (sender, receiver, io_threads) = stdio_transport();
-        (Connection { sender, receiver }, io_threads)
+        Connection::stdio_with_mode(ReadMode::default())
+    }
+
+    /// Create connection over standard in/standard out, validating incoming
+    /// messages according to `mode`.
+    pub fn stdio_with_mode(mode: ReadMode) -> (Connection, IoThreads) {
+        let (sender, receiver, errors, io_threads) = stdio_transport(mode);
+        let compression = OutgoingCompression::default();
+        (Connection::new(sender, receiver, errors, compression), io_threads)
     }
 
     /// Open a connection over tcp.
@@ -136,9 +303,20 @@ impl Connection {
     ///
     /// Use this to create a real language server.
     pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<(Connection, IoThreads)> {
+        Connection::connect_with_options(addr, SocketOptions::default())
+    }
+
+    /// Open a connection over tcp, configuring the stream with `options`.
+    /// This call blocks until a connection is established.
+    pub fn connect_with_options<A: ToSocketAddrs>(
+        addr: A,
+        options: SocketOptions,
+    ) -> io::Result<(Connection, IoThreads)> {
         let stream = TcpStream::connect(addr)?;
-        let (sender, receiver, io_threads) = socket_transport(stream);
-        Ok((Connection { sender, receiver }, io_threads))
+        configure_stream(&stream, &options)?;
+        let (sender, receiver, errors, io_threads, compression) =
+            socket_transport(stream, ReadMode::default());
+        Ok((Connection::new(sender, receiver, errors, compression), io_threads))
     }
 
     /// Listen for a connection over tcp.
@@ -146,10 +324,36 @@ impl Connection {
     ///
     /// Use this to create a real language server.
     pub fn listen<A: ToSocketAddrs>(addr: A) -> io::Result<(Connection, IoThreads)> {
+        Connection::listen_with_options(addr, SocketOptions::default())
+    }
+
+    /// Listen for a connection over tcp, configuring the accepted stream with
+    /// `options`.
+    /// This call blocks until a connection is established.
+    pub fn listen_with_options<A: ToSocketAddrs>(
+        addr: A,
+        options: SocketOptions,
+    ) -> io::Result<(Connection, IoThreads)> {
         let listener = TcpListener::bind(addr)?;
         let (stream, _) = listener.accept()?;
-        let (sender, receiver, io_threads) = socket_transport(stream);
-        Ok((Connection { sender, receiver }, io_threads))
+        configure_stream(&stream, &options)?;
+        let (sender, receiver, errors, io_threads, compression) =
+            socket_transport(stream, ReadMode::default());
+        Ok((Connection::new(sender, receiver, errors, compression), io_threads))
+    }
+
+    /// Create a connection over an arbitrary pair of streams, such as a pipe
+    /// or an in-process duplex.
+    ///
+    /// Use this to embed the server in another process.
+    pub fn from_io<R, W>(reader: R, writer: W) -> (Connection, IoThreads)
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let (sender, receiver, errors, io_threads, compression) =
+            io_transport(reader, writer, ReadMode::default());
+        (Connection::new(sender, receiver, errors, compression), io_threads)
     }
 
     /// Creates a pair of connected connections.
@@ -158,24 +362,273 @@ impl Connection {
     pub fn memory() -> (Connection, Connection) {
         let (s1, r1) = crossbeam_channel::unbounded();
         let (s2, r2) = crossbeam_channel::unbounded();
-        (Connection { sender: s1, receiver: r2 }, Connection { sender: s2, receiver: r1 })
+        (
+            Connection::new(s1, r2, never(), OutgoingCompression::default()),
+            Connection::new(s2, r1, never(), OutgoingCompression::default()),
+        )
     }
 
-    /// If `req` is `Shutdown`, respond to it and return `true`, otherwise return `false`
-    pub fn handle_shutdown(&self, req: &Request) -> Result<bool, ProtocolError> {
-        if !req.is_shutdown() {
-            return Ok(false);
+    /// Like `memory`, but each direction holds at most `capacity` messages,
+    /// so a side that stops reading eventually blocks the other.
+    ///
+    /// Use this for testing backpressure.
+    pub fn memory_bounded(capacity: usize) -> (Connection, Connection) {
+        let (s1, r1) = bounded(capacity);
+        let (s2, r2) = bounded(capacity);
+        (
+            Connection::new(s1, r2, never(), OutgoingCompression::default()),
+            Connection::new(s2, r1, never(), OutgoingCompression::default()),
+        )
+    }
+
+    /// Sends a request with an id from `ids` and blocks until the matching
+    /// response arrives.
+    ///
+    /// Use this when acting as a client. Other messages received in the
+    /// meantime are dropped, so it only suits peers that don't send requests
+    /// or notifications of their own that matter.
+    pub fn request<P: serde::Serialize>(
+        &self,
+        ids: &RequestIdGen,
+        method: &str,
+        params: P,
+    ) -> Result<Response, ProtocolError> {
+        let id = ids.next();
+        let req = Request::new(id.clone(), method.to_string(), params);
+        self.sender.send(req.into()).map_err(|_| ProtocolError("connection closed".to_string()))?;
+        for msg in &self.receiver {
+            match msg {
+                Message::Response(resp) if resp.id == id => return Ok(resp),
+                msg => log::debug!("dropping message while waiting for {id}: {msg:?}"),
+            }
         }
-        let resp = Response::new_ok(req.id.clone(), ());
-        let _ = self.sender.send(resp.into());
-        match &self.receiver.recv_timeout(std::time::Duration::from_secs(30)) {
-            Ok(Message::Notification(n)) if n.is_exit() => (),
-            Ok(msg) => {
-                return Err(ProtocolError(format!("unexpected message during shutdown: {msg:?}")))
+        Err(ProtocolError(format!("connection closed while waiting for response to {id}")))
+    }
+
+    /// Sends `req` and waits up to `timeout` for the response with its id.
+    ///
+    /// Use this when acting as a client. Unlike `request`, other messages
+    /// received in the meantime are kept for `take_unclaimed`. Only responses
+    /// received after sending count, so one an earlier call set aside for
+    /// the same id stays with the unclaimed messages.
+    pub fn request_timeout(
+        &self,
+        req: Request,
+        timeout: Duration,
+    ) -> Result<Response, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let id = req.id.clone();
+        self.sender.send(req.into()).map_err(|_| RecvTimeoutError::Disconnected)?;
+        loop {
+            match self.receiver.recv_deadline(deadline)? {
+                Message::Response(resp) if resp.id == id => return Ok(resp),
+                msg => self.unclaimed.lock().unwrap().push_back(msg),
             }
-            Err(e) => return Err(ProtocolError(format!("unexpected error during shutdown: {e}"))),
         }
-        Ok(true)
+    }
+
+    /// Takes the messages `request_timeout` set aside, oldest first.
+    pub fn take_unclaimed(&self) -> Vec<Message> {
+        self.unclaimed.lock().unwrap().drain(..).collect()
+    }
+
+    /// Sends the response to `shutdown`, failing if the writer doesn't take
+    /// it within 30 seconds.
+    pub fn send_shutdown_response(&self, id: RequestId) -> Result<(), ProtocolError> {
+        let resp = Response::new_ok(id, ());
+        // If the writer thread is gone the client will never see the response,
+        // so there's no point waiting for its `exit`.
+        self.sender
+            .send_timeout(resp.into(), Duration::from_secs(30))
+            .map_err(|e| ProtocolError(format!("failed to send shutdown response: {e}")))
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::msg::Notification;
+
+    use super::*;
+
+    /// Both ends of a loopback tcp connection.
+    fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn configure_stream_applies_nodelay_and_keepalive() {
+        let (stream, _peer) = tcp_pair();
+        configure_stream(&stream, &SocketOptions::default()).unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+
+        configure_stream(&stream, &SocketOptions { nodelay: false, keepalive: None }).unwrap();
+        assert!(!stream.nodelay().unwrap());
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+    }
+
+    /// A writer whose bytes stay readable after the writer thread has it.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn from_io_reads_and_writes_messages_over_the_streams() {
+        let body = r#"{"jsonrpc":"2.0","method":"ping"}"#;
+        let input = format!("Content-Length: {}\r\n\r\n{body}", body.len());
+        let output = SharedBuf::default();
+        let (connection, io_threads) =
+            Connection::from_io(io::Cursor::new(input.into_bytes()), output.clone());
+
+        let msg = connection.receiver.recv().unwrap();
+        assert!(matches!(msg, Message::Notification(not) if not.method == "ping"));
+        let pong = Notification::new("pong".to_string(), ());
+        connection.sender.send(pong.into()).unwrap();
+        drop(connection);
+        io_threads.join().unwrap();
+
+        let written = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert!(written.ends_with(r#"{"jsonrpc":"2.0","method":"pong"}"#), "{written}");
+    }
+
+    #[test]
+    fn request_waits_for_the_response_with_its_id() {
+        let (client, server) = Connection::memory();
+        let peer = thread::spawn(move || {
+            let req = match server.receiver.recv().unwrap() {
+                Message::Request(req) => req,
+                msg => panic!("expected a request, got {msg:?}"),
+            };
+            let stale = Response::new_ok(RequestId::from(99), "stale");
+            server.sender.send(stale.into()).unwrap();
+            server.sender.send(Response::new_ok(req.id, req.method).into()).unwrap();
+        });
+        let ids = RequestIdGen::new();
+        let resp = client.request(&ids, "Health", ()).unwrap();
+        assert_eq!(resp.id, RequestId::from(1));
+        assert_eq!(resp.into_result::<String>().unwrap(), "Health");
+        peer.join().unwrap();
+
+        let err = client.request(&ids, "Health", ()).unwrap_err();
+        assert!(err.to_string().starts_with("connection closed"), "{err}");
+    }
+
+    #[test]
+    fn send_shutdown_response_fails_once_the_peer_is_gone() {
+        let (server, client) = Connection::memory();
+        server.send_shutdown_response(RequestId::from(1)).unwrap();
+        match client.receiver.recv().unwrap() {
+            Message::Response(resp) => assert_eq!(resp.id, RequestId::from(1)),
+            msg => panic!("expected the response, got {msg:?}"),
+        }
+        drop(client);
+        let err = server.send_shutdown_response(RequestId::from(2)).unwrap_err();
+        assert!(err.to_string().starts_with("failed to send shutdown response"), "{err}");
+    }
+
+    #[test]
+    fn memory_bounded_blocks_the_sender_once_the_peer_stops_reading() {
+        let (a, b) = Connection::memory_bounded(2);
+        let ping = || Message::from(Notification::new("ping".to_string(), ()));
+        for _ in 0..2 {
+            a.sender.try_send(ping()).unwrap();
+        }
+        assert!(a.sender.try_send(ping()).unwrap_err().is_full());
+        b.receiver.recv().unwrap();
+        a.sender.try_send(ping()).unwrap();
+        // The other direction has its own room.
+        b.sender.try_send(ping()).unwrap();
+    }
+
+    #[test]
+    fn errors_reports_a_reader_thread_that_fails() {
+        let input = io::Cursor::new(b"garbage\n".to_vec());
+        let (connection, io_threads) = Connection::from_io(input, io::sink());
+        let err = connection.errors.recv().unwrap();
+        assert!(err.to_string().starts_with("reader thread failed: malformed header"), "{err}");
+        drop(connection);
+        assert_eq!(io_threads.join().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    struct PanickingReader;
+
+    impl Read for PanickingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            panic!("read failed");
+        }
+    }
+
+    #[test]
+    fn errors_reports_a_reader_thread_that_panics() {
+        let (connection, _io_threads) = Connection::from_io(PanickingReader, io::sink());
+        assert_eq!(connection.errors.recv().unwrap().to_string(), "reader thread panicked");
+        // The report comes before the message channel closes.
+        assert!(connection.receiver.recv().is_err());
+    }
+
+    #[test]
+    fn errors_is_never_ready_for_memory_connections() {
+        let (a, _b) = Connection::memory();
+        assert!(a.errors.recv_timeout(Duration::from_millis(10)).is_err());
+    }
+
+    #[test]
+    fn request_timeout_keeps_other_messages_for_take_unclaimed() {
+        let (client, server) = Connection::memory();
+        let ping = Notification::new("ping".to_string(), ());
+        server.sender.send(ping.into()).unwrap();
+        server.sender.send(Response::new_ok(RequestId::from(2), "early").into()).unwrap();
+        server.sender.send(Response::new_ok(RequestId::from(1), "first").into()).unwrap();
+
+        let req = |id: i32| Request::new(RequestId::from(id), "Health".to_string(), ());
+        let timeout = Duration::from_millis(100);
+        let resp = client.request_timeout(req(1), timeout).unwrap();
+        assert_eq!(resp.into_result::<String>().unwrap(), "first");
+        // A response set aside earlier doesn't answer a later request.
+        server.sender.send(Response::new_ok(RequestId::from(2), "second").into()).unwrap();
+        let resp = client.request_timeout(req(2), timeout).unwrap();
+        assert_eq!(resp.into_result::<String>().unwrap(), "second");
+        let sent: Vec<RequestId> = server
+            .receiver
+            .try_iter()
+            .map(|msg| match msg {
+                Message::Request(req) => req.id,
+                msg => panic!("expected a request, got {msg:?}"),
+            })
+            .collect();
+        assert_eq!(sent, [RequestId::from(1), RequestId::from(2)]);
+
+        let unclaimed = client.take_unclaimed();
+        assert!(matches!(&unclaimed[0], Message::Notification(not) if not.method == "ping"));
+        assert!(matches!(&unclaimed[1], Message::Response(resp) if resp.id == RequestId::from(2)));
+        assert_eq!(unclaimed.len(), 2);
+        let err = client.request_timeout(req(3), Duration::from_millis(10)).unwrap_err();
+        assert_eq!(err, RecvTimeoutError::Timeout);
+    }
+
+    #[test]
+    fn the_reader_stops_at_exit() {
+        let body = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        // Reading on would fail on the garbage.
+        let input = format!("Content-Length: {}\r\n\r\n{body}garbage\n", body.len());
+        let (connection, io_threads) =
+            Connection::from_io(io::Cursor::new(input.into_bytes()), io::sink());
+        let msg = connection.receiver.recv().unwrap();
+        assert!(matches!(msg, Message::Notification(not) if not.method == "exit"));
+        assert!(connection.receiver.recv().is_err());
+        drop(connection);
+        io_threads.join().unwrap();
+    }
+}
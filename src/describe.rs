@@ -0,0 +1,329 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::ast::{AstBlock, AstNode, Comment, DisassembleEntry};
+use crate::handlers::{
+    DisassembleParams, FindByTextParams, GetAstAsDotParams, GetAstAsDotResponse,
+    GetAstDiffSummaryParams, GetAstDiffSummaryResponse, GetAstFingerprintParams,
+    GetAstFingerprintResponse, GetAstForSelectionParams, GetBracketMatchParams,
+    GetBracketMatchResponse, GetCommentsParams, GetErrorRecoveryTreeParams,
+    GetErrorRecoveryTreeResponse, GetFieldsParams, GetInjectionRegionsParams, GetKeywordAtParams,
+    GetKeywordAtResponse, GetLeafAtPositionParams, GetLeafAtPositionResponse,
+    GetNamedNodeAtEachLineParams, GetNodeParentUntilKindParams, GetSExpForKindsParams,
+    GetSExpForKindsResponse, GetSExpHashParams, GetScopeMapParams, GetSiblingsInRangeParams,
+    GetSiblingsInRangeResponse, GetSupertypesParams, GetTreeSExpForSubtreeParams,
+    GetWhitespaceSensitiveRangeParams, GetWhitespaceSensitiveRangeResponse, HealthResponse,
+    HighlightOccurrencesParams, IsLanguageSupportedParams, IsLanguageSupportedResponse,
+    NavigateErrorParams, NodesForRangesParams, OffsetToPositionParams, ParseAndQueryParams,
+    ParseAndQueryResponse, ParseAstInRangeParams, ParseAstInRangeResponse, ParseManyParams,
+    ParseManyResult, ParseSubRangeParams, PositionToOffsetParams, PositionToOffsetResponse,
+    ReloadGrammarParams, ResolveLocalParams, ResolveLocalResponse, RunQueryParams, SelectionRange,
+    SelectionRangesForPositionsParams, SemanticTokensParams, SemanticTokensResponse, WarmUpParams,
+    WarmUpResult,
+};
+use crate::injections::InjectionRegion;
+use crate::position::Position;
+use crate::query::Capture;
+use crate::server::{CancelAllResult, ResetResult, Stats};
+
+/// What `describe` returns: every method the server routes, with the field
+/// names of its params and result, for generating client bindings.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Catalog {
+    /// Params every method accepts on top of its own.
+    pub common_params: &'static [&'static str],
+    pub methods: Vec<MethodSchema>,
+}
+
+/// One method of the `Catalog`. Optional params end in `?`; result fields
+/// ending in `?` are left out when empty.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MethodSchema {
+    pub method: &'static str,
+    pub params: Vec<String>,
+    pub result: Shape,
+    /// The fields of the result object, or of the objects in it for the
+    /// other shapes. Empty when those are plain values.
+    pub result_fields: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Shape {
+    Object,
+    /// An object or `null`.
+    Nullable,
+    Array,
+    /// An object keyed by name.
+    Map,
+    /// Always `null`.
+    Null,
+}
+
+/// The JSON fields of a params or result type, as `describe` lists them.
+/// Implemented with `schema!` right below each type, so the two change
+/// together.
+pub trait Schema {
+    /// In camelCase, with `?` appended to optional params and to result
+    /// fields left out when empty.
+    fn fields() -> Vec<String>;
+}
+
+/// For methods without params, and results that aren't objects.
+impl Schema for () {
+    fn fields() -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// `name` as `rename_all = "camelCase"` writes it, with `?` appended if
+/// `optional`.
+pub fn field_name(name: &str, optional: bool) -> String {
+    let mut field = String::new();
+    let mut upper = false;
+    for c in name.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            field.push(c.to_ascii_uppercase());
+            upper = false;
+        } else {
+            field.push(c);
+        }
+    }
+    if optional {
+        field.push('?');
+    }
+    field
+}
+
+/// Deserializing `Known` fails wherever deserializing `Self` fails, and
+/// also on fields `Self` doesn't have, at any depth. For `strict_params`.
+/// Implemented with `schema!` for params types.
+pub trait Strict {
+    type Known: DeserializeOwned;
+}
+
+impl<T: Strict> Strict for Vec<T> {
+    type Known = Vec<T::Known>;
+}
+
+/// Implements `Schema` for a type from its fields, listed in declaration
+/// order, and `Strict` if it is a params type:
+///
+/// ```ignore
+/// schema!(params RunQueryParams { language, code, query, document_uri? });
+/// ```
+///
+/// The kind is `params` for a method's params, `flattened` for params only
+/// ever flattened into others, or `result`. A field is `name`, or `name?` if
+/// optional; `name: T` or `name?: T` if its value is a params type `T` of
+/// its own, `..name: T` if it is `#[serde(flatten)]`ed.
+macro_rules! schema {
+    (@munch $kind:ident $ty:ident [$($names:tt)*] [$($known:tt)*]
+        .. $name:ident : $t:ty $(, $($rest:tt)*)?) => {
+        $crate::describe::schema!(@munch $kind $ty
+            [$($names)* <$t as $crate::describe::Schema>::fields(),]
+            [$($known)* #[serde(flatten)] $name: <$t as $crate::describe::Strict>::Known,]
+            $($($rest)*)?);
+    };
+    (@munch $kind:ident $ty:ident [$($names:tt)*] [$($known:tt)*]
+        $name:ident ? : $t:ty $(, $($rest:tt)*)?) => {
+        $crate::describe::schema!(@munch $kind $ty
+            [$($names)* vec![$crate::describe::field_name(stringify!($name), true)],]
+            [$($known)* $name: Option<<$t as $crate::describe::Strict>::Known>,]
+            $($($rest)*)?);
+    };
+    (@munch $kind:ident $ty:ident [$($names:tt)*] [$($known:tt)*]
+        $name:ident : $t:ty $(, $($rest:tt)*)?) => {
+        $crate::describe::schema!(@munch $kind $ty
+            [$($names)* vec![$crate::describe::field_name(stringify!($name), false)],]
+            [$($known)* $name: Option<<$t as $crate::describe::Strict>::Known>,]
+            $($($rest)*)?);
+    };
+    (@munch $kind:ident $ty:ident [$($names:tt)*] [$($known:tt)*]
+        $name:ident ? $(, $($rest:tt)*)?) => {
+        $crate::describe::schema!(@munch $kind $ty
+            [$($names)* vec![$crate::describe::field_name(stringify!($name), true)],]
+            [$($known)* $name: Option<::serde::de::IgnoredAny>,]
+            $($($rest)*)?);
+    };
+    (@munch $kind:ident $ty:ident [$($names:tt)*] [$($known:tt)*]
+        $name:ident $(, $($rest:tt)*)?) => {
+        $crate::describe::schema!(@munch $kind $ty
+            [$($names)* vec![$crate::describe::field_name(stringify!($name), false)],]
+            [$($known)* $name: Option<::serde::de::IgnoredAny>,]
+            $($($rest)*)?);
+    };
+    (@munch params $ty:ident [$($names:tt)*] [$($known:tt)*]) => {
+        $crate::describe::schema!(@schema $ty [$($names)*]);
+        // Missing fields are left to `$ty` to complain about.
+        $crate::describe::schema!(@strict $ty [deny_unknown_fields,] [$($known)*]);
+    };
+    (@munch flattened $ty:ident [$($names:tt)*] [$($known:tt)*]) => {
+        $crate::describe::schema!(@schema $ty [$($names)*]);
+        // The fields it doesn't have are the outer type's, which rejects
+        // those nothing flattened into it has either.
+        $crate::describe::schema!(@strict $ty [] [$($known)*]);
+    };
+    (@munch result $ty:ident [$($names:tt)*] [$($known:tt)*]) => {
+        $crate::describe::schema!(@schema $ty [$($names)*]);
+    };
+    (@schema $ty:ident [$($names:tt)*]) => {
+        impl $crate::describe::Schema for $ty {
+            fn fields() -> Vec<String> {
+                let names: Vec<Vec<String>> = vec![$($names)*];
+                names.concat()
+            }
+        }
+    };
+    (@strict $ty:ident [$($deny:tt)*] [$($known:tt)*]) => {
+        const _: () = {
+            #[derive(::serde::Deserialize)]
+            #[serde($($deny)* rename_all = "camelCase")]
+            #[allow(dead_code)]
+            pub struct Known {
+                $($known)*
+            }
+
+            impl $crate::describe::Strict for $ty {
+                type Known = Known;
+            }
+        };
+    };
+    ($kind:ident $ty:ident { $($fields:tt)* }) => {
+        $crate::describe::schema!(@munch $kind $ty [] [] $($fields)*);
+    };
+}
+pub(crate) use schema;
+
+schema!(result Catalog { common_params, methods });
+
+pub fn catalog() -> Catalog {
+    Catalog { common_params: COMMON_PARAMS, methods: methods() }
+}
+
+/// `filePath` stands in for `code`, see `AstServerConfig::allowed_paths`.
+const COMMON_PARAMS: &[&str] = &["filePath?", "normalizeNewlines?", "replaceNulBytes?"];
+
+/// The schema of a method taking `P` and returning `R`, or objects of type
+/// `R` for shapes other than `Object` and `Nullable`.
+fn method<P: Schema, R: Schema>(method: &'static str, result: Shape) -> MethodSchema {
+    MethodSchema { method, params: P::fields(), result, result_fields: R::fields() }
+}
+
+/// In the order of `AstServer::route`, then the methods the main loop
+/// answers itself. Add new methods here too.
+fn methods() -> Vec<MethodSchema> {
+    vec![
+        // With `dryRun`, the result is a `ValidationResponse` instead.
+        method::<ParseAstInRangeParams, ParseAstInRangeResponse>("ParseAstInRange", Shape::Object),
+        method::<GetErrorRecoveryTreeParams, GetErrorRecoveryTreeResponse>(
+            "GetErrorRecoveryTree",
+            Shape::Object,
+        ),
+        method::<DisassembleParams, DisassembleEntry>("Disassemble", Shape::Array),
+        method::<OffsetToPositionParams, Position>("OffsetToPosition", Shape::Object),
+        method::<PositionToOffsetParams, PositionToOffsetResponse>(
+            "PositionToOffset",
+            Shape::Object,
+        ),
+        method::<ResolveLocalParams, ResolveLocalResponse>("ResolveLocal", Shape::Object),
+        method::<HighlightOccurrencesParams, AstBlock>("HighlightOccurrences", Shape::Array),
+        method::<GetCommentsParams, Comment>("GetComments", Shape::Array),
+        // The kind for each line, or `null`.
+        method::<GetNamedNodeAtEachLineParams, ()>("GetNamedNodeAtEachLine", Shape::Array),
+        method::<ParseSubRangeParams, AstNode>("ParseSubRange", Shape::Object),
+        method::<GetKeywordAtParams, GetKeywordAtResponse>("GetKeywordAt", Shape::Object),
+        method::<RunQueryParams, Capture>("RunQuery", Shape::Array),
+        method::<ParseAndQueryParams, ParseAndQueryResponse>("ParseAndQuery", Shape::Object),
+        method::<GetTreeSExpForSubtreeParams, AstBlock>("GetTreeSExpForSubtree", Shape::Object),
+        method::<GetBracketMatchParams, GetBracketMatchResponse>(
+            "GetBracketMatch",
+            Shape::Nullable,
+        ),
+        method::<IsLanguageSupportedParams, IsLanguageSupportedResponse>(
+            "IsLanguageSupported",
+            Shape::Object,
+        ),
+        method::<SelectionRangesForPositionsParams, SelectionRange>(
+            "SelectionRangesForPositions",
+            Shape::Array,
+        ),
+        method::<ParseManyParams, ParseManyResult>("ParseMany", Shape::Array),
+        method::<GetNodeParentUntilKindParams, AstBlock>("GetNodeParentUntilKind", Shape::Object),
+        method::<GetAstFingerprintParams, GetAstFingerprintResponse>(
+            "GetAstFingerprint",
+            Shape::Object,
+        ),
+        method::<GetSExpHashParams, GetAstFingerprintResponse>("GetSExpHash", Shape::Object),
+        method::<GetLeafAtPositionParams, GetLeafAtPositionResponse>(
+            "GetLeafAtPosition",
+            Shape::Object,
+        ),
+        method::<GetFieldsParams, AstBlock>("GetFields", Shape::Map),
+        method::<GetAstForSelectionParams, AstBlock>("GetAstForSelection", Shape::Array),
+        method::<NavigateErrorParams, AstBlock>("GetNextError", Shape::Nullable),
+        method::<NavigateErrorParams, AstBlock>("GetPrevError", Shape::Nullable),
+        // `null` for ranges no node covers.
+        method::<NodesForRangesParams, AstBlock>("NodesForRanges", Shape::Array),
+        method::<GetSiblingsInRangeParams, GetSiblingsInRangeResponse>(
+            "GetSiblingsInRange",
+            Shape::Object,
+        ),
+        method::<GetWhitespaceSensitiveRangeParams, GetWhitespaceSensitiveRangeResponse>(
+            "GetWhitespaceSensitiveRange",
+            Shape::Nullable,
+        ),
+        method::<GetInjectionRegionsParams, InjectionRegion>("GetInjectionRegions", Shape::Array),
+        method::<GetAstDiffSummaryParams, GetAstDiffSummaryResponse>(
+            "GetAstDiffSummary",
+            Shape::Object,
+        ),
+        method::<GetSExpForKindsParams, GetSExpForKindsResponse>("GetSExpForKinds", Shape::Object),
+        method::<FindByTextParams, AstBlock>("FindByText", Shape::Array),
+        method::<GetAstAsDotParams, GetAstAsDotResponse>("GetAstAsDot", Shape::Object),
+        method::<SemanticTokensParams, SemanticTokensResponse>("SemanticTokens", Shape::Object),
+        // Capture names mapped to arrays of kinds.
+        method::<GetScopeMapParams, ()>("GetScopeMap", Shape::Map),
+        // Supertype kinds mapped to arrays of subtype kinds.
+        method::<GetSupertypesParams, ()>("GetSupertypes", Shape::Map),
+        method::<WarmUpParams, WarmUpResult>("WarmUp", Shape::Array),
+        method::<ReloadGrammarParams, ()>("ReloadGrammar", Shape::Null),
+        method::<(), HealthResponse>("Health", Shape::Object),
+        method::<(), Stats>("Stats", Shape::Object),
+        method::<(), ResetResult>("Reset", Shape::Object),
+        method::<(), CancelAllResult>("CancelAll", Shape::Object),
+        method::<(), Catalog>("describe", Shape::Object),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    #[test]
+    fn field_name_matches_serdes_camel_case() {
+        assert_eq!(field_name("code", false), "code");
+        assert_eq!(field_name("include_widest_at_start", true), "includeWidestAtStart?");
+    }
+
+    #[test]
+    fn catalog_lists_each_method_once_with_flattened_params_inlined() {
+        let methods = catalog().methods;
+        let names: BTreeSet<_> = methods.iter().map(|method| method.method).collect();
+        assert_eq!(names.len(), methods.len());
+        let params = |name: &str| {
+            methods.iter().find(|method| method.method == name).unwrap().params.clone()
+        };
+        let parse_ast_in_range = params("ParseAstInRange");
+        for field in ["language", "cursorPosition", "prevFingerprint?", "includeRoot?"] {
+            assert!(parse_ast_in_range.iter().any(|param| param == field), "{field}");
+        }
+        assert!(params("ParseSubRange").iter().any(|param| param == "excludeKinds?"));
+        assert!(params("Health").is_empty());
+    }
+}
@@ -0,0 +1,3096 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+use serde::{Deserialize, Serialize};
+use tree_sitter::{InputEdit, Node, Parser, Point, Tree};
+
+use crate::ast::{
+    self, AstBlock, AstNode, Comment, DisassembleEntry, ErrorRange, KindFilter, SexpLiterals,
+    SexpOptions, Token,
+};
+use crate::cache::{self, QueryCache, QueryEntry, TreeCache};
+use crate::describe::schema;
+use crate::highlights::{self, ScopeMap};
+use crate::injections::{self, InjectionRegion};
+use crate::language::Language;
+use crate::locals::Locals;
+use crate::msg::ResponseError;
+use crate::parser_pool::ParserPool;
+use crate::position::{LineIndex, Position, PositionEncoding, Range};
+use crate::query::{self, Capture};
+use crate::rate_limit::RateLimit;
+use crate::semantic_tokens;
+
+#[derive(Debug, Default, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InitializeParams {
+    #[serde(default)]
+    pub capabilities: ClientCapabilities,
+}
+
+#[derive(Debug, Default, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientCapabilities {
+    /// The client can read messages sent with `Content-Encoding: gzip`.
+    #[serde(default)]
+    pub gzip: bool,
+    /// Forward server logs as `window/logMessage` notifications.
+    #[serde(default)]
+    pub log_messages: bool,
+    /// The position encodings the client supports, as in LSP's
+    /// `general.positionEncodings`.
+    #[serde(default)]
+    pub position_encodings: Vec<String>,
+    /// Attach `_meta` with the server and grammar versions to every
+    /// response, to tie a bad response to a build.
+    #[serde(default)]
+    pub response_meta: bool,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InitializeResult {
+    pub capabilities: ServerCapabilities,
+    pub server_info: ServerInfo,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerCapabilities {
+    /// Large messages will be gzip-compressed. Only socket transports
+    /// support this.
+    pub gzip: bool,
+    pub log_messages: bool,
+    /// The unit of `character` in every position from now on.
+    pub position_encoding: PositionEncoding,
+    /// Requests beyond this fail with a "rate limited" error whose data holds
+    /// `retryAfterMs`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimit>,
+    pub response_meta: bool,
+    /// How many requests for each listed language run at once.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub language_permits: BTreeMap<Language, usize>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerInfo {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParseAstInRangeParams {
+    pub language: Language,
+    pub cursor_position: Position,
+    pub code: String,
+    /// Only check whether the code parses cleanly, skipping the cursor lookup
+    /// and serialization.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// The `fingerprint` of an earlier response. If `code` still has it, the
+    /// tree cached for it is reused instead of reparsing.
+    #[serde(default)]
+    pub prev_fingerprint: Option<String>,
+    #[serde(flatten)]
+    pub options: ParseAstInRangeOptions,
+}
+
+schema!(params ParseAstInRangeParams {
+    language, cursor_position: Position, code, dry_run?, prev_fingerprint?,
+    ..options: ParseAstInRangeOptions
+});
+
+/// Optional parts of a `ParseAstInRange` response.
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParseAstInRangeOptions {
+    /// Include the root node, e.g. for bounds checks.
+    #[serde(default)]
+    pub include_root: bool,
+    /// Include the parent node. Turning this off saves serializing a second,
+    /// larger sexp in hot edit loops.
+    #[serde(default = "default_true")]
+    pub include_parent: bool,
+    /// Fill in `contentHash` on the returned nodes.
+    #[serde(default)]
+    pub include_hash: bool,
+    /// Fill in `descendantCount`, `childCount` and `namedChildCount` on the
+    /// returned nodes, so tree views can estimate the cost of expanding them.
+    #[serde(default)]
+    pub include_counts: bool,
+    /// Leave out the `astResult` sexps, which dominate the response size, for
+    /// clients that render from their own buffer.
+    #[serde(default)]
+    pub ranges_only: bool,
+    /// Include `widestAtStart`.
+    #[serde(default)]
+    pub include_widest_at_start: bool,
+}
+
+schema!(flattened ParseAstInRangeOptions {
+    include_root?, include_parent?, include_hash?, include_counts?, ranges_only?,
+    include_widest_at_start?
+});
+
+impl Default for ParseAstInRangeOptions {
+    fn default() -> ParseAstInRangeOptions {
+        ParseAstInRangeOptions {
+            include_root: false,
+            include_parent: true,
+            include_hash: false,
+            include_counts: false,
+            ranges_only: false,
+            include_widest_at_start: false,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParseAstInRangeResponse {
+    /// The node at the cursor.
+    #[serde(flatten)]
+    pub node: AstBlock,
+    pub parent: Option<AstBlock>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root: Option<AstBlock>,
+    /// Whether the cursor was in whitespace without a node and had to be
+    /// moved to the nearest token.
+    #[serde(default)]
+    pub snapped: bool,
+    /// Where the cursor was moved to, if `snapped`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapped_position: Option<Position>,
+    /// The largest node below the root starting where the node at the
+    /// cursor does, e.g. the whole call when the cursor is at the start of
+    /// the callee.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub widest_at_start: Option<AstBlock>,
+    /// Identifies the language and code, for `prevFingerprint` on the next
+    /// request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+}
+
+schema!(result ParseAstInRangeResponse {
+    ..node: AstBlock, parent, root?, snapped, snapped_position?, widest_at_start?, fingerprint?
+});
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidationResponse {
+    /// Whether the code parsed without any errors.
+    pub ok: bool,
+    pub has_error: bool,
+    /// The number of `ERROR` and `MISSING` nodes.
+    pub error_count: usize,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ParseAstInRangeResult {
+    Node(Box<ParseAstInRangeResponse>),
+    DryRun(ValidationResponse),
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetErrorRecoveryTreeParams {
+    pub language: Language,
+    pub code: String,
+}
+
+schema!(params GetErrorRecoveryTreeParams { language, code });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetErrorRecoveryTreeResponse {
+    /// The sexp of the whole tree, with positions appended to every `ERROR`
+    /// and `MISSING` node.
+    pub ast_result: String,
+    pub errors: Vec<ErrorRange>,
+}
+
+schema!(result GetErrorRecoveryTreeResponse { ast_result, errors });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DisassembleParams {
+    pub language: Language,
+    pub code: String,
+    /// Truncate node text longer than this many characters.
+    #[serde(default)]
+    pub max_text_len: Option<usize>,
+}
+
+schema!(params DisassembleParams { language, code, max_text_len? });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OffsetToPositionParams {
+    /// Accepted for symmetry with the other methods, but not needed.
+    #[serde(default)]
+    pub language: String,
+    pub code: String,
+    pub offset: usize,
+    #[serde(default)]
+    pub position_encoding: PositionEncoding,
+}
+
+schema!(params OffsetToPositionParams { language?, code, offset, position_encoding? });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PositionToOffsetParams {
+    /// Accepted for symmetry with the other methods, but not needed.
+    #[serde(default)]
+    pub language: String,
+    pub code: String,
+    pub position: Position,
+    #[serde(default)]
+    pub position_encoding: PositionEncoding,
+}
+
+schema!(params PositionToOffsetParams { language?, code, position: Position, position_encoding? });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PositionToOffsetResponse {
+    pub offset: usize,
+}
+
+schema!(result PositionToOffsetResponse { offset });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolveLocalParams {
+    pub language: Language,
+    pub cursor_position: Position,
+    pub code: String,
+}
+
+schema!(params ResolveLocalParams { language, cursor_position: Position, code });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolveLocalResponse {
+    pub definition: Option<AstBlock>,
+    /// Every reference resolving to `definition`, in document order.
+    pub references: Vec<AstBlock>,
+}
+
+schema!(result ResolveLocalResponse { definition, references });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HighlightOccurrencesParams {
+    pub language: Language,
+    pub cursor_position: Position,
+    pub code: String,
+}
+
+schema!(params HighlightOccurrencesParams { language, cursor_position: Position, code });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetCommentsParams {
+    pub language: Language,
+    pub code: String,
+}
+
+schema!(params GetCommentsParams { language, code });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetNamedNodeAtEachLineParams {
+    pub language: Language,
+    pub code: String,
+    /// Ignore anonymous nodes such as keywords and punctuation.
+    #[serde(default = "default_true")]
+    pub skip_anonymous: bool,
+}
+
+schema!(params GetNamedNodeAtEachLineParams { language, code, skip_anonymous? });
+
+fn default_true() -> bool {
+    true
+}
+
+/// Parses `code[range_start_byte..range_end_byte]` on its own, e.g. a script
+/// embedded in HTML, reporting positions relative to the whole document.
+///
+/// `line_offset` is added to every line. `column_offset` is the column the
+/// slice starts at in the document, so it is only added to positions on the
+/// slice's first line; later lines already start at column 0.
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParseSubRangeParams {
+    pub language: Language,
+    pub code: String,
+    pub range_start_byte: usize,
+    pub range_end_byte: usize,
+    #[serde(default)]
+    pub line_offset: usize,
+    #[serde(default)]
+    pub column_offset: usize,
+    #[serde(flatten)]
+    pub filter: KindFilter,
+}
+
+schema!(params ParseSubRangeParams {
+    language, code, range_start_byte, range_end_byte, line_offset?, column_offset?,
+    ..filter: KindFilter
+});
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetKeywordAtParams {
+    pub language: Language,
+    pub cursor_position: Position,
+    pub code: String,
+}
+
+schema!(params GetKeywordAtParams { language, cursor_position: Position, code });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetKeywordAtResponse {
+    /// The token at the cursor, named or not.
+    pub node: AstBlock,
+    pub kind: String,
+    pub is_keyword: bool,
+}
+
+schema!(result GetKeywordAtResponse { node, kind, is_keyword });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunQueryParams {
+    pub language: Language,
+    pub code: String,
+    /// A tree-sitter query in the usual S-expression syntax.
+    pub query: String,
+    /// Identifies the document across requests. With it, the query reruns
+    /// only over what changed since the last `RunQuery` with the same
+    /// document and query.
+    #[serde(default)]
+    pub document_uri: Option<String>,
+}
+
+schema!(params RunQueryParams { language, code, query, document_uri? });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetInjectionRegionsParams {
+    pub language: Language,
+    pub code: String,
+}
+
+schema!(params GetInjectionRegionsParams { language, code });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParseAndQueryParams {
+    pub language: Language,
+    pub code: String,
+    pub query: String,
+    pub cursor_position: Position,
+    #[serde(flatten)]
+    pub options: ParseAstInRangeOptions,
+}
+
+schema!(params ParseAndQueryParams {
+    language, code, query, cursor_position: Position, ..options: ParseAstInRangeOptions
+});
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParseAndQueryResponse {
+    /// What `ParseAstInRange` returns for the cursor.
+    pub node: ParseAstInRangeResponse,
+    /// What `RunQuery` returns for the query.
+    pub captures: Vec<Capture>,
+}
+
+schema!(result ParseAndQueryResponse { node, captures });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetTreeSExpForSubtreeParams {
+    pub language: Language,
+    pub code: String,
+    /// Named-child indices leading from the root to the subtree, as in the
+    /// `children` of `GetErrorRecoveryTree`. Empty for the root itself.
+    pub path: Vec<usize>,
+    /// Append `[row,col]-[row,col]` to every node in the sexp.
+    #[serde(default)]
+    pub with_positions: bool,
+    /// Write string and number literals with their quoted source text, e.g.
+    /// `(integer "42")`.
+    #[serde(default)]
+    pub with_literals: bool,
+}
+
+schema!(params GetTreeSExpForSubtreeParams {
+    language, code, path, with_positions?, with_literals?
+});
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetBracketMatchParams {
+    pub language: Language,
+    pub code: String,
+    pub cursor_position: Position,
+}
+
+schema!(params GetBracketMatchParams { language, code, cursor_position: Position });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetBracketMatchResponse {
+    /// The delimiter at the cursor.
+    pub bracket: Token,
+    pub matching: Token,
+}
+
+schema!(result GetBracketMatchResponse { bracket, matching });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IsLanguageSupportedParams {
+    /// A language name or alias.
+    pub language: String,
+}
+
+schema!(params IsLanguageSupportedParams { language });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IsLanguageSupportedResponse {
+    pub supported: bool,
+    /// The name `language` resolves to, if supported.
+    pub canonical: Option<String>,
+}
+
+schema!(result IsLanguageSupportedResponse { supported, canonical });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectionRangesForPositionsParams {
+    pub language: Language,
+    pub code: String,
+    pub positions: Vec<Position>,
+}
+
+schema!(params SelectionRangesForPositionsParams { language, code, positions: Vec<Position> });
+
+/// LSP's `SelectionRange`: a range and the next larger one containing it.
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectionRange {
+    pub range: Range,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<Box<SelectionRange>>,
+}
+
+schema!(result SelectionRange { range, parent? });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParseManyParams {
+    pub files: Vec<ParseManyFile>,
+    #[serde(default)]
+    pub mode: ParseManyMode,
+    /// Parse files given by `filePath` straight from disk, reading chunks as
+    /// the parser asks for them instead of the whole file up front. Only
+    /// allowed under the UTF-8 position encoding, since there's no code to
+    /// convert positions against.
+    #[serde(default)]
+    pub stream: bool,
+    /// Applied to the trees of the `tree` mode.
+    #[serde(flatten)]
+    pub filter: KindFilter,
+}
+
+schema!(params ParseManyParams { files: Vec<ParseManyFile>, mode?, stream?, ..filter: KindFilter });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParseManyFile {
+    /// Chosen by the client to match results to files.
+    pub id: String,
+    pub language: Language,
+    #[serde(default)]
+    pub code: String,
+    /// Set instead of `code` in `stream` mode, to the canonical path of the
+    /// file to parse.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_path: Option<String>,
+}
+
+schema!(params ParseManyFile { id, language, code?, file_path? });
+
+/// How much `ParseMany` reports for each file.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ParseManyMode {
+    /// Only whether the file parses cleanly.
+    #[default]
+    Validate,
+    /// Also where the errors are.
+    Errors,
+    /// Also the tree of named nodes.
+    Tree,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParseManyResult {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub validation: Option<ValidationResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<Vec<ErrorRange>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tree: Option<AstNode>,
+    /// Why the file couldn't be handled. The other files are unaffected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+schema!(result ParseManyResult { id, validation?, errors?, tree?, error? });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetNodeParentUntilKindParams {
+    pub language: Language,
+    pub code: String,
+    pub cursor_position: Position,
+    /// Kinds that end the walk, e.g. `function_definition` and
+    /// `class_definition` for "select enclosing function or class".
+    pub stop_kinds: Vec<String>,
+}
+
+schema!(params GetNodeParentUntilKindParams {
+    language, code, cursor_position: Position, stop_kinds
+});
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetAstFingerprintParams {
+    pub language: Language,
+    pub code: String,
+    /// Also tell apart trees that differ only in which fields nodes fill.
+    #[serde(default)]
+    pub include_field_names: bool,
+}
+
+schema!(params GetAstFingerprintParams { language, code, include_field_names? });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetAstFingerprintResponse {
+    /// 32 lowercase hex digits.
+    pub fingerprint: String,
+}
+
+schema!(result GetAstFingerprintResponse { fingerprint });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetSExpHashParams {
+    pub language: Language,
+    pub code: String,
+    /// Named-child indices leading to the subtree, as in
+    /// `GetTreeSExpForSubtree`.
+    pub path: Vec<usize>,
+    #[serde(default)]
+    pub include_field_names: bool,
+}
+
+schema!(params GetSExpHashParams { language, code, path, include_field_names? });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetLeafAtPositionParams {
+    pub language: Language,
+    pub code: String,
+    pub cursor_position: Position,
+}
+
+schema!(params GetLeafAtPositionParams { language, code, cursor_position: Position });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetLeafAtPositionResponse {
+    /// The deepest node at the cursor, normally a token.
+    pub node: AstBlock,
+    pub kind: String,
+    pub is_named: bool,
+}
+
+schema!(result GetLeafAtPositionResponse { node, kind, is_named });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetWhitespaceSensitiveRangeParams {
+    pub language: Language,
+    pub code: String,
+    pub cursor_position: Position,
+}
+
+schema!(params GetWhitespaceSensitiveRangeParams { language, code, cursor_position: Position });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetWhitespaceSensitiveRangeResponse {
+    /// The block node's kind, e.g. `block` for Python.
+    pub kind: String,
+    /// From the block's first statement to the start of the dedented line
+    /// after it, so trailing blank lines are included.
+    pub range: Range,
+    pub start_byte: usize,
+    pub end_byte: usize,
+}
+
+schema!(result GetWhitespaceSensitiveRangeResponse { kind, range, start_byte, end_byte });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetFieldsParams {
+    pub language: Language,
+    pub code: String,
+    pub cursor_position: Position,
+}
+
+schema!(params GetFieldsParams { language, code, cursor_position: Position });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetAstForSelectionParams {
+    pub language: Language,
+    pub code: String,
+    pub start: Position,
+    pub end: Position,
+}
+
+schema!(params GetAstForSelectionParams { language, code, start: Position, end: Position });
+
+/// Params of `GetNextError` and `GetPrevError`.
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NavigateErrorParams {
+    pub language: Language,
+    pub code: String,
+    pub cursor_position: Position,
+}
+
+schema!(params NavigateErrorParams { language, code, cursor_position: Position });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetAstDiffSummaryParams {
+    pub language: Language,
+    pub old_code: String,
+    pub new_code: String,
+}
+
+schema!(params GetAstDiffSummaryParams { language, old_code, new_code });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetAstDiffSummaryResponse {
+    /// One entry per named node kind that changed, sorted by kind.
+    pub kinds: Vec<KindDiff>,
+    /// E.g. `+1 function_definition, -2 import_statement`, or empty if no
+    /// nodes were added or removed.
+    pub summary: String,
+}
+
+schema!(result GetAstDiffSummaryResponse { kinds, summary });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KindDiff {
+    pub kind: String,
+    pub added: usize,
+    pub removed: usize,
+    /// Nodes of the kind in the changed region on both sides.
+    pub changed: usize,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetSExpForKindsParams {
+    pub language: Language,
+    pub code: String,
+    /// The node kinds to keep, e.g. `function_definition` and
+    /// `class_definition` for an outline.
+    pub keep_kinds: Vec<String>,
+}
+
+schema!(params GetSExpForKindsParams { language, code, keep_kinds });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetSExpForKindsResponse {
+    /// The root's sexp with only the kept nodes below it.
+    pub ast_result: String,
+}
+
+schema!(result GetSExpForKindsResponse { ast_result });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FindByTextParams {
+    pub language: Language,
+    pub code: String,
+    pub text: String,
+    /// Only match nodes of these kinds. Empty matches every kind.
+    #[serde(default)]
+    pub kinds: Vec<String>,
+    /// Match nodes whose text contains `text` rather than equals it.
+    #[serde(default)]
+    pub substring: bool,
+}
+
+schema!(params FindByTextParams { language, code, text, kinds?, substring? });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetAstAsDotParams {
+    pub language: Language,
+    pub code: String,
+    /// Leave out anonymous tokens such as punctuation.
+    #[serde(default)]
+    pub named_only: bool,
+    /// Label edges with the field the child fills.
+    #[serde(default)]
+    pub field_names: bool,
+}
+
+schema!(params GetAstAsDotParams { language, code, named_only?, field_names? });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetAstAsDotResponse {
+    pub dot: String,
+}
+
+schema!(result GetAstAsDotResponse { dot });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SemanticTokensParams {
+    pub language: Language,
+    pub code: String,
+    /// First line to emit tokens for, 0-based.
+    pub start_line: usize,
+    /// Last line to emit tokens for, inclusive.
+    pub end_line: usize,
+    /// The unit of the token columns and lengths.
+    #[serde(default)]
+    pub position_encoding: PositionEncoding,
+}
+
+schema!(params SemanticTokensParams { language, code, start_line, end_line, position_encoding? });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SemanticTokensResponse {
+    /// The legend `data`'s token types index into.
+    pub token_types: Vec<String>,
+    pub data: Vec<u32>,
+}
+
+schema!(result SemanticTokensResponse { token_types, data });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetSupertypesParams {
+    pub language: Language,
+}
+
+schema!(params GetSupertypesParams { language });
+
+/// One entry of a grammar's `node-types.json`, reduced to what
+/// `get_supertypes` needs.
+#[derive(Deserialize)]
+struct NodeTypeEntry {
+    #[serde(rename = "type")]
+    kind: String,
+    /// Only present on supertypes.
+    #[serde(default)]
+    subtypes: Option<Vec<NodeTypeEntry>>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetScopeMapParams {
+    pub language: Language,
+}
+
+schema!(params GetScopeMapParams { language });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WarmUpParams {
+    /// Language names or aliases.
+    pub languages: Vec<String>,
+}
+
+schema!(params WarmUpParams { languages });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WarmUpResult {
+    /// As given in the params.
+    pub language: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+schema!(result WarmUpResult { language, ok, error? });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReloadGrammarParams {
+    /// A language name or alias.
+    pub name: String,
+}
+
+schema!(params ReloadGrammarParams { name });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodesForRangesParams {
+    pub language: Language,
+    pub code: String,
+    /// E.g. the spans of compiler diagnostics.
+    pub ranges: Vec<Range>,
+}
+
+schema!(params NodesForRangesParams { language, code, ranges: Vec<Range> });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetSiblingsInRangeParams {
+    pub language: Language,
+    pub code: String,
+    pub cursor_position: Position,
+}
+
+schema!(params GetSiblingsInRangeParams { language, code, cursor_position: Position });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetSiblingsInRangeResponse {
+    /// The named children of the cursor node's parent, in order, the cursor
+    /// node included.
+    pub siblings: Vec<AstBlock>,
+    /// The cursor node's position in `siblings`.
+    pub index: usize,
+}
+
+schema!(result GetSiblingsInRangeResponse { siblings, index });
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Ok,
+    /// At least one grammar failed to load.
+    Degraded,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthResponse {
+    pub status: HealthStatus,
+    pub languages: Vec<LanguageHealth>,
+}
+
+schema!(result HealthResponse { status, languages });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LanguageHealth {
+    pub name: String,
+    pub loaded: bool,
+}
+
+pub fn error(message: &str) -> ResponseError {
+    ResponseError { code: 1, message: message.to_string(), data: None }
+}
+
+fn parse(parser: &mut Parser, language: Language, code: &[u8]) -> Result<Tree, ResponseError> {
+    parse_from(parser, language, code, None)
+}
+
+/// Like `parse`, reusing the unchanged parts of `old_tree`, which must have
+/// been edited to match `code`.
+fn reparse(
+    parser: &mut Parser,
+    language: Language,
+    code: &[u8],
+    old_tree: &Tree,
+) -> Result<Tree, ResponseError> {
+    parse_from(parser, language, code, Some(old_tree))
+}
+
+fn parse_from(
+    parser: &mut Parser,
+    language: Language,
+    code: &[u8],
+    old_tree: Option<&Tree>,
+) -> Result<Tree, ResponseError> {
+    set_language(parser, language)?;
+
+    if code.is_empty() {
+        return Err(error("code is empty"));
+    }
+    // Some grammars stop at a NUL byte, silently dropping the rest.
+    if let Some(offset) = code.iter().position(|b| *b == 0) {
+        return Err(nul_byte(offset));
+    }
+    parser.parse(code, old_tree).ok_or_else(|| error("ast parse fail"))
+}
+
+/// Points `parser` at `language`'s grammar. Grammars are compiled in, so
+/// this only fails for one built against an incompatible tree-sitter ABI.
+fn set_language(parser: &mut Parser, language: Language) -> Result<(), ResponseError> {
+    parser
+        .set_language(language.to_tree_sitter())
+        .map_err(|_| error(&format!("failed to load the {language} grammar")))
+}
+
+fn nul_byte(offset: usize) -> ResponseError {
+    error(&format!("code has a NUL byte at offset {offset}; set replaceNulBytes to parse it"))
+}
+
+/// Like `parse`, for the file at `path`, read in chunks as the parser asks
+/// for them so it's never held in memory whole.
+fn parse_file(parser: &mut Parser, language: Language, path: &str) -> Result<Tree, ResponseError> {
+    const CHUNK_SIZE: usize = 64 * 1024;
+    set_language(parser, language)?;
+
+    let cannot_read = |err: io::Error| error(&format!("cannot read {path}: {err}"));
+    let mut file = File::open(path).map_err(cannot_read)?;
+    if file.metadata().map_err(cannot_read)?.len() == 0 {
+        return Err(error("code is empty"));
+    }
+    let mut read_error = None;
+    let mut nul_offset = None;
+    let tree = parser.parse_with(
+        &mut |offset, _| {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            let read = file.seek(SeekFrom::Start(offset as u64));
+            match read.and_then(|_| file.read(&mut chunk)) {
+                Ok(len) => {
+                    chunk.truncate(len);
+                    // Ending the input here fails the parse below, as in
+                    // `parse`.
+                    if let Some(i) = chunk.iter().position(|b| *b == 0) {
+                        nul_offset = Some(offset + i);
+                        chunk.clear();
+                    }
+                }
+                // An empty chunk ends the input; the error fails the parse below.
+                Err(err) => {
+                    read_error = Some(err);
+                    chunk.clear();
+                }
+            }
+            chunk
+        },
+        None,
+    );
+    if let Some(err) = read_error {
+        return Err(cannot_read(err));
+    }
+    if let Some(offset) = nul_offset {
+        return Err(nul_byte(offset));
+    }
+    tree.ok_or_else(|| error("ast parse fail"))
+}
+
+fn validate(root: Node) -> ValidationResponse {
+    let has_error = root.has_error();
+    ValidationResponse { ok: !has_error, has_error, error_count: ast::collect_errors(root).len() }
+}
+
+pub fn parse_ast_in_range(
+    parser: &mut Parser,
+    params: ParseAstInRangeParams,
+    trees: &TreeCache,
+) -> Result<ParseAstInRangeResult, ResponseError> {
+    let code = params.code.as_bytes();
+    if params.dry_run {
+        let tree = parse(parser, params.language, code)?;
+        return Ok(ParseAstInRangeResult::DryRun(validate(tree.root_node())));
+    }
+    let response = parse_ast_in_range_bytes(
+        parser,
+        params.language,
+        code,
+        &params.cursor_position,
+        &params.options,
+        trees,
+        params.prev_fingerprint.as_deref(),
+    )?;
+    Ok(ParseAstInRangeResult::Node(Box::new(response)))
+}
+
+/// Same as `ParseAstInRange`, but parses straight from borrowed bytes, so
+/// callers holding e.g. a mmapped file don't have to copy it into a `String`.
+/// The JSON handler goes through here too, once serde has built its `code`.
+pub fn parse_ast_in_range_bytes(
+    parser: &mut Parser,
+    language: Language,
+    code: &[u8],
+    cursor: &Position,
+    options: &ParseAstInRangeOptions,
+    trees: &TreeCache,
+    prev_fingerprint: Option<&str>,
+) -> Result<ParseAstInRangeResponse, ResponseError> {
+    let fingerprint = cache::fingerprint(language, code);
+    let cached =
+        prev_fingerprint.filter(|prev| *prev == fingerprint).and_then(|prev| trees.get(prev));
+    let tree = match cached {
+        Some(tree) => {
+            log::debug!("reusing cached tree {}", fingerprint);
+            tree
+        }
+        None => {
+            let tree = parse(parser, language, code)?;
+            trees.insert(fingerprint.clone(), tree.clone());
+            tree
+        }
+    };
+    let mut response = node_at_cursor(&tree, code, cursor, options)?;
+    response.fingerprint = Some(fingerprint);
+    Ok(response)
+}
+
+/// Looks up the node under `cursor` in an already parsed `tree`. When no
+/// named node below the root covers it, as in a file holding only a comment
+/// or whitespace, the result is the root itself, with no parent.
+fn node_at_cursor(
+    tree: &Tree,
+    code: &[u8],
+    cursor: &Position,
+    options: &ParseAstInRangeOptions,
+) -> Result<ParseAstInRangeResponse, ResponseError> {
+    let root_node = tree.root_node();
+
+    // Between top-level statements the lookup finds the root rather than
+    // nothing, which is as unhelpful.
+    let (node, snapped_point) = match named_descendant_at(root_node, cursor) {
+        Some(node) if node != root_node => (node, None),
+        _ => {
+            let offset = match cursor.byte {
+                Some(byte) => Some(byte.min(code.len())),
+                None => point_to_byte(code, Point::from(cursor)),
+            };
+            match offset.and_then(|offset| snap_to_token(root_node, code, offset)) {
+                Some((node, point)) => (node, Some(point)),
+                None => (root_node, None),
+            }
+        }
+    };
+    let format = |node: Node| {
+        let mut block =
+            if options.ranges_only { ast::format_range(node) } else { ast::format_node(node) };
+        if options.include_hash {
+            block.content_hash = Some(ast::content_hash(node, code));
+        }
+        if options.include_counts {
+            block.descendant_count = Some(ast::descendant_count(node));
+            block.child_count = Some(node.child_count());
+            block.named_child_count = Some(node.named_child_count());
+        }
+        block
+    };
+    Ok(ParseAstInRangeResponse {
+        node: format(node),
+        parent: node.parent().filter(|_| options.include_parent).map(format),
+        root: options.include_root.then(|| format(root_node)),
+        snapped: snapped_point.is_some(),
+        snapped_position: snapped_point.map(Position::from),
+        widest_at_start: options.include_widest_at_start.then(|| format(widest_at_start(node))),
+        fingerprint: None,
+    })
+}
+
+/// Walks up from `node` while the parent starts at the same byte, stopping
+/// below the root.
+fn widest_at_start(mut node: Node) -> Node {
+    while let Some(parent) = node.parent() {
+        if parent.parent().is_none() || parent.start_byte() != node.start_byte() {
+            break;
+        }
+        node = parent;
+    }
+    node
+}
+
+/// Retries the lookup for a cursor in whitespace that no node covers, e.g.
+/// leading indentation or a blank line, at the nearest non-whitespace byte
+/// to the left and then to the right.
+fn snap_to_token<'tree>(
+    root: Node<'tree>,
+    code: &[u8],
+    offset: usize,
+) -> Option<(Node<'tree>, Point)> {
+    let is_token = |b: &u8| !b.is_ascii_whitespace();
+    let left = code[..offset].iter().rposition(is_token);
+    let right = code[offset..].iter().position(is_token).map(|i| offset + i);
+    left.into_iter().chain(right).find_map(|byte| {
+        let point = byte_to_point(code, byte);
+        root.named_descendant_for_point_range(point, point).map(|node| (node, point))
+    })
+}
+
+/// The smallest node at `cursor`, looked up by byte offset when the client
+/// gave one and by row and column otherwise.
+fn descendant_at<'tree>(root: Node<'tree>, cursor: &Position) -> Option<Node<'tree>> {
+    match cursor.byte {
+        Some(byte) => root.descendant_for_byte_range(byte, byte),
+        None => root.descendant_for_point_range(Point::from(cursor), Point::from(cursor)),
+    }
+}
+
+/// Like `descendant_at`, skipping anonymous nodes.
+fn named_descendant_at<'tree>(root: Node<'tree>, cursor: &Position) -> Option<Node<'tree>> {
+    match cursor.byte {
+        Some(byte) => root.named_descendant_for_byte_range(byte, byte),
+        None => root.named_descendant_for_point_range(Point::from(cursor), Point::from(cursor)),
+    }
+}
+
+/// Columns past the end of the line are clamped to it.
+fn point_to_byte(code: &[u8], point: Point) -> Option<usize> {
+    let line_start = match point.row {
+        0 => 0,
+        row => code.iter().enumerate().filter(|(_, b)| **b == b'\n').nth(row - 1)?.0 + 1,
+    };
+    let line_end = match code[line_start..].iter().position(|b| *b == b'\n') {
+        Some(len) => line_start + len,
+        None => code.len(),
+    };
+    Some((line_start + point.column).min(line_end))
+}
+
+fn byte_to_point(code: &[u8], byte: usize) -> Point {
+    let before = &code[..byte];
+    let line_start = before.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+    Point { row: before.iter().filter(|b| **b == b'\n').count(), column: byte - line_start }
+}
+
+pub fn get_error_recovery_tree(
+    parser: &mut Parser,
+    params: GetErrorRecoveryTreeParams,
+    max_depth: usize,
+) -> Result<GetErrorRecoveryTreeResponse, ResponseError> {
+    let tree = parse(parser, params.language, params.code.as_bytes())?;
+    let root_node = tree.root_node();
+    let options = SexpOptions { mark_errors: true, ..SexpOptions::default() };
+    Ok(GetErrorRecoveryTreeResponse {
+        ast_result: ast::write_sexp(root_node, &options, max_depth)?,
+        errors: ast::collect_errors(root_node),
+    })
+}
+
+pub fn disassemble(
+    parser: &mut Parser,
+    params: DisassembleParams,
+) -> Result<Vec<DisassembleEntry>, ResponseError> {
+    let tree = parse(parser, params.language, params.code.as_bytes())?;
+    ast::disassemble(tree.root_node(), params.code.as_bytes(), params.max_text_len)
+}
+
+pub fn offset_to_position(params: OffsetToPositionParams) -> Result<Position, ResponseError> {
+    LineIndex::new(&params.code)
+        .offset_to_position(params.offset, params.position_encoding)
+        .ok_or_else(|| error("offset out of range"))
+}
+
+pub fn position_to_offset(
+    params: PositionToOffsetParams,
+) -> Result<PositionToOffsetResponse, ResponseError> {
+    LineIndex::new(&params.code)
+        .position_to_offset(&params.position, params.position_encoding)
+        .map(|offset| PositionToOffsetResponse { offset })
+        .ok_or_else(|| error("position out of range"))
+}
+
+pub fn resolve_local(
+    parser: &mut Parser,
+    params: ResolveLocalParams,
+) -> Result<ResolveLocalResponse, ResponseError> {
+    let info = params.language.info();
+    let tree = parse(parser, params.language, params.code.as_bytes())?;
+    let empty = ResolveLocalResponse { definition: None, references: Vec::new() };
+    if info.locals_query.is_empty() {
+        return Ok(empty);
+    }
+    let query = query::compile(params.language, &info.locals_query.join("\n"))?;
+
+    let source = params.code.as_bytes();
+    let root_node = tree.root_node();
+    let node = match descendant_at(root_node, &params.cursor_position) {
+        Some(node) => node,
+        None => return Ok(empty),
+    };
+    let locals = Locals::collect(&query, root_node, source);
+    Ok(match locals.resolve(node, source) {
+        Some(resolution) => ResolveLocalResponse {
+            definition: resolution.definition.map(ast::format_node),
+            references: resolution.references.into_iter().map(ast::format_node).collect(),
+        },
+        None => empty,
+    })
+}
+
+/// Finds every identifier spelled the same as the one under the cursor.
+///
+/// This is purely textual; `ResolveLocal` gives scope-aware results for the
+/// grammars that ship a locals query.
+pub fn highlight_occurrences(
+    parser: &mut Parser,
+    params: HighlightOccurrencesParams,
+) -> Result<Vec<AstBlock>, ResponseError> {
+    let tree = parse(parser, params.language, params.code.as_bytes())?;
+    let source = params.code.as_bytes();
+    let root_node = tree.root_node();
+    let target = match descendant_at(root_node, &params.cursor_position) {
+        Some(node) if ast::is_identifier(node) => ast::node_text(node, source)?,
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut occurrences = Vec::new();
+    ast::walk_tree(root_node, |node, _| {
+        if ast::is_identifier(node) && node.utf8_text(source) == Ok(target) {
+            occurrences.push(ast::format_node(node));
+        }
+    });
+    Ok(occurrences)
+}
+
+pub fn get_comments(
+    parser: &mut Parser,
+    params: GetCommentsParams,
+) -> Result<Vec<Comment>, ResponseError> {
+    let info = params.language.info();
+    let tree = parse(parser, params.language, params.code.as_bytes())?;
+    let source = params.code.as_bytes();
+    let mut nodes = Vec::new();
+    ast::walk_tree(tree.root_node(), |node, _| {
+        if info.comment_kinds.contains(&node.kind()) {
+            nodes.push(node);
+        }
+    });
+    nodes
+        .into_iter()
+        .map(|node| {
+            Ok(Comment {
+                text: ast::node_text(node, source)?.to_string(),
+                start_point: node.start_position().into(),
+                end_point: node.end_position().into(),
+            })
+        })
+        .collect()
+}
+
+/// For every line, the kind of the outermost node starting on it, or `None`
+/// if no node starts there.
+pub fn get_named_node_at_each_line(
+    parser: &mut Parser,
+    params: GetNamedNodeAtEachLineParams,
+) -> Result<Vec<Option<String>>, ResponseError> {
+    let tree = parse(parser, params.language, params.code.as_bytes())?;
+    let root_node = tree.root_node();
+    let mut lines = vec![None; params.code.split('\n').count()];
+    // A preorder walk meets the outermost node starting on a line first.
+    ast::walk_tree(root_node, |node, depth| {
+        if depth == 0 || (params.skip_anonymous && !node.is_named()) {
+            return;
+        }
+        match lines.get_mut(node.start_position().row) {
+            Some(line) if line.is_none() => *line = Some(node.kind().to_string()),
+            _ => {}
+        }
+    });
+    Ok(lines)
+}
+
+pub fn parse_sub_range(
+    parser: &mut Parser,
+    params: ParseSubRangeParams,
+    max_depth: usize,
+) -> Result<AstNode, ResponseError> {
+    let code = params
+        .code
+        .get(params.range_start_byte..params.range_end_byte)
+        .ok_or_else(|| error("invalid byte range"))?;
+    let tree = parse(parser, params.language, code.as_bytes())?;
+    let to_position = |point: Point| Position {
+        line: point.row + params.line_offset,
+        character: match point.row {
+            0 => point.column + params.column_offset,
+            _ => point.column,
+        },
+        byte: None,
+    };
+    params.filter.warn_unknown(params.language.to_tree_sitter());
+    ast::build_tree(tree.root_node(), &to_position, max_depth, &params.filter)
+}
+
+pub fn get_keyword_at(
+    parser: &mut Parser,
+    params: GetKeywordAtParams,
+) -> Result<GetKeywordAtResponse, ResponseError> {
+    let tree = parse(parser, params.language, params.code.as_bytes())?;
+    match descendant_at(tree.root_node(), &params.cursor_position) {
+        None => Err(error("ast parse fail")),
+        Some(node) => Ok(GetKeywordAtResponse {
+            node: ast::format_node(node),
+            kind: node.kind().to_string(),
+            is_keyword: ast::is_keyword(node),
+        }),
+    }
+}
+
+pub fn run_query(
+    parser: &mut Parser,
+    params: RunQueryParams,
+    queries: &QueryCache,
+) -> Result<Vec<Capture>, ResponseError> {
+    let query = query::compile(params.language, &params.query)?;
+    let document_uri = match params.document_uri {
+        Some(document_uri) => document_uri,
+        None => {
+            let tree = parse(parser, params.language, params.code.as_bytes())?;
+            return query::run(&query, tree.root_node(), params.code.as_bytes());
+        }
+    };
+
+    let depths = query::root_depths(&query, &params.query);
+    let key = (document_uri, params.language, params.query);
+    let code = params.code.as_bytes();
+    let (tree, matches) = match queries.take(&key) {
+        Some(entry) if entry.code == params.code => (entry.tree, entry.matches),
+        Some(entry) => {
+            let edit = single_edit(entry.code.as_bytes(), code);
+            let mut old_tree = entry.tree;
+            old_tree.edit(&edit);
+            let tree = reparse(parser, params.language, code, &old_tree)?;
+            let matches = query::rerun(
+                &query,
+                &depths,
+                entry.matches,
+                &edit,
+                &old_tree,
+                &tree,
+                &params.code,
+            )?;
+            (tree, matches)
+        }
+        None => {
+            let tree = parse(parser, params.language, code)?;
+            let matches = query::matches(&query, &depths, tree.root_node(), code, None)?;
+            (tree, matches)
+        }
+    };
+    let captures = query::captures(&matches);
+    queries.insert(key, QueryEntry { code: params.code, tree, matches });
+    Ok(captures)
+}
+
+/// Lists the embedded-language regions of the document, which clients can
+/// then parse with `ParseSubRange`. Empty for grammars without an injection
+/// query.
+pub fn get_injection_regions(
+    parser: &mut Parser,
+    params: GetInjectionRegionsParams,
+) -> Result<Vec<InjectionRegion>, ResponseError> {
+    let info = params.language.info();
+    let tree = parse(parser, params.language, params.code.as_bytes())?;
+    if info.injection_query.is_empty() {
+        return Ok(Vec::new());
+    }
+    let query = query::compile(params.language, &info.injection_query.join("\n"))?;
+    Ok(injections::collect(&query, tree.root_node(), params.code.as_bytes()))
+}
+
+/// `ParseAstInRange` and `RunQuery` over a single parse of `code`.
+pub fn parse_and_query(
+    parser: &mut Parser,
+    params: ParseAndQueryParams,
+) -> Result<ParseAndQueryResponse, ResponseError> {
+    let query = query::compile(params.language, &params.query)?;
+    let code = params.code.as_bytes();
+    let tree = parse(parser, params.language, code)?;
+    Ok(ParseAndQueryResponse {
+        node: node_at_cursor(&tree, code, &params.cursor_position, &params.options)?,
+        captures: query::run(&query, tree.root_node(), code)?,
+    })
+}
+
+/// The sexp of the subtree at `path`, so tree views can expand one branch at
+/// a time.
+pub fn get_tree_sexp_for_subtree(
+    parser: &mut Parser,
+    params: GetTreeSExpForSubtreeParams,
+    max_depth: usize,
+) -> Result<AstBlock, ResponseError> {
+    let code = params.code.as_bytes();
+    let tree = parse(parser, params.language, code)?;
+    let node = node_at_path(tree.root_node(), &params.path)?;
+    if !params.with_positions && !params.with_literals {
+        return Ok(ast::format_node(node));
+    }
+    let literals = SexpLiterals { kinds: params.language.info().literal_kinds, source: code };
+    let options = SexpOptions {
+        with_positions: params.with_positions,
+        literals: params.with_literals.then_some(literals),
+        ..SexpOptions::default()
+    };
+    Ok(AstBlock {
+        ast_result: Some(ast::write_sexp(node, &options, max_depth)?),
+        ..ast::format_range(node)
+    })
+}
+
+/// Follows named-child indices from `root`.
+fn node_at_path<'tree>(root: Node<'tree>, path: &[usize]) -> Result<Node<'tree>, ResponseError> {
+    let mut node = root;
+    for (depth, index) in path.iter().enumerate() {
+        node = node
+            .named_child(*index)
+            .ok_or_else(|| error(&format!("invalid path: no child {index} at depth {depth}")))?;
+    }
+    Ok(node)
+}
+
+/// Finds the delimiter matching the one the cursor is on or just after.
+/// Returns `None` when there is no delimiter there or it is unbalanced.
+pub fn get_bracket_match(
+    parser: &mut Parser,
+    params: GetBracketMatchParams,
+) -> Result<Option<GetBracketMatchResponse>, ResponseError> {
+    const BRACKET_PAIRS: &[(&str, &str)] = &[("(", ")"), ("[", "]"), ("{", "}")];
+    let info = params.language.info();
+    let code = params.code.as_bytes();
+    let tree = parse(parser, params.language, code)?;
+    let root_node = tree.root_node();
+    let cursor = &params.cursor_position;
+    let offset = match cursor.byte {
+        Some(byte) => byte.min(code.len()),
+        None => match point_to_byte(code, Point::from(cursor)) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        },
+    };
+    let pair_of = |node: Node| {
+        BRACKET_PAIRS
+            .iter()
+            .chain(info.bracket_pairs)
+            .find(|(open, close)| node.kind() == *open || node.kind() == *close)
+            .filter(|_| !node.is_named())
+    };
+    let bracket = [Some(offset), offset.checked_sub(1)].into_iter().flatten().find_map(|byte| {
+        let node = root_node.descendant_for_byte_range(byte, byte + 1)?;
+        pair_of(node).map(|pair| (node, pair))
+    });
+    let (bracket, (open, close)) = match bracket {
+        Some(bracket) => bracket,
+        None => return Ok(None),
+    };
+    Ok(ast::matching_bracket(bracket, open, close).map(|matching| GetBracketMatchResponse {
+        bracket: ast::format_token(bracket),
+        matching: ast::format_token(matching),
+    }))
+}
+
+pub fn is_language_supported(
+    params: IsLanguageSupportedParams,
+) -> Result<IsLanguageSupportedResponse, ResponseError> {
+    let language = params.language.parse::<Language>().ok();
+    Ok(IsLanguageSupportedResponse {
+        supported: language.is_some(),
+        canonical: language.map(String::from),
+    })
+}
+
+/// Answers LSP's `textDocument/selectionRange`: for each position, the chain
+/// of node ranges from the innermost node out to the root. Ancestors with
+/// the same range as their child are skipped, since LSP requires each parent
+/// to be strictly larger.
+pub fn selection_ranges_for_positions(
+    parser: &mut Parser,
+    params: SelectionRangesForPositionsParams,
+) -> Result<Vec<SelectionRange>, ResponseError> {
+    let tree = parse(parser, params.language, params.code.as_bytes())?;
+    let root_node = tree.root_node();
+    let ranges = params.positions.iter().map(|position| {
+        let mut ranges: Vec<Range> = Vec::new();
+        let mut node = descendant_at(root_node, position);
+        while let Some(current) = node {
+            let range = Range {
+                start: current.start_position().into(),
+                end: current.end_position().into(),
+            };
+            if ranges.last() != Some(&range) {
+                ranges.push(range);
+            }
+            node = current.parent();
+        }
+        let mut selection: Option<Box<SelectionRange>> = None;
+        for range in ranges.into_iter().rev() {
+            selection = Some(Box::new(SelectionRange { range, parent: selection }));
+        }
+        match selection {
+            Some(selection) => *selection,
+            // Only when no node contains the position, e.g. past the end.
+            None => {
+                let range = Range { start: position.clone(), end: position.clone() };
+                SelectionRange { range, parent: None }
+            }
+        }
+    });
+    Ok(ranges.collect())
+}
+
+/// Parses a batch of files, reporting on each as much as `mode` asks for.
+/// A file that fails doesn't fail the batch; its result carries the error.
+pub fn parse_many(
+    parser: &mut Parser,
+    params: ParseManyParams,
+    max_depth: usize,
+) -> Result<Vec<ParseManyResult>, ResponseError> {
+    let mode = params.mode;
+    let filter = params.filter;
+    let results = params.files.into_iter().map(|file| {
+        let mut result = ParseManyResult {
+            id: file.id,
+            validation: None,
+            errors: None,
+            tree: None,
+            error: None,
+        };
+        let tree = match &file.file_path {
+            Some(path) => parse_file(parser, file.language, path),
+            None => parse(parser, file.language, file.code.as_bytes()),
+        };
+        let outcome = tree.and_then(|tree| {
+            let root_node = tree.root_node();
+            result.validation = Some(validate(root_node));
+            if mode != ParseManyMode::Validate {
+                result.errors = Some(ast::collect_errors(root_node));
+            }
+            if mode == ParseManyMode::Tree {
+                filter.warn_unknown(file.language.to_tree_sitter());
+                let tree = ast::build_tree(root_node, &Position::from, max_depth, &filter)?;
+                result.tree = Some(tree);
+            }
+            Ok(())
+        });
+        if let Err(err) = outcome {
+            result.error = Some(err.message);
+        }
+        result
+    });
+    Ok(results.collect())
+}
+
+/// Walks up from the node at the cursor, itself included, to the first
+/// node whose kind is one of `stop_kinds`.
+pub fn get_node_parent_until_kind(
+    parser: &mut Parser,
+    params: GetNodeParentUntilKindParams,
+) -> Result<AstBlock, ResponseError> {
+    let tree = parse(parser, params.language, params.code.as_bytes())?;
+    let mut node = descendant_at(tree.root_node(), &params.cursor_position);
+    while let Some(current) = node {
+        if params.stop_kinds.iter().any(|kind| kind == current.kind()) {
+            return Ok(ast::format_node(current));
+        }
+        node = current.parent();
+    }
+    Err(error("no enclosing node of the given kinds"))
+}
+
+/// A formatting-independent fingerprint of the file's structure, for use as
+/// a cache key.
+pub fn get_ast_fingerprint(
+    parser: &mut Parser,
+    params: GetAstFingerprintParams,
+) -> Result<GetAstFingerprintResponse, ResponseError> {
+    let tree = parse(parser, params.language, params.code.as_bytes())?;
+    let fingerprint = ast::fingerprint(tree.root_node(), params.include_field_names);
+    Ok(GetAstFingerprintResponse { fingerprint: format!("{fingerprint:032x}") })
+}
+
+/// `GetAstFingerprint` for the subtree at `path`, so clients watching one
+/// region can poll it without transferring its sexp. Like the fingerprint,
+/// it ignores token text.
+pub fn get_sexp_hash(
+    parser: &mut Parser,
+    params: GetSExpHashParams,
+) -> Result<GetAstFingerprintResponse, ResponseError> {
+    let tree = parse(parser, params.language, params.code.as_bytes())?;
+    let node = node_at_path(tree.root_node(), &params.path)?;
+    let fingerprint = ast::fingerprint(node, params.include_field_names);
+    Ok(GetAstFingerprintResponse { fingerprint: format!("{fingerprint:032x}") })
+}
+
+/// The token under the cursor, where `ParseAstInRange` gives the smallest
+/// named node, which may be a composite like a call expression.
+pub fn get_leaf_at_position(
+    parser: &mut Parser,
+    params: GetLeafAtPositionParams,
+) -> Result<GetLeafAtPositionResponse, ResponseError> {
+    let code = params.code.as_bytes();
+    let tree = parse(parser, params.language, code)?;
+    let cursor = &params.cursor_position;
+    let offset = match cursor.byte {
+        Some(byte) => byte,
+        None => point_to_byte(code, Point::from(cursor)).ok_or_else(|| error("ast parse fail"))?,
+    };
+    let mut node =
+        named_descendant_at(tree.root_node(), cursor).ok_or_else(|| error("ast parse fail"))?;
+    // Stops early if the cursor is in whitespace between the node's children.
+    while let Some(child) = (0..node.child_count())
+        .filter_map(|i| node.child(i))
+        .find(|child| child.start_byte() <= offset && offset < child.end_byte())
+    {
+        node = child;
+    }
+    Ok(GetLeafAtPositionResponse {
+        node: ast::format_node(node),
+        kind: node.kind().to_string(),
+        is_named: node.is_named(),
+    })
+}
+
+/// The indented block enclosing the cursor, for tools that move or reindent
+/// it as a unit. `None` if the cursor isn't inside one.
+pub fn get_whitespace_sensitive_range(
+    parser: &mut Parser,
+    params: GetWhitespaceSensitiveRangeParams,
+) -> Result<Option<GetWhitespaceSensitiveRangeResponse>, ResponseError> {
+    let block_kinds = params.language.info().indented_block_kinds;
+    if block_kinds.is_empty() {
+        return Err(error("language has no indented blocks"));
+    }
+    let code = params.code.as_bytes();
+    let tree = parse(parser, params.language, code)?;
+    let mut node = descendant_at(tree.root_node(), &params.cursor_position);
+    while let Some(current) = node {
+        if block_kinds.contains(&current.kind()) {
+            let end_byte = extend_over_blank_lines(code, current.end_byte());
+            return Ok(Some(GetWhitespaceSensitiveRangeResponse {
+                kind: current.kind().to_string(),
+                range: Range {
+                    start: current.start_position().into(),
+                    end: byte_to_point(code, end_byte).into(),
+                },
+                start_byte: current.start_byte(),
+                end_byte,
+            }));
+        }
+        node = current.parent();
+    }
+    Ok(None)
+}
+
+/// Moves `end` past the rest of its line and any blank lines after it, as
+/// long as they hold only whitespace.
+fn extend_over_blank_lines(code: &[u8], end: usize) -> usize {
+    let mut end = end;
+    while let Some(len) = code[end..].iter().position(|b| *b == b'\n') {
+        if !code[end..end + len].iter().all(u8::is_ascii_whitespace) {
+            break;
+        }
+        end += len + 1;
+    }
+    end
+}
+
+/// The field children of the smallest named node at the cursor, keyed by
+/// field name, e.g. `name`, `parameters` and `body` for a Python function.
+/// A field holding several children maps to the first.
+pub fn get_fields(
+    parser: &mut Parser,
+    params: GetFieldsParams,
+) -> Result<BTreeMap<String, AstBlock>, ResponseError> {
+    let tree = parse(parser, params.language, params.code.as_bytes())?;
+    let node = named_descendant_at(tree.root_node(), &params.cursor_position)
+        .ok_or_else(|| error("ast parse fail"))?;
+    let language = tree.language();
+    // Field ids start at 1.
+    let fields = (1..=language.field_count() as u16).filter_map(|id| {
+        let name = language.field_name_for_id(id)?;
+        let child = node.child_by_field_id(id)?;
+        Some((name.to_string(), ast::format_node(child)))
+    });
+    Ok(fields.collect())
+}
+
+/// The named nodes the selection covers completely, e.g. the statements the
+/// user selected, as the children of the smallest node enclosing it.
+/// Partially selected nodes are left out.
+pub fn get_ast_for_selection(
+    parser: &mut Parser,
+    params: GetAstForSelectionParams,
+) -> Result<Vec<AstBlock>, ResponseError> {
+    let code = params.code.as_bytes();
+    let tree = parse(parser, params.language, code)?;
+    let to_byte = |position: &Position| match position.byte {
+        Some(byte) => Some(byte.min(code.len())),
+        None => point_to_byte(code, Point::from(position)),
+    };
+    let (start, end) = match (to_byte(&params.start), to_byte(&params.end)) {
+        (Some(start), Some(end)) if start <= end => (start, end),
+        _ => return Err(error("invalid selection")),
+    };
+    let contained = |node: &Node| start <= node.start_byte() && node.end_byte() <= end;
+    let mut parent = tree
+        .root_node()
+        .descendant_for_byte_range(start, end)
+        .ok_or_else(|| error("ast parse fail"))?;
+    // A node matching the selection exactly should be returned itself, not
+    // its children.
+    while contained(&parent) {
+        match parent.parent() {
+            Some(grandparent) => parent = grandparent,
+            None => break,
+        }
+    }
+    let mut cursor = parent.walk();
+    let nodes =
+        parent.named_children(&mut cursor).filter(contained).map(ast::format_node).collect();
+    Ok(nodes)
+}
+
+/// The first `ERROR` or `MISSING` node starting after the cursor, for "go to
+/// next error".
+pub fn get_next_error(
+    parser: &mut Parser,
+    params: NavigateErrorParams,
+) -> Result<Option<AstBlock>, ResponseError> {
+    navigate_error(parser, params, true)
+}
+
+/// The last `ERROR` or `MISSING` node starting before the cursor.
+pub fn get_prev_error(
+    parser: &mut Parser,
+    params: NavigateErrorParams,
+) -> Result<Option<AstBlock>, ResponseError> {
+    navigate_error(parser, params, false)
+}
+
+fn navigate_error(
+    parser: &mut Parser,
+    params: NavigateErrorParams,
+    forward: bool,
+) -> Result<Option<AstBlock>, ResponseError> {
+    let code = params.code.as_bytes();
+    let tree = parse(parser, params.language, code)?;
+    let cursor = &params.cursor_position;
+    let offset = match cursor.byte {
+        Some(byte) => byte.min(code.len()),
+        None => point_to_byte(code, Point::from(cursor)).unwrap_or(code.len()),
+    };
+    // Errors are in document order. Strict comparisons keep repeated calls
+    // from sticking to the error the cursor was moved to.
+    let errors = ast::error_nodes(tree.root_node());
+    let error = if forward {
+        errors.into_iter().find(|node| node.start_byte() > offset)
+    } else {
+        errors.into_iter().rev().find(|node| node.start_byte() < offset)
+    };
+    Ok(error.map(ast::format_node))
+}
+
+/// The smallest named node containing each of `ranges`, in the same order,
+/// from a single parse. Ranges outside the code map to `None`.
+pub fn nodes_for_ranges(
+    parser: &mut Parser,
+    params: NodesForRangesParams,
+) -> Result<Vec<Option<AstBlock>>, ResponseError> {
+    let code = params.code.as_bytes();
+    let tree = parse(parser, params.language, code)?;
+    let root_node = tree.root_node();
+    let to_byte = |position: &Position| match position.byte {
+        Some(byte) => Some(byte.min(code.len())),
+        None => point_to_byte(code, Point::from(position)),
+    };
+    let nodes = params.ranges.iter().map(|range| {
+        let start = to_byte(&range.start)?;
+        let end = to_byte(&range.end)?.max(start);
+        root_node.named_descendant_for_byte_range(start, end).map(ast::format_node)
+    });
+    Ok(nodes.collect())
+}
+
+/// The list the named node at the cursor belongs to, e.g. all arguments of
+/// a call, for "move item up/down" commands.
+pub fn get_siblings_in_range(
+    parser: &mut Parser,
+    params: GetSiblingsInRangeParams,
+) -> Result<GetSiblingsInRangeResponse, ResponseError> {
+    let tree = parse(parser, params.language, params.code.as_bytes())?;
+    let node = named_descendant_at(tree.root_node(), &params.cursor_position)
+        .ok_or_else(|| error("ast parse fail"))?;
+    // The root is alone in its list.
+    let siblings: Vec<Node> = match node.parent() {
+        Some(parent) => {
+            let mut cursor = parent.walk();
+            parent.named_children(&mut cursor).collect()
+        }
+        None => vec![node],
+    };
+    let index = siblings
+        .iter()
+        .position(|sibling| sibling.id() == node.id())
+        .ok_or_else(|| error("ast parse fail"))?;
+    Ok(GetSiblingsInRangeResponse {
+        siblings: siblings.into_iter().map(ast::format_node).collect(),
+        index,
+    })
+}
+
+/// Summarizes the structural edits from `old_code` to `new_code` as counts
+/// of named nodes by kind.
+///
+/// The new code is parsed incrementally from the old tree, edited with the
+/// single edit spanning everything between their common prefix and suffix.
+/// Nodes lying wholly inside a changed range are counted on the new side,
+/// and those inside the matching old range on the old side.
+pub fn get_ast_diff_summary(
+    parser: &mut Parser,
+    params: GetAstDiffSummaryParams,
+) -> Result<GetAstDiffSummaryResponse, ResponseError> {
+    let old_code = params.old_code.as_bytes();
+    let new_code = params.new_code.as_bytes();
+    let old_tree = parse(parser, params.language, old_code)
+        .map_err(|err| error(&format!("old code: {}", err.message)))?;
+    let edit = single_edit(old_code, new_code);
+    let mut edited = old_tree.clone();
+    edited.edit(&edit);
+    let new_tree = reparse(parser, params.language, new_code, &edited)
+        .map_err(|err| error(&format!("new code: {}", err.message)))?;
+
+    let mut new_ranges: Vec<(usize, usize)> =
+        edited.changed_ranges(&new_tree).map(|range| (range.start_byte, range.end_byte)).collect();
+    // Text-only edits don't always change the tree's shape.
+    new_ranges.push((edit.start_byte, edit.new_end_byte));
+    // Maps a byte of the new code back to the old, clamping bytes inside
+    // the edit to the old edit range. A pure deletion leaves the edit empty
+    // in the new code, so range ends map past the deleted text rather than
+    // before it.
+    let to_old = |byte: usize, is_end: bool| {
+        if byte < edit.start_byte || (byte == edit.start_byte && !is_end) {
+            byte
+        } else if byte >= edit.new_end_byte {
+            byte - edit.new_end_byte + edit.old_end_byte
+        } else {
+            edit.old_end_byte.min(byte)
+        }
+    };
+    let old_ranges: Vec<(usize, usize)> =
+        new_ranges.iter().map(|(start, end)| (to_old(*start, false), to_old(*end, true))).collect();
+
+    let mut counts: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+    for kind in kinds_within(old_tree.root_node(), &old_ranges) {
+        counts.entry(kind.to_string()).or_default().0 += 1;
+    }
+    for kind in kinds_within(new_tree.root_node(), &new_ranges) {
+        counts.entry(kind.to_string()).or_default().1 += 1;
+    }
+    let kinds: Vec<KindDiff> = counts
+        .into_iter()
+        .map(|(kind, (old, new))| KindDiff {
+            kind,
+            added: new.saturating_sub(old),
+            removed: old.saturating_sub(new),
+            changed: old.min(new),
+        })
+        .collect();
+    let summary = kinds
+        .iter()
+        .flat_map(|diff| {
+            let added = (diff.added > 0).then(|| format!("+{} {}", diff.added, diff.kind));
+            let removed = (diff.removed > 0).then(|| format!("-{} {}", diff.removed, diff.kind));
+            added.into_iter().chain(removed)
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    Ok(GetAstDiffSummaryResponse { kinds, summary })
+}
+
+/// The edit turning `old` into `new`, covering everything between their
+/// longest common prefix and suffix.
+fn single_edit(old: &[u8], new: &[u8]) -> InputEdit {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_end_byte = old.len() - suffix;
+    let new_end_byte = new.len() - suffix;
+    InputEdit {
+        start_byte: prefix,
+        old_end_byte,
+        new_end_byte,
+        start_position: byte_to_point(old, prefix),
+        old_end_position: byte_to_point(old, old_end_byte),
+        new_end_position: byte_to_point(new, new_end_byte),
+    }
+}
+
+/// The kinds of the non-empty named nodes lying wholly inside one of
+/// `ranges`, once per node.
+fn kinds_within(root: Node, ranges: &[(usize, usize)]) -> Vec<&'static str> {
+    let mut kinds = Vec::new();
+    ast::walk_tree(root, |node, _| {
+        let inside = ranges
+            .iter()
+            .any(|(start, end)| *start <= node.start_byte() && node.end_byte() <= *end);
+        if node.is_named() && inside && node.start_byte() < node.end_byte() {
+            kinds.push(node.kind());
+        }
+    });
+    kinds
+}
+
+/// The sexp of the tree pruned down to `keepKinds`, keeping their nesting,
+/// for compact structural outlines.
+pub fn get_sexp_for_kinds(
+    parser: &mut Parser,
+    params: GetSExpForKindsParams,
+    max_depth: usize,
+) -> Result<GetSExpForKindsResponse, ResponseError> {
+    // An empty filter keeps everything, which is no outline.
+    if params.keep_kinds.is_empty() {
+        return Err(error("keepKinds is empty"));
+    }
+    let tree = parse(parser, params.language, params.code.as_bytes())?;
+    let filter = KindFilter { include_kinds: params.keep_kinds, exclude_kinds: Vec::new() };
+    filter.warn_unknown(params.language.to_tree_sitter());
+    Ok(GetSExpForKindsResponse {
+        ast_result: ast::write_pruned_sexp(tree.root_node(), &filter, max_depth)?,
+    })
+}
+
+/// Every node whose text matches `text`, in document order, for structural
+/// search without writing a query. Nested nodes with the same text, such
+/// as an identifier and the expression statement wrapping it, all match
+/// unless `kinds` narrows them down.
+pub fn find_by_text(
+    parser: &mut Parser,
+    params: FindByTextParams,
+) -> Result<Vec<AstBlock>, ResponseError> {
+    if params.text.is_empty() {
+        return Err(error("text is empty"));
+    }
+    let source = params.code.as_bytes();
+    let tree = parse(parser, params.language, source)?;
+    let mut matches = Vec::new();
+    ast::walk_tree(tree.root_node(), |node, _| {
+        if !params.kinds.is_empty() && !params.kinds.iter().any(|kind| kind == node.kind()) {
+            return;
+        }
+        let is_match = match node.utf8_text(source) {
+            Ok(text) if params.substring => text.contains(&params.text),
+            Ok(text) => text == params.text,
+            Err(_) => false,
+        };
+        if is_match {
+            matches.push(ast::format_node(node));
+        }
+    });
+    Ok(matches)
+}
+
+/// The tree in Graphviz DOT format, ready to pipe into `dot`.
+pub fn get_ast_as_dot(
+    parser: &mut Parser,
+    params: GetAstAsDotParams,
+) -> Result<GetAstAsDotResponse, ResponseError> {
+    let tree = parse(parser, params.language, params.code.as_bytes())?;
+    let dot = ast::write_dot(tree.root_node(), params.named_only, params.field_names);
+    Ok(GetAstAsDotResponse { dot })
+}
+
+/// Emits the tokens on the requested lines in LSP's delta-encoded
+/// `semanticTokens` format, typed by the language's default kind mapping.
+pub fn semantic_tokens(
+    parser: &mut Parser,
+    params: SemanticTokensParams,
+) -> Result<SemanticTokensResponse, ResponseError> {
+    if params.start_line > params.end_line {
+        return Err(error("startLine is after endLine"));
+    }
+    let tree = parse(parser, params.language, params.code.as_bytes())?;
+    let data = semantic_tokens::encode(
+        tree.root_node(),
+        &params.code,
+        params.language.info(),
+        params.start_line,
+        params.end_line,
+        params.position_encoding,
+    );
+    let token_types = semantic_tokens::TOKEN_TYPES.iter().map(|name| name.to_string()).collect();
+    Ok(SemanticTokensResponse { token_types, data })
+}
+
+/// Maps each supertype kind of the grammar, such as `_expression`, to the
+/// kinds it stands for, from the grammar's `node-types.json`. A subtype may
+/// itself be a supertype. Empty for grammars that define none.
+pub fn get_supertypes(
+    params: GetSupertypesParams,
+) -> Result<BTreeMap<String, Vec<String>>, ResponseError> {
+    let entries: Vec<NodeTypeEntry> = serde_json::from_str(params.language.info().node_types)
+        .map_err(|err| error(&format!("invalid node types: {err}")))?;
+    let supertypes = entries.into_iter().filter_map(|entry| {
+        let subtypes = entry.subtypes?.into_iter().map(|subtype| subtype.kind).collect();
+        Some((entry.kind, subtypes))
+    });
+    Ok(supertypes.collect())
+}
+
+/// Maps each highlight capture name to the node kinds it covers, so thin
+/// clients can theme by capture name without running the query.
+pub fn get_scope_map(params: GetScopeMapParams) -> Result<ScopeMap, ResponseError> {
+    Ok(highlights::scope_map(params.language))
+}
+
+/// Checks that each of `languages` loads, leaving an idle parser set to it
+/// in `parsers`. Reports each language's outcome in the order given.
+///
+/// A language given twice, perhaps under an alias, is warmed up once. At
+/// most `max_languages` are, since no more parsers than that run at once.
+pub fn warm_up(
+    parsers: &ParserPool,
+    params: WarmUpParams,
+    max_languages: usize,
+) -> Result<Vec<WarmUpResult>, ResponseError> {
+    // The outcome of each language warmed up so far.
+    let mut warmed: Vec<(Language, Option<String>)> = Vec::new();
+    let results = params
+        .languages
+        .into_iter()
+        .map(|name| {
+            let error = match name.parse::<Language>() {
+                Ok(language) => match warmed.iter().find(|(warm, _)| *warm == language) {
+                    Some((_, error)) => error.clone(),
+                    None if warmed.len() >= max_languages => {
+                        Some(format!("at most {max_languages} languages are warmed up at once"))
+                    }
+                    None => {
+                        let error = (!parsers.warm(language))
+                            .then(|| format!("failed to load the {language} grammar"));
+                        warmed.push((language, error.clone()));
+                        error
+                    }
+                },
+                Err(err) => Some(err.to_string()),
+            };
+            WarmUpResult { language: name, ok: error.is_none(), error }
+        })
+        .collect();
+    Ok(results)
+}
+
+/// Reloads a dynamically loaded grammar from its library.
+///
+/// Every grammar is compiled in for now, so this only ever reports that;
+/// it reserves the method for when grammars can be loaded at runtime.
+pub fn reload_grammar(params: ReloadGrammarParams) -> Result<(), ResponseError> {
+    match params.name.parse::<Language>() {
+        Ok(_) => Err(error("static grammar cannot be reloaded")),
+        Err(err) => Err(error(&err.to_string())),
+    }
+}
+
+/// Reports which grammars the parser accepts. Grammars are compiled in, so
+/// this only fails for one built against an incompatible tree-sitter ABI.
+pub fn health(parser: &mut Parser) -> HealthResponse {
+    let languages: Vec<LanguageHealth> = Language::ALL
+        .iter()
+        .map(|language| LanguageHealth {
+            name: language.name().to_string(),
+            loaded: parser.set_language(language.to_tree_sitter()).is_ok(),
+        })
+        .collect();
+    let status = if languages.iter().all(|language| language.loaded) {
+        HealthStatus::Ok
+    } else {
+        HealthStatus::Degraded
+    };
+    HealthResponse { status, languages }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::de::DeserializeOwned;
+    use serde_json::json;
+
+    use super::*;
+
+    /// Deserializes `params` as the server does, so tests only spell out
+    /// the fields they care about.
+    fn params<P: DeserializeOwned>(params: serde_json::Value) -> P {
+        serde_json::from_value(params).unwrap()
+    }
+
+    #[test]
+    fn get_error_recovery_tree_marks_error_nodes_with_their_ranges() {
+        let code = "def f(:\n    pass\n";
+        let params = params(json!({ "language": "python", "code": code }));
+        let response = get_error_recovery_tree(&mut Parser::new(), params, 100).unwrap();
+        assert!(!response.errors.is_empty());
+        for err in &response.errors {
+            let (start, end) = (&err.start_point, &err.end_point);
+            let range =
+                format!("[{},{}]-[{},{}]", start.line, start.character, end.line, end.character);
+            assert!(response.ast_result.contains(&range), "{range} in {}", response.ast_result);
+        }
+    }
+
+    #[test]
+    fn get_error_recovery_tree_reports_nothing_for_clean_code() {
+        let params = params(json!({ "language": "python", "code": "def f():\n    pass\n" }));
+        let response = get_error_recovery_tree(&mut Parser::new(), params, 100).unwrap();
+        assert!(response.errors.is_empty());
+        assert!(!response.ast_result.contains('['), "{}", response.ast_result);
+    }
+
+    fn run_query_params(query: &str, code: &str, document_uri: Option<&str>) -> RunQueryParams {
+        RunQueryParams {
+            language: Language::JavaScript,
+            code: code.to_string(),
+            query: query.to_string(),
+            document_uri: document_uri.map(str::to_string),
+        }
+    }
+
+    /// Runs `query` over each of `versions` of a document in turn, checking
+    /// that rerunning it over what changed gives what a full run does.
+    fn assert_rerun_matches_full_run(query: &str, versions: &[&str]) {
+        let mut parser = Parser::new();
+        let queries = QueryCache::new(1);
+        for code in versions {
+            let params = run_query_params(query, code, Some("file:///a.js"));
+            let incremental = run_query(&mut parser, params, &queries).unwrap();
+            let params = run_query_params(query, code, None);
+            let full = run_query(&mut parser, params, &queries).unwrap();
+            assert_eq!(incremental, full, "for {code:?}");
+        }
+    }
+
+    #[test]
+    fn run_query_rerun_shifts_matches_after_the_edit() {
+        assert_rerun_matches_full_run(
+            "(function_declaration name: (identifier) @name)",
+            &[
+                "function a() {}\nfunction b() {}\n",
+                "function a() {}\nfunction c() { return 1; }\nfunction b() {}\n",
+                "function a() { let x = 2; }\nfunction c() { return 1; }\nfunction b() {}\n",
+                "function a() {}\nfunction b() {}\n",
+            ],
+        );
+    }
+
+    #[test]
+    fn run_query_rerun_drops_matches_whose_uncaptured_nodes_changed() {
+        // The edit only touches the string, which isn't captured.
+        assert_rerun_matches_full_run(
+            "(call_expression function: (identifier) @f arguments: (arguments (string)))",
+            &["foo(\"a\");\nbar(\"b\");\n", "foo(\"a\");\nbar(1);\n", "foo(\"a\");\nbar(\"c\");\n"],
+        );
+    }
+
+    #[test]
+    fn run_query_rerun_rechecks_predicates() {
+        assert_rerun_matches_full_run(
+            "((call_expression function: (identifier) @f) (#eq? @f \"foo\"))",
+            &["foo();\nbar();\n", "foo();\nfoo();\n", "fob();\nfoo();\n"],
+        );
+    }
+
+    /// `ParseAstInRange` on Python `code` at `line:character`, with the
+    /// params in `extra` on top, expecting a node.
+    fn node_at(
+        code: &str,
+        line: usize,
+        character: usize,
+        extra: serde_json::Value,
+    ) -> ParseAstInRangeResponse {
+        let mut request = json!({
+            "language": "python",
+            "code": code,
+            "cursorPosition": { "line": line, "character": character },
+        });
+        request.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        match parse_ast_in_range(&mut Parser::new(), params(request), &TreeCache::new(0)).unwrap() {
+            ParseAstInRangeResult::Node(response) => *response,
+            result => panic!("expected a node, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_ast_in_range_bytes_answers_as_the_json_path_does() {
+        let code = "def f(x):\n    return x + 1\n";
+        let request: ParseAstInRangeParams = params(json!({
+            "language": "python",
+            "code": code,
+            "cursorPosition": { "line": 1, "character": 11 },
+        }));
+        let response = parse_ast_in_range_bytes(
+            &mut Parser::new(),
+            request.language,
+            code.as_bytes(),
+            &request.cursor_position,
+            &request.options,
+            &TreeCache::new(0),
+            None,
+        )
+        .unwrap();
+        assert_eq!(response, node_at(code, 1, 11, json!({})));
+        assert_eq!(response.node.ast_result.as_deref(), Some("(identifier)"));
+    }
+
+    #[test]
+    fn offset_to_position_and_position_to_offset_round_trip() {
+        // `é` is two bytes and one UTF-16 unit, `😀` four bytes and two.
+        let code = "aé\n😀b\n";
+        for (offset, line, character) in [(0, 0, 0), (3, 0, 2), (4, 1, 0), (8, 1, 2), (10, 2, 0)] {
+            let request = json!({ "code": code, "offset": offset, "positionEncoding": "utf-16" });
+            let position = offset_to_position(params(request)).unwrap();
+            assert_eq!(position, Position { line, character, byte: None });
+            let request =
+                json!({ "code": code, "position": position, "positionEncoding": "utf-16" });
+            assert_eq!(position_to_offset(params(request)).unwrap().offset, offset);
+        }
+    }
+
+    #[test]
+    fn offset_to_position_rejects_offsets_inside_a_character_or_past_the_end() {
+        for offset in [2, 11] {
+            let request = json!({ "code": "aé\n😀b\n", "offset": offset });
+            assert!(offset_to_position(params(request)).is_err(), "{offset}");
+        }
+    }
+
+    fn start(block: &AstBlock) -> (usize, usize) {
+        (block.start_point.line, block.start_point.character)
+    }
+
+    #[test]
+    fn resolve_local_finds_the_definition_in_the_innermost_scope() {
+        let code = "let x = 1;\nfunction f() { let x = 2; return x; }\nx;\n";
+        let resolve = |line: usize, character: usize| {
+            let request = json!({
+                "language": "javascript",
+                "code": code,
+                "cursorPosition": { "line": line, "character": character },
+            });
+            let response = resolve_local(&mut Parser::new(), params(request)).unwrap();
+            let references: Vec<_> = response.references.iter().map(start).collect();
+            (response.definition.as_ref().map(start), references)
+        };
+        assert_eq!(resolve(2, 0), (Some((0, 4)), vec![(2, 0)]));
+        assert_eq!(resolve(1, 33), (Some((1, 19)), vec![(1, 33)]));
+    }
+
+    #[test]
+    fn resolve_local_is_empty_without_a_locals_query() {
+        let request = json!({
+            "language": "python",
+            "code": "x = 1\nx\n",
+            "cursorPosition": { "line": 1, "character": 0 },
+        });
+        let response = resolve_local(&mut Parser::new(), params(request)).unwrap();
+        assert_eq!(response, ResolveLocalResponse { definition: None, references: Vec::new() });
+    }
+
+    #[test]
+    fn highlight_occurrences_finds_every_identifier_with_the_same_name() {
+        let occurrences = |line: usize, character: usize| {
+            let request = json!({
+                "language": "python",
+                "code": "x = 1\ny = x + x\n",
+                "cursorPosition": { "line": line, "character": character },
+            });
+            let blocks = highlight_occurrences(&mut Parser::new(), params(request)).unwrap();
+            blocks.iter().map(start).collect::<Vec<_>>()
+        };
+        assert_eq!(occurrences(1, 4), vec![(0, 0), (1, 4), (1, 8)]);
+        assert_eq!(occurrences(1, 0), vec![(1, 0)]);
+        // On the `=`, which isn't an identifier.
+        assert_eq!(occurrences(0, 2), vec![]);
+    }
+
+    #[test]
+    fn get_comments_returns_each_comment_with_its_range() {
+        let request = json!({ "language": "python", "code": "# a\nx = 1  # b\n" });
+        let comments = get_comments(&mut Parser::new(), params(request)).unwrap();
+        let comments: Vec<_> = comments
+            .iter()
+            .map(|comment| {
+                (comment.text.as_str(), comment.start_point.line, comment.end_point.clone())
+            })
+            .collect();
+        assert_eq!(
+            comments,
+            vec![
+                ("# a", 0, Position { line: 0, character: 3, byte: None }),
+                ("# b", 1, Position { line: 1, character: 10, byte: None }),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_ast_in_range_dry_run_only_validates() {
+        let dry_run = |code: &str| {
+            let request = json!({
+                "language": "python",
+                "code": code,
+                "cursorPosition": { "line": 0, "character": 0 },
+                "dryRun": true,
+            });
+            parse_ast_in_range(&mut Parser::new(), params(request), &TreeCache::new(0)).unwrap()
+        };
+        let clean = ValidationResponse { ok: true, has_error: false, error_count: 0 };
+        assert_eq!(dry_run("x = 1\n"), ParseAstInRangeResult::DryRun(clean));
+        match dry_run("x = (\n") {
+            ParseAstInRangeResult::DryRun(response) => {
+                assert!(!response.ok && response.has_error && response.error_count > 0)
+            }
+            result => panic!("expected a validation, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_ast_in_range_includes_the_root_on_request() {
+        let code = "x = 1\ny = 2\n";
+        assert_eq!(node_at(code, 1, 0, json!({})).root, None);
+        let root = node_at(code, 1, 0, json!({ "includeRoot": true })).root.unwrap();
+        assert_eq!(start(&root), (0, 0));
+        assert_eq!(root.end_point, Position { line: 2, character: 0, byte: None });
+        assert!(root.ast_result.unwrap().starts_with("(module"));
+    }
+
+    #[test]
+    fn parse_ast_in_range_marks_extras() {
+        let code = "x = 1  # c\n";
+        let comment = node_at(code, 0, 8, json!({}));
+        assert_eq!(comment.node.ast_result.as_deref(), Some("(comment)"));
+        assert!(comment.node.is_extra);
+        assert!(!node_at(code, 0, 0, json!({})).node.is_extra);
+    }
+
+    #[test]
+    fn get_named_node_at_each_line_gives_the_outermost_node_per_line() {
+        let lines = |skip_anonymous: bool| {
+            let request = json!({
+                "language": "python",
+                "code": "x = [\n    1,\n]\n\n",
+                "skipAnonymous": skip_anonymous,
+            });
+            get_named_node_at_each_line(&mut Parser::new(), params(request)).unwrap()
+        };
+        let kind = |kind: &str| Some(kind.to_string());
+        let expected = vec![kind("expression_statement"), kind("integer"), None, None, None];
+        assert_eq!(lines(true), expected);
+        let expected = vec![kind("expression_statement"), kind("integer"), kind("]"), None, None];
+        assert_eq!(lines(false), expected);
+    }
+
+    #[test]
+    fn parse_sub_range_shifts_positions_into_the_document() {
+        let sub_range = |start: usize, end: usize| {
+            let request = json!({
+                "language": "python",
+                "code": "a = 1\nb = foo(2)\n",
+                "rangeStartByte": start,
+                "rangeEndByte": end,
+                "lineOffset": 1,
+                "columnOffset": 4,
+            });
+            parse_sub_range(&mut Parser::new(), params(request), 100)
+        };
+        let root = sub_range(10, 16).unwrap();
+        assert_eq!(root.kind, "module");
+        assert_eq!(root.start_point, Position { line: 1, character: 4, byte: None });
+        assert_eq!(root.end_point, Position { line: 1, character: 10, byte: None });
+        assert!(sub_range(10, 100).is_err());
+        assert!(sub_range(16, 10).is_err());
+    }
+
+    #[test]
+    fn get_keyword_at_tells_keywords_from_identifiers_and_punctuation() {
+        let keyword_at = |line: usize, character: usize| {
+            let request = json!({
+                "language": "python",
+                "code": "def f():\n    return x\n",
+                "cursorPosition": { "line": line, "character": character },
+            });
+            let response = get_keyword_at(&mut Parser::new(), params(request)).unwrap();
+            (response.kind, response.is_keyword)
+        };
+        assert_eq!(keyword_at(1, 4), ("return".to_string(), true));
+        assert_eq!(keyword_at(0, 0), ("def".to_string(), true));
+        assert_eq!(keyword_at(1, 11), ("identifier".to_string(), false));
+        assert_eq!(keyword_at(0, 5), ("(".to_string(), false));
+    }
+
+    #[test]
+    fn parse_ast_in_range_leaves_out_the_parent_on_request() {
+        let code = "x = 1\n";
+        let parent = node_at(code, 0, 0, json!({})).parent.unwrap();
+        assert!(parent.ast_result.unwrap().starts_with("(assignment"));
+        assert_eq!(node_at(code, 0, 0, json!({ "includeParent": false })).parent, None);
+    }
+
+    #[test]
+    fn parse_ast_in_range_snaps_a_cursor_in_whitespace_to_the_nearest_token() {
+        let at = |line, character| Some(Position { line, character, byte: None });
+        let code = "x = 1\n\n    \ny = 2\n";
+        for (line, character) in [(1, 0), (2, 2)] {
+            let response = node_at(code, line, character, json!({}));
+            assert!(response.snapped);
+            assert_eq!(response.snapped_position, at(0, 4));
+            assert_eq!(response.node.ast_result.as_deref(), Some("(integer)"));
+        }
+        assert_eq!(node_at("\ny = 2\n", 0, 0, json!({})).snapped_position, at(1, 0));
+
+        let response = node_at(code, 0, 4, json!({}));
+        assert!(!response.snapped);
+        assert_eq!(response.snapped_position, None);
+    }
+
+    #[test]
+    fn get_injection_regions_uses_the_grammars_injection_query() {
+        let code = "html`<p></p>`;\n";
+        let regions = |language: &str| {
+            let request = json!({ "language": language, "code": code });
+            get_injection_regions(&mut Parser::new(), params(request)).unwrap()
+        };
+        let region = &regions("javascript")[0];
+        assert_eq!(region.injected_language, "html");
+        // Grammar versions differ on whether the backticks are included.
+        assert!(code[region.start_byte..region.end_byte].contains("<p></p>"), "{region:?}");
+        assert_eq!(regions("python"), vec![]);
+    }
+
+    #[test]
+    fn parse_and_query_answers_as_the_two_requests_do() {
+        let code = "def f(x):\n    return x\n";
+        let query = "(identifier) @id";
+        let request = json!({
+            "language": "python",
+            "code": code,
+            "query": query,
+            "cursorPosition": { "line": 1, "character": 11 },
+        });
+        let response = parse_and_query(&mut Parser::new(), params(request)).unwrap();
+        // Only `ParseAstInRange` caches the tree, so only it has a fingerprint.
+        let expected =
+            ParseAstInRangeResponse { fingerprint: None, ..node_at(code, 1, 11, json!({})) };
+        assert_eq!(response.node, expected);
+        let request = json!({ "language": "python", "code": code, "query": query });
+        let captures = run_query(&mut Parser::new(), params(request), &QueryCache::new(0));
+        assert_eq!(response.captures, captures.unwrap());
+
+        let request = json!({
+            "language": "python",
+            "code": code,
+            "query": "(nonexistent) @x",
+            "cursorPosition": { "line": 0, "character": 0 },
+        });
+        assert!(parse_and_query(&mut Parser::new(), params(request)).is_err());
+    }
+
+    #[test]
+    fn parse_ast_in_range_hashes_nodes_on_request() {
+        let code = "f(a + 1)\ng(a  +  1)\n";
+        assert_eq!(node_at(code, 0, 2, json!({})).node.content_hash, None);
+        let hash = |line: usize| {
+            let response = node_at(code, line, 4, json!({ "includeHash": true }));
+            assert!(response.node.ast_result.unwrap().starts_with("(binary_operator "));
+            response.node.content_hash.unwrap()
+        };
+        assert_eq!(hash(0), hash(1));
+    }
+
+    #[test]
+    fn parse_ast_in_range_finds_the_same_node_by_byte_or_by_point() {
+        let code = "x = 1\ny = foo(2)\n";
+        let by_point = node_at(code, 1, 4, json!({}));
+        // The byte wins over a point that disagrees with it.
+        let cursor = json!({ "line": 0, "character": 0, "byte": 10 });
+        let by_byte = node_at(code, 0, 0, json!({ "cursorPosition": cursor }));
+        assert_eq!(by_point.node, by_byte.node);
+        assert_eq!(by_point.node.ast_result.as_deref(), Some("(identifier)"));
+    }
+
+    #[test]
+    fn get_tree_sexp_for_subtree_follows_named_child_indices() {
+        let subtree = |path: serde_json::Value| {
+            let request = json!({ "language": "python", "code": "x = 1\ny = 2\n", "path": path });
+            get_tree_sexp_for_subtree(&mut Parser::new(), params(request), 100)
+        };
+        let block = subtree(json!([1, 0])).unwrap();
+        assert_eq!(start(&block), (1, 0));
+        assert!(block.ast_result.unwrap().starts_with("(assignment "));
+        assert!(subtree(json!([])).unwrap().ast_result.unwrap().starts_with("(module "));
+        let err = subtree(json!([1, 0, 5])).unwrap_err();
+        assert_eq!(err.message, "invalid path: no child 5 at depth 2");
+    }
+
+    #[test]
+    fn get_bracket_match_pairs_the_bracket_at_or_before_the_cursor() {
+        let bracket_match = |character: usize| {
+            let request = json!({
+                "language": "javascript",
+                "code": "f(a[0], (b));",
+                "cursorPosition": { "line": 0, "character": character },
+            });
+            get_bracket_match(&mut Parser::new(), params(request)).unwrap().map(|response| {
+                let (bracket, matching) = (response.bracket, response.matching);
+                (bracket.start_point.character, matching.start_point.character, matching.kind)
+            })
+        };
+        assert_eq!(bracket_match(1), Some((1, 11, ")".to_string())));
+        assert_eq!(bracket_match(12), Some((11, 1, "(".to_string())));
+        assert_eq!(bracket_match(4), Some((3, 5, "]".to_string())));
+        assert_eq!(bracket_match(9), Some((8, 10, ")".to_string())));
+        assert_eq!(bracket_match(7), None);
+    }
+
+    #[test]
+    fn get_bracket_match_clamps_byte_offsets_past_the_end_of_the_code() {
+        for byte in [12, 13, usize::MAX] {
+            let request = json!({
+                "language": "javascript",
+                "code": "f(a[0], (b))",
+                "cursorPosition": { "line": 0, "character": 0, "byte": byte },
+            });
+            let response = get_bracket_match(&mut Parser::new(), params(request)).unwrap();
+            let brackets = response.map(|response| (response.bracket.kind, response.matching.kind));
+            assert_eq!(brackets, Some((")".to_string(), "(".to_string())), "byte {byte}");
+        }
+    }
+
+    #[test]
+    fn is_language_supported_resolves_aliases_without_failing_on_unknown_names() {
+        let supported = |language: &str| {
+            let response = is_language_supported(params(json!({ "language": language })));
+            let response = response.unwrap();
+            (response.supported, response.canonical)
+        };
+        assert_eq!(supported("JS"), (true, Some("javascript".to_string())));
+        assert_eq!(supported("cobol"), (false, None));
+    }
+
+    #[test]
+    fn selection_ranges_for_positions_grow_strictly_out_to_the_root() {
+        let request = json!({
+            "language": "python",
+            "code": "x = foo(1)\n",
+            "positions": [{ "line": 0, "character": 8 }, { "line": 0, "character": 0 }],
+        });
+        let selections = selection_ranges_for_positions(&mut Parser::new(), params(request));
+        let chains: Vec<Vec<_>> = selections
+            .unwrap()
+            .iter()
+            .map(|selection| {
+                let mut chain = Vec::new();
+                let mut selection = Some(selection);
+                while let Some(current) = selection {
+                    let (start, end) = (&current.range.start, &current.range.end);
+                    chain.push((start.character, end.line, end.character));
+                    selection = current.parent.as_deref();
+                }
+                chain
+            })
+            .collect();
+        // The expression statement is skipped, spanning what the assignment does.
+        assert_eq!(chains[0], [(8, 0, 9), (7, 0, 10), (4, 0, 10), (0, 0, 10), (0, 1, 0)]);
+        assert_eq!(chains[1], [(0, 0, 1), (0, 0, 10), (0, 1, 0)]);
+    }
+
+    #[test]
+    fn parse_ast_in_range_leaves_out_sexps_in_ranges_only_mode() {
+        let code = "x = 1\n";
+        let full = node_at(code, 0, 4, json!({ "includeRoot": true }));
+        let ranges = node_at(code, 0, 4, json!({ "includeRoot": true, "rangesOnly": true }));
+        let strip = |block: &AstBlock| AstBlock { ast_result: None, ..block.clone() };
+        assert!(full.node.ast_result.is_some());
+        assert_eq!(ranges.node, strip(&full.node));
+        assert_eq!(ranges.parent, full.parent.as_ref().map(strip));
+        assert_eq!(ranges.root, full.root.as_ref().map(strip));
+    }
+
+    #[test]
+    fn parse_many_reports_on_each_file_as_much_as_the_mode_asks() {
+        let files = json!([
+            { "id": "a", "language": "python", "code": "x = 1\n" },
+            { "id": "b", "language": "python", "code": "x = (\n" },
+        ]);
+        let request = json!({ "files": files, "mode": "errors" });
+        let results = parse_many(&mut Parser::new(), params(request), 100).unwrap();
+        let ids: Vec<_> = results.iter().map(|result| result.id.as_str()).collect();
+        assert_eq!(ids, ["a", "b"]);
+        assert!(results[0].validation.as_ref().unwrap().ok);
+        assert_eq!(results[0].errors, Some(Vec::new()));
+        assert!(!results[1].validation.as_ref().unwrap().ok);
+        assert!(!results[1].errors.as_ref().unwrap().is_empty());
+        assert!(results.iter().all(|result| result.tree.is_none() && result.error.is_none()));
+
+        // Only the file that is too deep fails.
+        let files = json!([
+            { "id": "shallow", "language": "python", "code": "pass\n" },
+            { "id": "deep", "language": "python", "code": "x = [[[1]]]\n" },
+        ]);
+        let request = json!({ "files": files, "mode": "tree" });
+        let results = parse_many(&mut Parser::new(), params(request), 3).unwrap();
+        assert_eq!(results[0].tree.as_ref().unwrap().kind, "module");
+        assert_eq!(results[0].error, None);
+        assert_eq!(results[1].tree, None);
+        assert!(results[1].error.as_ref().unwrap().contains("maximum depth of 3"));
+    }
+
+    #[test]
+    fn get_node_parent_until_kind_stops_at_the_first_listed_kind() {
+        let enclosing = |stop_kinds: serde_json::Value| {
+            let request = json!({
+                "language": "python",
+                "code": "def f():\n    return g(1)\n",
+                "cursorPosition": { "line": 1, "character": 13 },
+                "stopKinds": stop_kinds,
+            });
+            get_node_parent_until_kind(&mut Parser::new(), params(request))
+        };
+        let call = enclosing(json!(["call", "function_definition"])).unwrap();
+        assert_eq!(start(&call), (1, 11));
+        let function = enclosing(json!(["function_definition"])).unwrap();
+        assert_eq!(start(&function), (0, 0));
+        // The node at the cursor counts too.
+        assert_eq!(start(&enclosing(json!(["integer"])).unwrap()), (1, 13));
+        let err = enclosing(json!(["class_definition"])).unwrap_err();
+        assert_eq!(err.message, "no enclosing node of the given kinds");
+    }
+
+    #[test]
+    fn get_ast_fingerprint_ignores_formatting_comments_and_token_text() {
+        let fingerprint = |code: &str| {
+            let request = json!({ "language": "python", "code": code });
+            get_ast_fingerprint(&mut Parser::new(), params(request)).unwrap().fingerprint
+        };
+        let original = fingerprint("x = f(1)\n");
+        assert_eq!(original.len(), 32);
+        assert_eq!(fingerprint("x  =  f( 1 )  # call\n"), original);
+        assert_eq!(fingerprint("y = g(2)\n"), original);
+        assert_ne!(fingerprint("x = f(1, 2)\n"), original);
+    }
+
+    #[test]
+    fn get_leaf_at_position_returns_anonymous_tokens_too() {
+        let leaf_at = |character: usize| {
+            let request = json!({
+                "language": "javascript",
+                "code": "f(a, b);",
+                "cursorPosition": { "line": 0, "character": character },
+            });
+            let response = get_leaf_at_position(&mut Parser::new(), params(request)).unwrap();
+            (response.kind, response.is_named)
+        };
+        assert_eq!(leaf_at(1), ("(".to_string(), false));
+        assert_eq!(leaf_at(2), ("identifier".to_string(), true));
+        assert_eq!(leaf_at(3), (",".to_string(), false));
+        assert_eq!(leaf_at(7), (";".to_string(), false));
+    }
+
+    #[test]
+    fn parse_ast_in_range_answers_from_the_cache_for_a_matching_prev_fingerprint() {
+        let trees = TreeCache::new(1);
+        // Long enough for the parser to check its cancellation flag, which
+        // it only does every so many steps.
+        let code = "x = 1\n".repeat(200);
+        let request = |prev_fingerprint: Option<&str>| {
+            params(json!({
+                "language": "python",
+                "code": code,
+                "cursorPosition": { "line": 0, "character": 4 },
+                "prevFingerprint": prev_fingerprint,
+            }))
+        };
+        let first = match parse_ast_in_range(&mut Parser::new(), request(None), &trees).unwrap() {
+            ParseAstInRangeResult::Node(response) => response,
+            result => panic!("expected a node, got {result:?}"),
+        };
+        let fingerprint = first.fingerprint.clone().unwrap();
+
+        // A parser that fails any parse, so only a cache hit succeeds.
+        let flag = std::sync::atomic::AtomicUsize::new(1);
+        let mut parser = Parser::new();
+        unsafe { parser.set_cancellation_flag(Some(&flag)) };
+        let cached = parse_ast_in_range(&mut parser, request(Some(&fingerprint)), &trees);
+        assert_eq!(cached.unwrap(), ParseAstInRangeResult::Node(first));
+        assert!(parse_ast_in_range(&mut parser, request(None), &trees).is_err());
+        assert!(parse_ast_in_range(&mut parser, request(Some("0")), &trees).is_err());
+        unsafe { parser.set_cancellation_flag(None) };
+    }
+
+    #[test]
+    fn health_reports_every_grammar_loaded() {
+        let response = health(&mut Parser::new());
+        assert_eq!(response.status, HealthStatus::Ok);
+        let names: Vec<_> =
+            response.languages.iter().map(|language| language.name.as_str()).collect();
+        let expected: Vec<_> = Language::ALL.iter().map(|language| language.name()).collect();
+        assert_eq!(names, expected);
+        assert!(response.languages.iter().all(|language| language.loaded));
+    }
+
+    #[test]
+    fn get_whitespace_sensitive_range_extends_the_block_over_trailing_blank_lines() {
+        let range = |language: &str, line: usize| {
+            let request = json!({
+                "language": language,
+                "code": "def f():\n    x = 1\n\n    y = 2\n\nz = 3\n",
+                "cursorPosition": { "line": line, "character": 4 },
+            });
+            get_whitespace_sensitive_range(&mut Parser::new(), params(request))
+        };
+        let block = range("python", 3).unwrap().unwrap();
+        assert_eq!(block.kind, "block");
+        assert_eq!((block.start_byte, block.end_byte), (13, 31));
+        assert_eq!(block.range.start, Position { line: 1, character: 4, byte: None });
+        assert_eq!(block.range.end, Position { line: 5, character: 0, byte: None });
+        assert_eq!(range("python", 5).unwrap(), None);
+        assert_eq!(range("javascript", 1).unwrap_err().message, "language has no indented blocks");
+    }
+
+    #[test]
+    fn get_fields_maps_field_names_to_children() {
+        let request = json!({
+            "language": "python",
+            "code": "def f(a):\n    return a\n",
+            "cursorPosition": { "line": 0, "character": 0 },
+        });
+        let fields = get_fields(&mut Parser::new(), params(request)).unwrap();
+        assert_eq!(fields.keys().collect::<Vec<_>>(), ["body", "name", "parameters"]);
+        assert_eq!(fields["name"].ast_result.as_deref(), Some("(identifier)"));
+        assert_eq!(start(&fields["name"]), (0, 4));
+        assert_eq!(start(&fields["body"]), (1, 4));
+    }
+
+    #[test]
+    fn get_ast_for_selection_returns_the_fully_selected_nodes() {
+        let selection = |start: (usize, usize), end: (usize, usize)| {
+            let request = json!({
+                "language": "python",
+                "code": "x = 1\ny = 2\nz = 3\n",
+                "start": { "line": start.0, "character": start.1 },
+                "end": { "line": end.0, "character": end.1 },
+            });
+            get_ast_for_selection(&mut Parser::new(), params(request))
+        };
+        let starts = |blocks: Vec<AstBlock>| blocks.iter().map(start).collect::<Vec<_>>();
+        assert_eq!(starts(selection((0, 0), (1, 5)).unwrap()), [(0, 0), (1, 0)]);
+        // The partly selected first statement is left out.
+        assert_eq!(starts(selection((0, 2), (1, 5)).unwrap()), [(1, 0)]);
+        // An exactly selected statement is returned itself.
+        let exact = selection((0, 0), (0, 5)).unwrap();
+        assert_eq!(exact.len(), 1);
+        assert!(exact[0].ast_result.as_deref().unwrap().starts_with("(expression_statement"));
+        assert_eq!(selection((1, 0), (0, 0)).unwrap_err().message, "invalid selection");
+    }
+
+    #[test]
+    fn next_and_prev_error_step_through_the_errors_in_order() {
+        let code = "a = ];\nb = 1;\nc = ];\n";
+        let mut parser = Parser::new();
+        parser.set_language(Language::JavaScript.to_tree_sitter()).unwrap();
+        let tree = parser.parse(code, None).unwrap();
+        let mut errors: Vec<_> =
+            ast::error_nodes(tree.root_node()).iter().map(|node| node.start_position()).collect();
+        // Nested errors starting together are one stop.
+        errors.dedup();
+        assert!(errors.len() >= 2, "{}", tree.root_node().to_sexp());
+        let navigate = |forward: bool, point: Point| {
+            let request = json!({
+                "language": "javascript",
+                "code": code,
+                "cursorPosition": { "line": point.row, "character": point.column },
+            });
+            let error = if forward {
+                get_next_error(&mut Parser::new(), params(request))
+            } else {
+                get_prev_error(&mut Parser::new(), params(request))
+            };
+            error
+                .unwrap()
+                .map(|block| Point::new(block.start_point.line, block.start_point.character))
+        };
+        let (first, last) = (errors[0], errors[errors.len() - 1]);
+        assert_eq!(navigate(true, Point::new(0, 0)), Some(first));
+        assert_eq!(navigate(true, first), Some(errors[1]));
+        assert_eq!(navigate(true, last), None);
+        assert_eq!(navigate(false, last), Some(errors[errors.len() - 2]));
+        assert_eq!(navigate(false, first), None);
+    }
+
+    #[test]
+    fn with_literals_quotes_the_text_of_literal_nodes_only() {
+        let request = json!({
+            "language": "python",
+            "code": "x = \"hi\"\ny = 42\n",
+            "path": [],
+            "withLiterals": true,
+        });
+        let block = get_tree_sexp_for_subtree(&mut Parser::new(), params(request), 100).unwrap();
+        let sexp = block.ast_result.unwrap();
+        assert!(sexp.contains(r#"right: (string "\"hi\"")"#), "{sexp}");
+        assert!(sexp.contains(r#"right: (integer "42")"#), "{sexp}");
+        assert!(sexp.contains("left: (identifier) "), "{sexp}");
+    }
+
+    #[test]
+    fn reload_grammar_rejects_compiled_in_grammars() {
+        let reload = |name: &str| reload_grammar(params(json!({ "name": name }))).unwrap_err();
+        assert_eq!(reload(" Python ").message, "static grammar cannot be reloaded");
+        assert!(reload("cobol").message.starts_with("invalid language \"cobol\""));
+    }
+
+    #[test]
+    fn nodes_for_ranges_maps_each_range_to_its_enclosing_node() {
+        let range = |start: (usize, usize), end: (usize, usize)| {
+            json!({
+                "start": { "line": start.0, "character": start.1 },
+                "end": { "line": end.0, "character": end.1 },
+            })
+        };
+        let request = json!({
+            "language": "python",
+            "code": "x = foo(1)\n",
+            "ranges": [range((0, 4), (0, 7)), range((0, 4), (0, 10)), range((5, 0), (5, 1))],
+        });
+        let nodes = nodes_for_ranges(&mut Parser::new(), params(request)).unwrap();
+        let sexps: Vec<_> = nodes
+            .iter()
+            .map(|node| node.as_ref().map(|block| block.ast_result.clone().unwrap()))
+            .collect();
+        assert_eq!(sexps[0].as_deref(), Some("(identifier)"));
+        assert!(sexps[1].as_deref().unwrap().starts_with("(call "));
+        assert_eq!(sexps[2], None);
+    }
+
+    #[test]
+    fn get_sexp_hash_changes_only_with_the_subtree_structure() {
+        let hash = |code: &str, path: serde_json::Value| {
+            let request = json!({ "language": "python", "code": code, "path": path });
+            get_sexp_hash(&mut Parser::new(), params(request)).unwrap().fingerprint
+        };
+        let code = "x = 1\ny = 2\n";
+        assert_eq!(hash(code, json!([0])).len(), 32);
+        assert_eq!(hash(code, json!([0])), hash("x = 1\nif y:\n    pass\n", json!([0])));
+        assert_ne!(hash(code, json!([1])), hash("x = 1\nif y:\n    pass\n", json!([1])));
+        // Token text is ignored.
+        assert_eq!(hash(code, json!([0])), hash("z = 3\ny = 2\n", json!([0])));
+    }
+
+    #[test]
+    fn include_counts_reports_child_and_descendant_counts() {
+        let counts =
+            |block: &AstBlock| (block.child_count, block.named_child_count, block.descendant_count);
+        let extra = json!({ "includeCounts": true, "includeParent": true });
+        let response = node_at("f(a, b)\n", 0, 4, extra);
+        let sexp = response.node.ast_result.as_deref();
+        assert_eq!(sexp, Some("(argument_list (identifier) (identifier))"));
+        assert_eq!(counts(&response.node), (Some(5), Some(2), Some(6)));
+        assert_eq!(counts(&response.parent.unwrap()), (Some(2), Some(2), Some(8)));
+        assert_eq!(counts(&node_at("f(a, b)\n", 0, 4, json!({})).node), (None, None, None));
+    }
+
+    #[test]
+    fn get_siblings_in_range_lists_the_cursor_nodes_named_siblings() {
+        let request = json!({
+            "language": "javascript",
+            "code": "f(a, b, c);",
+            "cursorPosition": { "line": 0, "character": 5 },
+        });
+        let response = get_siblings_in_range(&mut Parser::new(), params(request)).unwrap();
+        assert_eq!(response.index, 1);
+        let starts: Vec<_> = response.siblings.iter().map(start).collect();
+        assert_eq!(starts, [(0, 2), (0, 5), (0, 8)]);
+    }
+
+    #[test]
+    fn single_edit_spans_the_code_between_the_common_prefix_and_suffix() {
+        let edit = single_edit(b"a = 1\nb = 2\n", b"a = 1\nc = 3\nb = 2\n");
+        assert_eq!((edit.start_byte, edit.old_end_byte, edit.new_end_byte), (6, 6, 12));
+        assert_eq!(edit.new_end_position, Point::new(2, 0));
+    }
+
+    #[test]
+    fn get_ast_diff_summary_counts_added_and_removed_kinds() {
+        let diff = |old_code: &str, new_code: &str| {
+            let request = json!({
+                "language": "python",
+                "oldCode": old_code,
+                "newCode": new_code,
+            });
+            get_ast_diff_summary(&mut Parser::new(), params(request)).unwrap()
+        };
+        let old_code = "import os\n\nx = 1\n";
+        let new_code = "import os\n\ndef f():\n    pass\n\nx = 1\n";
+        let added = diff(old_code, new_code);
+        let function = added.kinds.iter().find(|diff| diff.kind == "function_definition").unwrap();
+        assert_eq!((function.added, function.removed), (1, 0));
+        assert!(added.kinds.iter().all(|diff| diff.removed == 0), "{added:?}");
+        assert!(added.summary.contains("+1 function_definition"), "{}", added.summary);
+        let removed = diff(new_code, old_code);
+        assert!(removed.summary.contains("-1 function_definition"), "{}", removed.summary);
+        assert_eq!(diff(old_code, old_code).summary, "");
+    }
+
+    #[test]
+    fn warm_up_reports_each_language_in_order() {
+        let request = json!({ "languages": ["python", "cobol", "javascript"] });
+        let results = warm_up(&ParserPool::new(), params(request), 4).unwrap();
+        let outcomes: Vec<_> =
+            results.iter().map(|result| (&result.language[..], result.ok)).collect();
+        assert_eq!(outcomes, [("python", true), ("cobol", false), ("javascript", true)]);
+        assert!(results[1].error.as_deref().unwrap().starts_with("invalid language \"cobol\""));
+        assert_eq!(results[0].error, None);
+    }
+
+    #[test]
+    fn warm_up_leaves_one_parser_set_to_each_language() {
+        let parsers = ParserPool::new();
+        let request = json!({ "languages": ["python", "javascript", "py", "python"] });
+        let results = warm_up(&parsers, params(request), 4).unwrap();
+        assert!(results.iter().all(|result| result.ok), "{results:?}");
+
+        // The checkout for JavaScript takes the parser already set to it,
+        // leaving the Python one, and no other, idle.
+        let mut javascript = parsers.checkout(Some(Language::JavaScript));
+        let python = parsers.checkout(None);
+        assert_eq!(python.language(), Some(Language::Python));
+        assert_eq!(parsers.checkout(None).language(), None);
+        let request = json!({ "language": "javascript", "code": "a; // note\n" });
+        assert_eq!(get_comments(&mut javascript, params(request)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn warm_up_stops_at_max_languages() {
+        let request = json!({ "languages": ["python", "javascript", "python"] });
+        let results = warm_up(&ParserPool::new(), params(request), 1).unwrap();
+        let outcomes: Vec<_> = results.iter().map(|result| result.ok).collect();
+        assert_eq!(outcomes, [true, false, true]);
+        let error = results[1].error.as_deref().unwrap();
+        assert_eq!(error, "at most 1 languages are warmed up at once");
+    }
+
+    #[test]
+    fn parse_ast_in_range_falls_back_to_the_root_without_named_nodes() {
+        for code in ["\n", "\n\n   \n"] {
+            let response = node_at(code, 1, 0, json!({ "includeParent": true }));
+            assert_eq!(response.node.ast_result.as_deref(), Some("(module)"), "{code:?}");
+            assert_eq!(response.parent, None);
+        }
+        let request = json!({
+            "language": "python",
+            "code": "",
+            "cursorPosition": { "line": 0, "character": 0 },
+        });
+        let err = parse_ast_in_range(&mut Parser::new(), params(request), &TreeCache::new(0));
+        assert_eq!(err.unwrap_err().message, "code is empty");
+        let comment = node_at("# note\n", 0, 2, json!({}));
+        assert_eq!(comment.node.ast_result.as_deref(), Some("(comment)"));
+        let token = node_at("x", 0, 0, json!({}));
+        assert_eq!(token.node.ast_result.as_deref(), Some("(identifier)"));
+    }
+
+    #[test]
+    fn widest_at_start_spans_the_chained_call_starting_at_the_cursor() {
+        let code = "a.b().c()\nx = 1\n";
+        let extra = json!({ "includeWidestAtStart": true });
+        let widest = node_at(code, 0, 0, extra.clone()).widest_at_start.unwrap();
+        assert!(widest.ast_result.unwrap().starts_with("(expression_statement (call"));
+        assert_eq!((widest.end_point.line, widest.end_point.character), (0, 9));
+        let callee = node_at(code, 0, 2, extra).widest_at_start.unwrap();
+        assert_eq!(callee.ast_result.as_deref(), Some("(identifier)"));
+        assert_eq!(start(&callee), (0, 2));
+        assert_eq!(node_at(code, 0, 0, json!({})).widest_at_start, None);
+    }
+
+    #[test]
+    fn get_sexp_for_kinds_keeps_only_the_nesting_of_the_kept_kinds() {
+        let outline = |keep_kinds: serde_json::Value| {
+            let request = json!({
+                "language": "python",
+                "code": "class A:\n    def f(self):\n        pass\n\ndef g():\n    x = 1\n",
+                "keepKinds": keep_kinds,
+            });
+            get_sexp_for_kinds(&mut Parser::new(), params(request), 100)
+        };
+        let kinds = json!(["class_definition", "function_definition"]);
+        assert_eq!(
+            outline(kinds).unwrap().ast_result,
+            "(module (class_definition (function_definition)) (function_definition))"
+        );
+        assert_eq!(outline(json!([])).unwrap_err().message, "keepKinds is empty");
+    }
+
+    #[test]
+    fn get_supertypes_maps_supertypes_to_their_subtypes() {
+        let supertypes =
+            |language: Language| get_supertypes(GetSupertypesParams { language }).unwrap();
+        let python = supertypes(Language::Python);
+        assert!(python["_compound_statement"].iter().any(|kind| kind == "function_definition"));
+        // Supertypes may stand for other supertypes.
+        assert!(python["expression"].iter().any(|kind| kind == "primary_expression"));
+        for language in Language::ALL {
+            supertypes(*language);
+        }
+    }
+
+    #[test]
+    fn find_by_text_matches_whole_text_or_substrings() {
+        let find = |text: &str, kinds: serde_json::Value, substring: bool| {
+            let request = json!({
+                "language": "python",
+                "code": "x = foo\ny = foo_bar\n",
+                "text": text,
+                "kinds": kinds,
+                "substring": substring,
+            });
+            find_by_text(&mut Parser::new(), params(request))
+        };
+        let starts = |blocks: Vec<AstBlock>| blocks.iter().map(start).collect::<Vec<_>>();
+
+        assert_eq!(starts(find("foo", json!([]), false).unwrap()), [(0, 4)]);
+        // The statement and the assignment it wraps have the same text.
+        assert_eq!(starts(find("x = foo", json!([]), false).unwrap()), [(0, 0), (0, 0)]);
+        let identifiers = find("foo", json!(["identifier"]), true).unwrap();
+        assert_eq!(starts(identifiers), [(0, 4), (1, 4)]);
+        assert_eq!(find("", json!([]), false).unwrap_err().message, "text is empty");
+    }
+}
@@ -0,0 +1,57 @@
+/// The 64-bit FNV-1a hash, for hashes clients keep many of.
+pub struct Fnv1a64(u64);
+
+impl Fnv1a64 {
+    pub fn new() -> Fnv1a64 {
+        Fnv1a64(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        const PRIME: u64 = 0x0100_0000_01b3;
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(PRIME);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// The 128-bit FNV-1a hash, which is fast, dependency-free and stable across
+/// releases, unlike `DefaultHasher`.
+pub struct Fnv1a128(u128);
+
+impl Fnv1a128 {
+    pub fn new() -> Fnv1a128 {
+        Fnv1a128(0x6c62_272e_07bb_0142_62b8_2175_6295_c58d)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        const PRIME: u128 = 0x0000_0000_0100_0000_0000_0000_0000_013b;
+        for byte in bytes {
+            self.0 = (self.0 ^ u128::from(*byte)).wrapping_mul(PRIME);
+        }
+    }
+
+    pub fn finish(&self) -> u128 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv1a_matches_the_reference_values() {
+        let mut hash = Fnv1a64::new();
+        assert_eq!(hash.finish(), 0xcbf2_9ce4_8422_2325);
+        hash.write(b"a");
+        assert_eq!(hash.finish(), 0xaf63_dc4c_8601_ec8c);
+
+        let mut hash = Fnv1a128::new();
+        hash.write(b"a");
+        assert_eq!(hash.finish(), 0xd228_cb69_6f1a_8caf_7891_2b70_4e4a_8964);
+    }
+}
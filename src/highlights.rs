@@ -0,0 +1,172 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Mutex,
+};
+
+use crate::language::Language;
+
+/// Highlight capture names, such as `keyword` or `string.special`, mapped to
+/// the node kinds the language's `highlights.scm` captures under them.
+pub type ScopeMap = BTreeMap<String, BTreeSet<String>>;
+
+/// The `ScopeMap` for `language`, computed on first use. Empty for grammars
+/// that don't ship a highlight query.
+pub fn scope_map(language: Language) -> ScopeMap {
+    static SCOPE_MAPS: Mutex<BTreeMap<Language, ScopeMap>> = Mutex::new(BTreeMap::new());
+    let mut maps = SCOPE_MAPS.lock().unwrap();
+    maps.entry(language)
+        .or_insert_with(|| {
+            let mut map = ScopeMap::new();
+            for source in language.info().highlight_query {
+                scan(source, &mut map);
+            }
+            map
+        })
+        .clone()
+}
+
+/// One parenthesized or bracketed expression being scanned.
+#[derive(Default)]
+struct Frame {
+    /// The node kind of a `(kind ...)` pattern, or the predicate name of a
+    /// `(#eq? ...)`, whose arguments aren't captures.
+    head: Option<String>,
+    /// Whether this is a `[...]` alternation, which matches the kinds of all
+    /// its children.
+    alternation: bool,
+    /// Kinds of the children, for alternations and `((kind) ...)` groups.
+    children: Vec<String>,
+    /// Kinds of the last complete child, which a following `@capture` names.
+    last: Vec<String>,
+}
+
+impl Frame {
+    fn kinds(self) -> Vec<String> {
+        match self.head {
+            Some(head) if head.starts_with('#') => Vec::new(),
+            Some(head) => vec![head],
+            None => self.children,
+        }
+    }
+
+    fn is_predicate(&self) -> bool {
+        matches!(&self.head, Some(head) if head.starts_with('#'))
+    }
+
+    /// Records a complete child expression matching `kinds`.
+    fn push(&mut self, kinds: Vec<String>) {
+        // A group stands for its first child, as in `((identifier) @x (#eq? ...))`.
+        if self.alternation || (self.head.is_none() && self.children.is_empty()) {
+            self.children.extend(kinds.iter().cloned());
+        }
+        self.last = kinds;
+    }
+}
+
+/// Adds the captures of one query source to `map`. This is a lightweight
+/// scan of the query syntax rather than a full parse, which is enough to
+/// pair each `@capture` with the pattern right before it.
+fn scan(source: &str, map: &mut ScopeMap) {
+    let mut stack = vec![Frame::default()];
+    let mut chars = source.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let frame = stack.last_mut().unwrap();
+        match c {
+            ';' => while chars.next_if(|(_, c)| *c != '\n').is_some() {},
+            '(' | '[' => stack.push(Frame { alternation: c == '[', ..Frame::default() }),
+            ')' | ']' => {
+                if stack.len() > 1 {
+                    let kinds = stack.pop().unwrap().kinds();
+                    stack.last_mut().unwrap().push(kinds);
+                }
+            }
+            '"' => {
+                let mut literal = String::new();
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '\\' => literal.extend(chars.next().map(|(_, c)| c)),
+                        '"' => break,
+                        c => literal.push(c),
+                    }
+                }
+                if !frame.is_predicate() {
+                    frame.push(vec![literal]);
+                }
+            }
+            c if c.is_whitespace() => {}
+            _ => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, c)) = chars.next_if(|(_, c)| is_word_char(*c)) {
+                    end = i + c.len_utf8();
+                }
+                let word = &source[start..end];
+                if frame.is_predicate() {
+                    continue;
+                }
+                if let Some(capture) = word.strip_prefix('@') {
+                    let kinds = frame.last.iter().cloned();
+                    map.entry(capture.to_string()).or_default().extend(kinds);
+                } else if frame.head.is_none()
+                    && !frame.alternation
+                    && frame.children.is_empty()
+                    && is_word_char(c)
+                {
+                    // The first word in parentheses is the node kind, or
+                    // `_` for any node.
+                    frame.head = Some(word.to_string()).filter(|word| word != "_");
+                }
+                // Field names, negated fields, quantifiers and anchors
+                // don't change what the next capture names.
+            }
+        }
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | '#' | '?' | '!' | '@' | ':')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scanned(source: &str) -> BTreeMap<String, Vec<String>> {
+        let mut map = ScopeMap::new();
+        scan(source, &mut map);
+        map.into_iter().map(|(capture, kinds)| (capture, kinds.into_iter().collect())).collect()
+    }
+
+    #[test]
+    fn scan_pairs_each_capture_with_the_pattern_before_it() {
+        let map = scanned(
+            r#"
+            ; (comment) @ignored
+            (identifier) @variable
+            ["if" "else"] @keyword
+            ((identifier) @constant (#match? @constant "^[A-Z]"))
+            (call function: (identifier) @function)
+            (_ (string) @string)
+            "#,
+        );
+        let expected = [
+            ("constant", vec!["identifier"]),
+            ("function", vec!["identifier"]),
+            ("keyword", vec!["else", "if"]),
+            ("string", vec!["string"]),
+            ("variable", vec!["identifier"]),
+        ];
+        let expected: BTreeMap<String, Vec<String>> = expected
+            .into_iter()
+            .map(|(capture, kinds)| {
+                (capture.to_string(), kinds.into_iter().map(String::from).collect())
+            })
+            .collect();
+        assert_eq!(map, expected);
+    }
+
+    #[test]
+    fn scope_map_covers_the_bundled_highlight_queries() {
+        assert!(scope_map(Language::Python)["keyword"].contains("def"));
+        assert!(scope_map(Language::GoSum).is_empty());
+    }
+}
@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+use tree_sitter::{Node, Query, QueryCursor};
+
+use crate::describe::schema;
+use crate::position::Position;
+
+/// A range of the document written in another language, as found by a
+/// grammar's `injections.scm` query.
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InjectionRegion {
+    /// The language name the query gives, e.g. `html` or `regex`. It isn't
+    /// necessarily one the server has a grammar for.
+    pub injected_language: String,
+    pub start_byte: usize,
+    pub end_byte: usize,
+    pub start_point: Position,
+    pub end_point: Position,
+}
+
+schema!(result InjectionRegion { injected_language, start_byte, end_byte, start_point, end_point });
+
+/// Runs an injection query over `root`, returning the regions in document
+/// order.
+///
+/// Both the `injection.content`/`injection.language` capture names and the
+/// older `content`/`language` ones are understood, and the language may also
+/// come from a `(#set! injection.language "...")` property.
+pub fn collect(query: &Query, root: Node, source: &[u8]) -> Vec<InjectionRegion> {
+    let mut regions = Vec::new();
+    let mut cursor = QueryCursor::new();
+    for m in cursor.matches(query, root, source) {
+        let mut language = query
+            .property_settings(m.pattern_index)
+            .iter()
+            .find(|property| &*property.key == "injection.language")
+            .and_then(|property| property.value.as_deref())
+            .map(str::to_string);
+        let mut contents = Vec::new();
+        for capture in m.captures {
+            match query.capture_names()[capture.index as usize].as_str() {
+                "injection.language" | "language" => {
+                    language = capture.node.utf8_text(source).ok().map(str::to_string);
+                }
+                "injection.content" | "content" => contents.push(capture.node),
+                _ => {}
+            }
+        }
+        let language = match language {
+            Some(language) if !language.is_empty() => language,
+            _ => continue,
+        };
+        for node in contents {
+            regions.push(InjectionRegion {
+                injected_language: language.clone(),
+                start_byte: node.start_byte(),
+                end_byte: node.end_byte(),
+                start_point: node.start_position().into(),
+                end_point: node.end_position().into(),
+            });
+        }
+    }
+    regions.sort_by_key(|region| (region.start_byte, region.end_byte));
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::language::Language;
+    use crate::query;
+
+    use super::*;
+
+    fn regions(query: &str, code: &str) -> Vec<(String, usize, usize)> {
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(Language::JavaScript.to_tree_sitter()).unwrap();
+        let tree = parser.parse(code, None).unwrap();
+        let query = query::compile(Language::JavaScript, query).unwrap();
+        collect(&query, tree.root_node(), code.as_bytes())
+            .into_iter()
+            .map(|region| (region.injected_language, region.start_byte, region.end_byte))
+            .collect()
+    }
+
+    #[test]
+    fn collect_takes_the_language_from_a_capture_or_a_property() {
+        let code = "css`a {}`;\nlet r = /a+/;\nhtml`<p></p>`;\n";
+        let query = "(call_expression
+                       function: (identifier) @injection.language
+                       arguments: (template_string) @injection.content)
+                     ((regex_pattern) @injection.content (#set! injection.language \"regex\"))";
+        let expected = vec![
+            ("css".to_string(), 3, 9),
+            ("regex".to_string(), 20, 22),
+            ("html".to_string(), 29, 38),
+        ];
+        assert_eq!(regions(query, code), expected);
+    }
+
+    #[test]
+    fn collect_understands_the_older_capture_names() {
+        let query = "(call_expression
+                       function: (identifier) @language
+                       arguments: (template_string) @content)";
+        assert_eq!(regions(query, "sql`select 1`;"), vec![("sql".to_string(), 3, 13)]);
+    }
+}
@@ -0,0 +1,400 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// A language the server has a grammar for.
+///
+/// On the wire this is the lowercase name from `LanguageInfo::name`, though
+/// any of its `aliases` is accepted too. An unknown name fails
+/// deserialization with the list of valid ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Language {
+    Python,
+    C,
+    JavaScript,
+    TypeScript,
+    Go,
+    Java,
+    Cpp,
+    CSharp,
+    Rust,
+    GoMod,
+    GoSum,
+}
+
+impl Language {
+    pub const ALL: &'static [Language] = &[
+        Language::Python,
+        Language::C,
+        Language::JavaScript,
+        Language::TypeScript,
+        Language::Go,
+        Language::Java,
+        Language::Cpp,
+        Language::CSharp,
+        Language::Rust,
+        Language::GoMod,
+        Language::GoSum,
+    ];
+
+    pub fn info(self) -> &'static LanguageInfo {
+        // `LANGUAGES` is kept in declaration order.
+        &LANGUAGES[self as usize]
+    }
+
+    pub fn name(self) -> &'static str {
+        self.info().name
+    }
+
+    pub fn to_tree_sitter(self) -> tree_sitter::Language {
+        (self.info().grammar)()
+    }
+
+    /// The language of files with extension `extension`, given without the
+    /// leading dot.
+    pub fn from_extension(extension: &str) -> Option<Language> {
+        let extension = extension.to_ascii_lowercase();
+        Language::ALL
+            .iter()
+            .copied()
+            .find(|language| language.info().extensions.contains(&extension.as_str()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownLanguage(String);
+
+impl std::error::Error for UnknownLanguage {}
+
+impl fmt::Display for UnknownLanguage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = Language::ALL.iter().map(|language| language.name()).collect();
+        write!(f, "invalid language {:?}, expected one of: {}", self.0, names.join(", "))
+    }
+}
+
+impl FromStr for Language {
+    type Err = UnknownLanguage;
+
+    /// Accepts the canonical name or any alias, ignoring case and surrounding
+    /// whitespace.
+    fn from_str(name: &str) -> Result<Language, UnknownLanguage> {
+        let normalized = name.trim().to_ascii_lowercase();
+        Language::ALL
+            .iter()
+            .copied()
+            .find(|language| {
+                let info = language.info();
+                info.name == normalized || info.aliases.contains(&normalized.as_str())
+            })
+            .ok_or_else(|| UnknownLanguage(name.to_string()))
+    }
+}
+
+impl fmt::Display for Language {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl TryFrom<String> for Language {
+    type Error = UnknownLanguage;
+
+    fn try_from(name: String) -> Result<Language, UnknownLanguage> {
+        name.parse()
+    }
+}
+
+impl From<Language> for String {
+    fn from(language: Language) -> String {
+        language.name().to_string()
+    }
+}
+
+/// What the server knows about each grammar.
+pub struct LanguageInfo {
+    /// The name clients pass as `language`.
+    pub name: &'static str,
+    /// Other names accepted for the language, such as file extensions.
+    pub aliases: &'static [&'static str],
+    /// File extensions, without the leading dot, for `Language::from_extension`.
+    pub extensions: &'static [&'static str],
+    pub grammar: fn() -> tree_sitter::Language,
+    /// The `locals.scm` sources bundled with the grammar crate, combined in
+    /// order. Empty when the crate doesn't ship one.
+    pub locals_query: &'static [&'static str],
+    /// The `injections.scm` sources, combined the same way.
+    pub injection_query: &'static [&'static str],
+    /// The `highlights.scm` sources, combined the same way.
+    pub highlight_query: &'static [&'static str],
+    /// Node kinds the grammar uses for comments.
+    pub comment_kinds: &'static [&'static str],
+    /// Open/close delimiter tokens beyond the `()`, `[]` and `{}` every
+    /// grammar has.
+    pub bracket_pairs: &'static [(&'static str, &'static str)],
+    /// Node kinds for blocks delimited by indentation rather than brackets.
+    /// Empty for languages where whitespace isn't significant.
+    pub indented_block_kinds: &'static [&'static str],
+    /// Node kinds for string, character and number literals.
+    pub literal_kinds: &'static [&'static str],
+    /// The grammar's `node-types.json`.
+    pub node_types: &'static str,
+}
+
+pub const LANGUAGES: &[LanguageInfo] = &[
+    LanguageInfo {
+        name: "python",
+        aliases: &["py"],
+        extensions: &["py", "pyi"],
+        grammar: tree_sitter_python::language,
+        locals_query: &[],
+        injection_query: &[],
+        highlight_query: &[tree_sitter_python::HIGHLIGHT_QUERY],
+        comment_kinds: &["comment"],
+        bracket_pairs: &[],
+        indented_block_kinds: &["block"],
+        literal_kinds: &["string", "integer", "float"],
+        node_types: tree_sitter_python::NODE_TYPES,
+    },
+    LanguageInfo {
+        name: "c",
+        aliases: &[],
+        extensions: &["c", "h"],
+        grammar: tree_sitter_c::language,
+        locals_query: &[],
+        injection_query: &[],
+        highlight_query: &[tree_sitter_c::HIGHLIGHT_QUERY],
+        comment_kinds: &["comment"],
+        bracket_pairs: &[],
+        indented_block_kinds: &[],
+        literal_kinds: &["string_literal", "char_literal", "number_literal"],
+        node_types: tree_sitter_c::NODE_TYPES,
+    },
+    LanguageInfo {
+        name: "javascript",
+        aliases: &["js", "jsx"],
+        extensions: &["js", "mjs", "cjs", "jsx"],
+        grammar: tree_sitter_javascript::language,
+        locals_query: &[tree_sitter_javascript::LOCALS_QUERY],
+        injection_query: &[tree_sitter_javascript::INJECTION_QUERY],
+        highlight_query: &[tree_sitter_javascript::HIGHLIGHT_QUERY],
+        comment_kinds: &["comment"],
+        bracket_pairs: &[("${", "}")],
+        indented_block_kinds: &[],
+        literal_kinds: &["string", "template_string", "number", "regex"],
+        node_types: tree_sitter_javascript::NODE_TYPES,
+    },
+    LanguageInfo {
+        name: "typescript",
+        aliases: &["ts"],
+        extensions: &["ts", "mts", "cts"],
+        grammar: tree_sitter_typescript::language_typescript,
+        // The TypeScript queries only cover what it adds on top of JavaScript.
+        locals_query: &[tree_sitter_javascript::LOCALS_QUERY, tree_sitter_typescript::LOCALS_QUERY],
+        injection_query: &[tree_sitter_javascript::INJECTION_QUERY],
+        highlight_query: &[
+            tree_sitter_javascript::HIGHLIGHT_QUERY,
+            tree_sitter_typescript::HIGHLIGHT_QUERY,
+        ],
+        comment_kinds: &["comment"],
+        bracket_pairs: &[("${", "}"), ("<", ">")],
+        indented_block_kinds: &[],
+        literal_kinds: &["string", "template_string", "number", "regex"],
+        node_types: tree_sitter_typescript::TYPESCRIPT_NODE_TYPES,
+    },
+    LanguageInfo {
+        name: "golang",
+        aliases: &["go"],
+        extensions: &["go"],
+        grammar: tree_sitter_go::language,
+        locals_query: &[],
+        injection_query: &[],
+        highlight_query: &[tree_sitter_go::HIGHLIGHT_QUERY],
+        comment_kinds: &["comment"],
+        bracket_pairs: &[],
+        indented_block_kinds: &[],
+        literal_kinds: &[
+            "interpreted_string_literal",
+            "raw_string_literal",
+            "rune_literal",
+            "int_literal",
+            "float_literal",
+            "imaginary_literal",
+        ],
+        node_types: tree_sitter_go::NODE_TYPES,
+    },
+    LanguageInfo {
+        name: "java",
+        aliases: &[],
+        extensions: &["java"],
+        grammar: tree_sitter_java::language,
+        locals_query: &[],
+        injection_query: &[],
+        highlight_query: &[tree_sitter_java::HIGHLIGHT_QUERY],
+        comment_kinds: &["comment", "line_comment", "block_comment"],
+        bracket_pairs: &[("<", ">")],
+        indented_block_kinds: &[],
+        literal_kinds: &[
+            "string_literal",
+            "character_literal",
+            "decimal_integer_literal",
+            "hex_integer_literal",
+            "octal_integer_literal",
+            "binary_integer_literal",
+            "decimal_floating_point_literal",
+            "hex_floating_point_literal",
+        ],
+        node_types: tree_sitter_java::NODE_TYPES,
+    },
+    LanguageInfo {
+        name: "cpp",
+        aliases: &["c++", "cxx"],
+        extensions: &["cc", "cpp", "cxx", "hh", "hpp", "hxx"],
+        grammar: tree_sitter_cpp::language,
+        locals_query: &[],
+        injection_query: &[],
+        highlight_query: &[tree_sitter_c::HIGHLIGHT_QUERY, tree_sitter_cpp::HIGHLIGHT_QUERY],
+        comment_kinds: &["comment"],
+        bracket_pairs: &[("<", ">")],
+        indented_block_kinds: &[],
+        literal_kinds: &["string_literal", "raw_string_literal", "char_literal", "number_literal"],
+        node_types: tree_sitter_cpp::NODE_TYPES,
+    },
+    LanguageInfo {
+        name: "csharp",
+        aliases: &["c#", "cs"],
+        extensions: &["cs"],
+        grammar: tree_sitter_c_sharp::language,
+        locals_query: &[],
+        injection_query: &[],
+        highlight_query: &[],
+        comment_kinds: &["comment"],
+        bracket_pairs: &[("<", ">")],
+        indented_block_kinds: &[],
+        literal_kinds: &[
+            "string_literal",
+            "verbatim_string_literal",
+            "character_literal",
+            "integer_literal",
+            "real_literal",
+        ],
+        node_types: tree_sitter_c_sharp::NODE_TYPES,
+    },
+    LanguageInfo {
+        name: "rust",
+        aliases: &["rs"],
+        extensions: &["rs"],
+        grammar: tree_sitter_rust::language,
+        locals_query: &[],
+        injection_query: &[tree_sitter_rust::INJECTIONS_QUERY],
+        highlight_query: &[tree_sitter_rust::HIGHLIGHT_QUERY],
+        comment_kinds: &["line_comment", "block_comment"],
+        bracket_pairs: &[("<", ">")],
+        indented_block_kinds: &[],
+        literal_kinds: &[
+            "string_literal",
+            "raw_string_literal",
+            "char_literal",
+            "integer_literal",
+            "float_literal",
+        ],
+        node_types: tree_sitter_rust::NODE_TYPES,
+    },
+    LanguageInfo {
+        name: "gomod",
+        aliases: &["go.mod"],
+        extensions: &["mod"],
+        grammar: tree_sitter_gomod::language,
+        locals_query: &[],
+        injection_query: &[],
+        highlight_query: &[],
+        comment_kinds: &["comment"],
+        bracket_pairs: &[],
+        indented_block_kinds: &[],
+        literal_kinds: &[],
+        node_types: tree_sitter_gomod::NODE_TYPES,
+    },
+    LanguageInfo {
+        name: "gosum",
+        aliases: &["go.sum"],
+        extensions: &["sum"],
+        grammar: tree_sitter_go_sum::language,
+        locals_query: &[],
+        injection_query: &[],
+        highlight_query: &[],
+        comment_kinds: &[],
+        bracket_pairs: &[],
+        indented_block_kinds: &[],
+        literal_kinds: &[],
+        node_types: tree_sitter_go_sum::NODE_TYPES,
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn languages_are_in_declaration_order() {
+        assert_eq!(LANGUAGES.len(), Language::ALL.len());
+        for (i, language) in Language::ALL.iter().enumerate() {
+            assert_eq!(*language as usize, i);
+        }
+    }
+
+    #[test]
+    fn language_parses_names_and_aliases_ignoring_case() {
+        assert_eq!("python".parse(), Ok(Language::Python));
+        assert_eq!(" TS ".parse(), Ok(Language::TypeScript));
+        assert_eq!("C++".parse(), Ok(Language::Cpp));
+        assert_eq!("c#".parse(), Ok(Language::CSharp));
+        for language in Language::ALL {
+            assert_eq!(language.name().parse(), Ok(*language));
+        }
+    }
+
+    #[test]
+    fn language_rejects_unknown_names_listing_the_valid_ones() {
+        let err = "cobol".parse::<Language>().unwrap_err().to_string();
+        let expected = "invalid language \"cobol\", expected one of: python, c,";
+        assert!(err.starts_with(expected), "{err}");
+    }
+
+    #[test]
+    fn language_serializes_as_its_name() {
+        assert_eq!(serde_json::to_value(Language::CSharp).unwrap(), "csharp");
+        assert_eq!(serde_json::from_value::<Language>("JS".into()).unwrap(), Language::JavaScript);
+        assert!(serde_json::from_value::<Language>("cobol".into()).is_err());
+    }
+
+    #[test]
+    fn from_extension_routes_module_files_to_their_grammars() {
+        assert_eq!(Language::from_extension("GO"), Some(Language::Go));
+        assert_eq!(Language::from_extension("mod"), Some(Language::GoMod));
+        assert_eq!(Language::from_extension("sum"), Some(Language::GoSum));
+        assert_eq!(Language::from_extension("txt"), None);
+    }
+
+    fn parses_cleanly(language: Language, code: &str) -> bool {
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(language.to_tree_sitter()).unwrap();
+        !parser.parse(code, None).unwrap().root_node().has_error()
+    }
+
+    #[test]
+    fn go_grammars_parse_generics_and_module_files() {
+        let generics = "package main\n\n\
+                        func Map[T, U any](s []T, f func(T) U) []U {\n\treturn nil\n}\n\n\
+                        type List[T comparable] struct{ items []T }\n";
+        assert!(parses_cleanly(Language::Go, generics));
+        let go_mod = "module example.com/m\n\ngo 1.21\n\nrequire golang.org/x/text v0.3.0\n";
+        assert!(parses_cleanly(Language::GoMod, go_mod));
+        let go_sum = [
+            "golang.org/x/text v0.3.0 h1:g61tztE5qeGQ89tm6NTjjM9VPIm088od1l6aSorWRWg=",
+            "golang.org/x/text v0.3.0/go.mod h1:NqM8EUOU14njkJ3fqMW+pc6Ldnwhi/IjpwHt7yyuwOQ=",
+            "",
+        ];
+        assert!(parses_cleanly(Language::GoSum, &go_sum.join("\n")));
+    }
+}
@@ -0,0 +1,93 @@
+use tree_sitter::{Node, Query, QueryCursor};
+
+/// The scopes, definitions and references captured by a grammar's
+/// `locals.scm` query.
+pub struct Locals<'tree> {
+    scopes: Vec<Node<'tree>>,
+    definitions: Vec<Node<'tree>>,
+    references: Vec<Node<'tree>>,
+}
+
+/// A definition together with every reference that resolves to it.
+pub struct Resolution<'tree> {
+    pub definition: Option<Node<'tree>>,
+    pub references: Vec<Node<'tree>>,
+}
+
+impl<'tree> Locals<'tree> {
+    pub fn collect(query: &Query, root: Node<'tree>, source: &[u8]) -> Locals<'tree> {
+        // The root is the outermost scope even if the query doesn't say so.
+        let mut scopes = vec![root];
+        let mut definitions = Vec::new();
+        let mut references = Vec::new();
+        let mut cursor = QueryCursor::new();
+        for m in cursor.matches(query, root, source) {
+            for capture in m.captures {
+                match query.capture_names()[capture.index as usize].as_str() {
+                    "local.scope" => scopes.push(capture.node),
+                    "local.definition" => definitions.push(capture.node),
+                    "local.reference" => references.push(capture.node),
+                    _ => {}
+                }
+            }
+        }
+        // Queries usually capture every identifier as a reference, including
+        // the ones that are also definitions.
+        references.retain(|reference| !definitions.contains(reference));
+        Locals { scopes, definitions, references }
+    }
+
+    /// Resolves the identifier `node`, which may be either a definition or a
+    /// reference. Returns `None` if the query captured it as neither.
+    pub fn resolve(&self, node: Node<'tree>, source: &[u8]) -> Option<Resolution<'tree>> {
+        let definition = if self.definitions.contains(&node) {
+            Some(node)
+        } else if self.references.contains(&node) {
+            self.definition_of(node, source)
+        } else {
+            return None;
+        };
+        let name = text(node, source);
+        let references = self
+            .references
+            .iter()
+            .filter(|reference| text(**reference, source) == name)
+            .filter(|reference| self.definition_of(**reference, source) == definition)
+            .copied()
+            .collect();
+        Some(Resolution { definition, references })
+    }
+
+    /// The definition with the same name in the innermost scope enclosing
+    /// `reference`.
+    fn definition_of(&self, reference: Node<'tree>, source: &[u8]) -> Option<Node<'tree>> {
+        let name = text(reference, source);
+        self.definitions
+            .iter()
+            .filter(|definition| text(**definition, source) == name)
+            .filter(|definition| contains(self.scope_of(**definition), reference))
+            .min_by_key(|definition| len(self.scope_of(**definition)))
+            .copied()
+    }
+
+    fn scope_of(&self, node: Node<'tree>) -> Node<'tree> {
+        self.scopes
+            .iter()
+            .filter(|scope| contains(**scope, node))
+            .min_by_key(|scope| len(**scope))
+            .copied()
+            .unwrap_or(self.scopes[0])
+    }
+}
+
+fn text<'a>(node: Node, source: &'a [u8]) -> &'a str {
+    node.utf8_text(source).unwrap_or("")
+}
+
+fn contains(outer: Node, inner: Node) -> bool {
+    outer.start_byte() <= inner.start_byte() && inner.end_byte() <= outer.end_byte()
+}
+
+fn len(node: Node) -> usize {
+    node.end_byte() - node.start_byte()
+}
@@ -0,0 +1,139 @@
+use std::{
+    cell::Cell,
+    env,
+    sync::{Mutex, OnceLock},
+};
+
+use crossbeam_channel::Sender;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+
+use crate::msg::{Message, Notification};
+
+/// Writes log records to stderr and, once the client opts in through the
+/// `logMessages` capability, forwards them as `window/logMessage`
+/// notifications its editor can show in an output panel.
+///
+/// Only records at `Info` and above are forwarded; debug output echoes every
+/// incoming message and would double the traffic.
+struct Logger {
+    /// The server name stderr lines are prefixed with.
+    name: OnceLock<String>,
+    forward: Mutex<Option<Sender<Message>>>,
+}
+
+static LOGGER: Logger = Logger { name: OnceLock::new(), forward: Mutex::new(None) };
+
+thread_local! {
+    /// Set while a record is being forwarded, so anything logged on the way
+    /// to the client isn't forwarded again.
+    static FORWARDING: Cell<bool> = const { Cell::new(false) };
+}
+
+#[derive(Serialize)]
+struct LogMessageParams {
+    /// LSP `MessageType`: 1 error, 2 warning, 3 info, 4 log.
+    #[serde(rename = "type")]
+    typ: u8,
+    message: String,
+}
+
+/// Installs the logger, prefixing stderr lines with `<name>`. `AST_RS_LOG`
+/// sets the level written to stderr, e.g. `info`; everything down to `debug`
+/// is written by default.
+pub fn init(name: &str) {
+    let level = env::var("AST_RS_LOG")
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(LevelFilter::Debug);
+    LOGGER.name.set(name.to_string()).expect("logger is only installed once");
+    log::set_logger(&LOGGER).expect("logger is only installed once");
+    log::set_max_level(level);
+}
+
+/// Starts forwarding log records to the client through `sender`.
+///
+/// `sender` must not feed the thread that writes messages out, or logging
+/// from that thread would block on itself.
+pub fn forward_to(sender: Sender<Message>) {
+    *LOGGER.forward.lock().unwrap() = Some(sender);
+}
+
+/// Stops forwarding and drops the sender `forward_to` installed, so the
+/// writer thread it feeds can see its channel close and exit.
+pub fn stop_forwarding() {
+    *LOGGER.forward.lock().unwrap() = None;
+}
+
+impl Log for Logger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let name = self.name.get().map_or("ast-rs", String::as_str);
+        eprintln!("<{name}> {}", record.args());
+        if record.level() > Level::Info || FORWARDING.with(|forwarding| forwarding.replace(true)) {
+            return;
+        }
+        let sender = self.forward.lock().unwrap().clone();
+        if let Some(sender) = sender {
+            let typ = match record.level() {
+                Level::Error => 1,
+                Level::Warn => 2,
+                Level::Info => 3,
+                Level::Debug | Level::Trace => 4,
+            };
+            let params = LogMessageParams { typ, message: record.args().to_string() };
+            let _ = sender.send(Notification::new("window/logMessage".to_string(), params).into());
+        }
+        FORWARDING.with(|forwarding| forwarding.set(false));
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use crossbeam_channel::unbounded;
+    use serde_json::json;
+
+    use super::*;
+
+    fn log(logger: &Logger, level: Level, message: &str) {
+        logger.log(&Record::builder().level(level).args(format_args!("{message}")).build());
+    }
+
+    #[test]
+    fn logger_forwards_info_and_above_as_log_messages() {
+        let (sender, receiver) = unbounded();
+        let logger = Logger { name: OnceLock::new(), forward: Mutex::new(Some(sender)) };
+        log(&logger, Level::Warn, "careful");
+        log(&logger, Level::Debug, "noisy");
+        log(&logger, Level::Info, "hello");
+
+        let forwarded: Vec<_> = receiver
+            .try_iter()
+            .map(|msg| match msg {
+                Message::Notification(not) => {
+                    assert_eq!(not.method, "window/logMessage");
+                    not.params
+                }
+                msg => panic!("expected a notification, got {msg:?}"),
+            })
+            .collect();
+        let expected = vec![
+            json!({ "type": 2, "message": "careful" }),
+            json!({ "type": 3, "message": "hello" }),
+        ];
+        assert_eq!(forwarded, expected);
+    }
+
+    #[test]
+    fn logger_only_writes_to_stderr_until_forwarding_starts() {
+        let logger = Logger { name: OnceLock::new(), forward: Mutex::new(None) };
+        log(&logger, Level::Error, "nobody listens");
+        // Forwarding state is reset after each record.
+        assert!(!FORWARDING.with(Cell::get));
+    }
+}
@@ -1,184 +1,428 @@
+mod ast;
+mod cache;
+// The protocol modules also carry the client side and the socket
+// transports, which the stdio server doesn't use.
+#[allow(dead_code)]
 mod connection;
+mod describe;
+#[allow(dead_code)]
 mod error;
+mod handlers;
+mod hash;
+mod highlights;
+mod injections;
+mod language;
+mod locals;
+mod logger;
+#[allow(dead_code)]
 mod msg;
+mod parser_pool;
+mod position;
+mod query;
+mod rate_limit;
+mod semantic_tokens;
+mod server;
+mod workers;
 
-use tree_sitter::{Parser, Point, Node};
-use std::error::Error;
+use std::{env, error::Error, process::ExitCode, sync::Arc, time::Duration};
 use connection::Connection;
-use msg::{Message, Response};
-use serde::{Deserialize, Serialize};
+use handlers::{InitializeParams, InitializeResult, ServerCapabilities, ServerInfo};
+use msg::{Lifecycle, Message, ReadMode, Response, ResponseError};
+use position::PositionEncoding;
+use rate_limit::RateLimiter;
+use server::{dispatch, into_response, AstServer, AstServerConfig, ServerState};
+use workers::WorkerPool;
 
-
-#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct Position {
-    pub line: usize,
-    pub character: usize,
+/// Why `main_loop` returned, which decides the process exit code. Protocol
+/// and I/O errors exit with 1 through the `Err` of `main`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Termination {
+    /// `exit` after `shutdown`, as the protocol intends.
+    Shutdown,
+    /// No message arrived within the configured idle timeout.
+    IdleTimeout,
+    /// `exit` without a `shutdown` first.
+    ExitWithoutShutdown,
+    /// The client closed the connection without sending `exit`.
+    Disconnected,
 }
 
-
-#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct ParseAstInRangeParams {
-    pub language: String,
-    pub cursor_position: Position,
-    pub code: String,
-}
-
-#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct AstBlock {
-    pub ast_result: String,
-    pub start_point: Position,
-    pub end_point: Position,
+impl Termination {
+    fn exit_code(self) -> ExitCode {
+        match self {
+            Termination::Shutdown | Termination::IdleTimeout => ExitCode::SUCCESS,
+            // What LSP asks for.
+            Termination::ExitWithoutShutdown => ExitCode::from(1),
+            Termination::Disconnected => ExitCode::from(2),
+        }
+    }
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct ParseAstInRangeResponse {
-    pub ast_result: String,
-    pub parent: Option<AstBlock>,
-    pub start_point: Position,
-    pub end_point: Position,
-}
+fn main() -> Result<ExitCode, Box<dyn Error + Sync + Send>> {
+    let config = AstServerConfig::from_env();
 
-fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
     // Note that  we must have our logging only write out to stderr.
-    eprintln!("<ast-rs> starting generic LSP server");
+    logger::init(&config.server_name);
+    log::info!("starting generic LSP server");
+
+    // Setting `AST_RS_STRICT` rejects messages that deviate from JSON-RPC 2.0
+    // instead of only warning about them.
+    let mode = match env::var_os("AST_RS_STRICT") {
+        Some(_) => ReadMode::Strict,
+        None => ReadMode::Lenient,
+    };
 
     // Create the transport. Includes the stdio (stdin and stdout) versions but this could
     // also be implemented to use sockets or HTTP.
-    let (connection, io_threads) = Connection::stdio();
+    let (connection, io_threads) = Connection::stdio_with_mode(mode);
 
-    main_loop(connection)?;
-    io_threads.join()?;
+    let termination = main_loop(connection, config);
+    // The forwarder holds a sender of the writer thread's channel.
+    logger::stop_forwarding();
+    let termination = termination?;
+    match termination {
+        // Nothing closed stdin, so the reader is still blocked on it.
+        Termination::IdleTimeout => io_threads.join_writer()?,
+        _ => io_threads.join()?,
+    }
 
     // Shut down gracefully.
-    eprintln!("<ast-rs> shutting down server");
-    Ok(())
-}
-
-fn format_node(node: Node) -> Option<AstBlock> {
-    let start_point = node.start_position();
-    let end_point = node.end_position();
-    let result = AstBlock {
-        ast_result: node.to_sexp(),
-        start_point: Position {
-            line: start_point.row,
-            character: start_point.column,
-        },
-        end_point: Position {
-            line: end_point.row,
-            character: end_point.column
-        }
-    };
-    return Some(result);
+    log::info!("shutting down server: {termination:?}");
+    Ok(termination.exit_code())
 }
 
 fn main_loop(
     connection: Connection,
-) -> Result<(), Box<dyn Error + Sync + Send>> {
+    config: AstServerConfig,
+) -> Result<Termination, Box<dyn Error + Sync + Send>> {
 
-    let mut parser = Parser::new();
+    let server = Arc::new(AstServer::new(config));
+    // Taken once `shutdown` arrives, to wait for the in-flight requests.
+    let mut pool = Some(WorkerPool::spawn(Arc::clone(&server), connection.sender.clone()));
 
-    eprintln!("<ast-rs> starting example main loop");
-    for msg in &connection.receiver {
-        eprintln!("<ast-rs> got msg: {msg:?}");
+    let mut limiter = server.config().rate_limit.map(RateLimiter::new);
+
+    // Swapped for `never` once both I/O threads have exited cleanly.
+    let mut io_errors = connection.errors.clone();
+
+    let idle_timeout = server.config().idle_timeout;
+    let idle_timer = || match idle_timeout {
+        Some(timeout) => crossbeam_channel::after(timeout),
+        None => crossbeam_channel::never(),
+    };
+    // Restarted for every message, and pushed back while requests are in
+    // flight, since their responses are activity too.
+    let mut idle = idle_timer();
+
+    log::info!("starting example main loop");
+    loop {
+        let msg = crossbeam_channel::select! {
+            recv(connection.receiver) -> msg => msg.ok(),
+            recv(idle) -> _ => {
+                let timeout = idle_timeout.unwrap_or_default();
+                let quiet = server.idle_since().map_or(Duration::ZERO, |since| since.elapsed());
+                if quiet < timeout {
+                    idle = crossbeam_channel::after(timeout - quiet);
+                    continue;
+                }
+                log::info!("idle timeout reached without a message, exiting");
+                return Ok(Termination::IdleTimeout);
+            }
+            recv(io_errors) -> err => match err {
+                Ok(err) => return Err(err.into()),
+                Err(_) => {
+                    io_errors = crossbeam_channel::never();
+                    continue;
+                }
+            },
+        };
+        let msg = match msg {
+            Some(msg) => msg,
+            // A failing reader reports before it closes the channel, so
+            // this doesn't race.
+            None => match connection.errors.try_recv() {
+                Ok(err) => return Err(err.into()),
+                Err(_) => break,
+            },
+        };
+        log::debug!("got msg: {msg:?}");
+        idle = idle_timer();
         match msg {
             Message::Request(req) => {
-                if connection.handle_shutdown(&req)? {
-                    return Ok(());
-                }
-                // eprintln!("got request: {req:?}");
-                if req.method == "ParseAstInRange" {
-                    let params: ParseAstInRangeParams = serde_json::from_value(req.params)?;
-                    let language = params.language;
-
-                    // TODO: 优化下写法
-                    if language == "python" {
-                        parser.set_language(tree_sitter_python::language()).unwrap();
-                    } else if language == "c" {
-                        parser.set_language(tree_sitter_c::language()).unwrap();
-                    } else if language == "javascript" {
-                        parser.set_language(tree_sitter_javascript::language()).unwrap();
-                    } else if language == "typescript" {
-                        parser.set_language(tree_sitter_typescript::language_typescript()).unwrap();
-                    } else if language == "golang" {
-                        parser.set_language(tree_sitter_go::language()).unwrap();
-                    } else if language == "java" {
-                        parser.set_language(tree_sitter_java::language()).unwrap();
-                    } else if language == "cpp" {
-                        parser.set_language(tree_sitter_cpp::language()).unwrap();
-                    } else if language == "csharp" {
-                        parser.set_language(tree_sitter_c_sharp::language()).unwrap();
-                    } else if language == "rust" {
-                        parser.set_language(tree_sitter_rust::language()).unwrap();
-                    } else {
-                        eprintln!("<ast-rs> invalid language");
-                        let resp = Response::new_err(req.id, 1, "invalid language".to_string());
-                        connection.sender.send(Message::Response(resp))?;
-                        continue;
-                    }
-
-                    if params.code == "" {
-                        let resp = Response::new_err(req.id, 1, "code is empty".to_string());
-                        connection.sender.send(Message::Response(resp))?;
-                        continue;
+                let resp = if req.is_shutdown() {
+                    // Queued requests are rejected from here on, but the ones
+                    // already running answer before the shutdown response.
+                    server.shut_down();
+                    if let Some(pool) = pool.take() {
+                        pool.join();
                     }
-
-                    let tree = parser.parse(params.code, None).unwrap();
-                    let root_node = tree.root_node();
-
-                    let cursor_point = Point {
-                        row: usize::try_from(params.cursor_position.line).unwrap(),
-                        column: usize::try_from(params.cursor_position.character).unwrap()
-                    };
-                
-                    match root_node.named_descendant_for_point_range(cursor_point, cursor_point) {
-                        None => {
-                            eprintln!("<ast-rs> ast parse None");
-                            let resp = Response::new_err(req.id, 1, "ast parse fail".to_string());
-                            connection.sender.send(Message::Response(resp))?;
-                            continue;
-                        },
-                        Some(node) => {
-                            // 生成结果
-                            let start_point = node.start_position();
-                            let end_point = node.end_position();
-                            let result = Some(ParseAstInRangeResponse {
-                                ast_result: node.to_sexp(),
-                                parent: match node.parent() {
-                                    Some(n) => format_node(n),
-                                    None => None
-                                },
-                                start_point: Position {
-                                    line: start_point.row,
-                                    character: start_point.column,
-                                },
-                                end_point: Position {
-                                    line: end_point.row,
-                                    character: end_point.column
-                                }
+                    connection.send_shutdown_response(req.id)?;
+                    None
+                } else {
+                    match (req.method.as_str(), &pool) {
+                        // Answered here, ahead of the queue it empties.
+                        ("CancelAll", _) => Some(Response::new_ok(req.id, server.cancel_all())),
+                        ("initialize", _) => {
+                            let result = dispatch(req.params, |params| {
+                                initialize(&connection, &server, params)
                             });
-                            let result = serde_json::to_value(&result).unwrap();
-                            let resp = Response { id: req.id, result: Some(result), error: None };
-                            connection.sender.send(Message::Response(resp))?;
-                            continue;
+                            Some(into_response(req.id, result))
                         }
-                    };
-                } else {
-                    eprintln!("<ast-rs> got invalid method: {}", req.method);
-                    let resp = Response::new_err(req.id, 1, "invalid method".to_string());
-                    connection.sender.send(Message::Response(resp))?;
+                        (_, Some(pool)) => {
+                            match limiter.as_mut().map_or(Ok(()), RateLimiter::try_acquire) {
+                                Ok(()) => pool.submit(req),
+                                Err(retry_after) => {
+                                    Some(rate_limit::rate_limited(req.id, retry_after))
+                                }
+                            }
+                        }
+                        (_, None) => Some(server::shutting_down(req.id)),
+                    }
                 };
+                if let Some(resp) = resp {
+                    connection.sender.send(Message::Response(resp))?;
+                }
             }
             Message::Response(resp) => {
-                eprintln!("<ast-rs> got response: {resp:?}");
+                let id = resp.id.clone();
+                if !server.complete(resp) {
+                    log::debug!("dropping response to unknown request {id}");
+                }
             }
-            Message::Notification(not) => {
-                eprintln!("<ast-rs> got notification: {not:?}");
+            // Unknown requests get a `MethodNotFound` error, but the spec
+            // forbids answering notifications, so those are only logged.
+            Message::Notification(not) => match not.is_lifecycle() {
+                Some(Lifecycle::Exit) => {
+                    return Ok(match server.state() {
+                        ServerState::ShuttingDown => Termination::Shutdown,
+                        ServerState::Running => Termination::ExitWithoutShutdown,
+                    });
+                }
+                Some(Lifecycle::Initialized) => log::debug!("client initialized"),
+                None => log::debug!("ignoring notification: {not:?}"),
+            },
+        }
+    }
+    Ok(Termination::Disconnected)
+}
+
+fn initialize(
+    connection: &Connection,
+    server: &AstServer,
+    params: InitializeParams,
+) -> Result<InitializeResult, ResponseError> {
+    // Compression is switched on before the response is sent, so a client
+    // asking for it must be ready to read a compressed response.
+    let gzip = params.capabilities.gzip && connection.compression.enable();
+    let log_messages = params.capabilities.log_messages;
+    if log_messages {
+        logger::forward_to(connection.sender.clone());
+    }
+    // UTF-16 is what LSP clients expect, but clients that don't say keep the
+    // byte columns the server has always used.
+    let offers = |name: &str| params.capabilities.position_encodings.iter().any(|e| e == name);
+    let position_encoding = if offers("utf-16") {
+        PositionEncoding::Utf16
+    } else if offers("utf-32") {
+        PositionEncoding::Utf32
+    } else {
+        PositionEncoding::Utf8
+    };
+    server.set_position_encoding(position_encoding);
+    let response_meta = params.capabilities.response_meta;
+    server.set_response_meta(response_meta);
+    Ok(InitializeResult {
+        capabilities: ServerCapabilities {
+            gzip,
+            log_messages,
+            position_encoding,
+            rate_limit: server.config().rate_limit,
+            response_meta,
+            language_permits: server.config().language_permits.clone(),
+        },
+        server_info: ServerInfo {
+            name: server.config().server_name.clone(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use serde_json::json;
+
+    use crate::msg::{ErrorCode, Notification, Request, RequestId, RequestIdGen};
+
+    use super::*;
+
+    /// Runs `main_loop` on one end of an in-memory connection, returning the
+    /// other end to act as the client with.
+    fn serve(config: AstServerConfig) -> (Connection, thread::JoinHandle<Termination>) {
+        let (client, server) = Connection::memory();
+        (client, thread::spawn(move || main_loop(server, config).unwrap()))
+    }
+
+    fn notify(client: &Connection, method: &str) {
+        let not = Notification::new(method.to_string(), ());
+        client.sender.send(not.into()).unwrap();
+    }
+
+    #[test]
+    fn initialize_reports_the_configured_server_name() {
+        let config = AstServerConfig { server_name: "custom".to_string(), ..Default::default() };
+        let (client, server) = serve(config);
+        let resp = client.request(&RequestIdGen::new(), "initialize", json!({})).unwrap();
+        let result: serde_json::Value = resp.into_result().unwrap();
+        assert_eq!(result["serverInfo"]["name"], "custom");
+        notify(&client, "exit");
+        assert_eq!(server.join().unwrap(), Termination::ExitWithoutShutdown);
+    }
+
+    #[test]
+    fn unknown_requests_get_method_not_found_and_unknown_notifications_nothing() {
+        let (client, server) = serve(AstServerConfig::default());
+        notify(&client, "NoSuchNotification");
+        let req = Request::new(RequestId::from(1), "NoSuchMethod".to_string(), ());
+        client.sender.send(req.into()).unwrap();
+        // The notification goes unanswered, or its reply would come first.
+        match client.receiver.recv().unwrap() {
+            Message::Response(resp) => {
+                assert_eq!(resp.id, RequestId::from(1));
+                assert_eq!(resp.error.unwrap().code, ErrorCode::MethodNotFound as i32);
             }
+            msg => panic!("expected the response, got {msg:?}"),
         }
+        notify(&client, "exit");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn initialize_negotiates_the_position_encoding_for_later_requests() {
+        let (client, server) = serve(AstServerConfig::default());
+        let ids = RequestIdGen::new();
+        let capabilities = json!({ "capabilities": { "positionEncodings": ["utf-32", "utf-16"] } });
+        let resp = client.request(&ids, "initialize", capabilities).unwrap();
+        let result: serde_json::Value = resp.into_result().unwrap();
+        assert_eq!(result["capabilities"]["positionEncoding"], "utf-16");
+
+        // `😀` is two UTF-16 code units and four bytes.
+        let params = json!({
+            "language": "python",
+            "code": "s = '😀'; t = 1\n",
+            "cursorPosition": { "line": 0, "character": 10 },
+        });
+        let resp = client.request(&ids, "ParseAstInRange", params).unwrap();
+        let result: serde_json::Value = resp.into_result().unwrap();
+        assert_eq!(result["astResult"], "(identifier)");
+        assert_eq!(result["startPoint"], json!({ "line": 0, "character": 10 }));
+        notify(&client, "exit");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn requests_after_shutdown_are_rejected() {
+        let (client, server) = serve(AstServerConfig::default());
+        let ids = RequestIdGen::new();
+        let resp = client.request(&ids, "shutdown", ()).unwrap();
+        assert!(resp.error.is_none());
+        let params = json!({ "language": "python", "code": "" });
+        let resp = client.request(&ids, "GetComments", params).unwrap();
+        assert_eq!(resp.error.unwrap().message, "server is shutting down");
+        notify(&client, "exit");
+        assert_eq!(server.join().unwrap(), Termination::Shutdown);
+    }
+
+    #[test]
+    fn main_loop_exits_after_the_idle_timeout() {
+        let timeout = Duration::from_millis(50);
+        let config = AstServerConfig { idle_timeout: Some(timeout), ..Default::default() };
+        let started = Instant::now();
+        let (_client, server) = serve(config);
+        assert_eq!(server.join().unwrap(), Termination::IdleTimeout);
+        assert!(started.elapsed() >= timeout);
+    }
+
+    #[test]
+    fn requests_running_past_the_idle_timeout_still_answer() {
+        let timeout = Duration::from_millis(100);
+        let config = AstServerConfig { idle_timeout: Some(timeout), ..Default::default() };
+        let (client, server) = serve(config);
+        // Takes several times the timeout to parse.
+        let code = "def f(x):\n    return x + 1  # one\n\n".repeat(50_000);
+        let params = json!({ "language": "python", "code": code });
+        let started = Instant::now();
+        let resp = client.request(&RequestIdGen::new(), "GetComments", params).unwrap();
+        assert!(started.elapsed() > timeout, "{:?}", started.elapsed());
+        assert!(!server.is_finished());
+        assert_eq!(resp.into_result::<Vec<serde_json::Value>>().unwrap().len(), 50_000);
+        assert_eq!(server.join().unwrap(), Termination::IdleTimeout);
+        assert!(started.elapsed() >= 2 * timeout);
+    }
+
+    #[test]
+    fn responses_nobody_waits_for_are_dropped() {
+        let (client, server) = serve(AstServerConfig::default());
+        let stray = Response::new_ok(RequestId::from(42), ());
+        client.sender.send(stray.into()).unwrap();
+        let resp = client.request(&RequestIdGen::new(), "Health", ()).unwrap();
+        assert!(resp.error.is_none());
+        notify(&client, "exit");
+        assert_eq!(server.join().unwrap(), Termination::ExitWithoutShutdown);
+    }
+
+    #[test]
+    fn initialize_negotiates_utf_32_when_utf_16_is_not_offered() {
+        let (client, server) = serve(AstServerConfig::default());
+        let ids = RequestIdGen::new();
+        let capabilities = json!({ "capabilities": { "positionEncodings": ["utf-32"] } });
+        let resp = client.request(&ids, "initialize", capabilities).unwrap();
+        let result: serde_json::Value = resp.into_result().unwrap();
+        assert_eq!(result["capabilities"]["positionEncoding"], "utf-32");
+
+        // `😀` is one codepoint.
+        let params = json!({
+            "language": "python",
+            "code": "s = '😀'; t = 1\n",
+            "cursorPosition": { "line": 0, "character": 9 },
+        });
+        let resp = client.request(&ids, "ParseAstInRange", params).unwrap();
+        let result: serde_json::Value = resp.into_result().unwrap();
+        assert_eq!(result["astResult"], "(identifier)");
+        assert_eq!(result["startPoint"], json!({ "line": 0, "character": 9 }));
+        notify(&client, "exit");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn initialize_reports_the_language_permits() {
+        let language_permits = [(language::Language::Cpp, 2)].into();
+        let (client, server) = serve(AstServerConfig { language_permits, ..Default::default() });
+        let resp = client.request(&RequestIdGen::new(), "initialize", json!({})).unwrap();
+        let result: serde_json::Value = resp.into_result().unwrap();
+        assert_eq!(result["capabilities"]["languagePermits"], json!({ "cpp": 2 }));
+        notify(&client, "exit");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn main_loop_reports_a_closed_connection() {
+        let (client, server) = serve(AstServerConfig::default());
+        drop(client);
+        assert_eq!(server.join().unwrap(), Termination::Disconnected);
+    }
+
+    #[test]
+    fn exit_code_is_nonzero_only_without_a_clean_exit() {
+        let code = |termination: Termination| format!("{:?}", termination.exit_code());
+        let success = format!("{:?}", ExitCode::SUCCESS);
+        assert_eq!(code(Termination::Shutdown), success);
+        assert_eq!(code(Termination::IdleTimeout), success);
+        assert_eq!(code(Termination::ExitWithoutShutdown), format!("{:?}", ExitCode::from(1)));
+        assert_eq!(code(Termination::Disconnected), format!("{:?}", ExitCode::from(2)));
     }
-    Ok(())
 }
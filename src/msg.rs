@@ -1,8 +1,13 @@
 use std::{
-    fmt,
-    io::{self, BufRead, Write},
+    env, fmt,
+    io::{self, BufRead, Read, Write},
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        OnceLock,
+    },
 };
 
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::error::ExtractError;
@@ -68,6 +73,29 @@ impl fmt::Display for RequestId {
     }
 }
 
+/// Hands out ids for requests sent from this side of a connection.
+///
+/// Ids are consecutive integers starting at 1, unique for the lifetime of the
+/// generator.
+#[derive(Debug)]
+pub struct RequestIdGen(AtomicI32);
+
+impl RequestIdGen {
+    pub fn new() -> RequestIdGen {
+        RequestIdGen(AtomicI32::new(1))
+    }
+
+    pub fn next(&self) -> RequestId {
+        RequestId::from(self.0.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Default for RequestIdGen {
+    fn default() -> RequestIdGen {
+        RequestIdGen::new()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Request {
     pub id: RequestId,
@@ -87,6 +115,9 @@ pub struct Response {
     pub result: Option<serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<ResponseError>,
+    /// Build information, sent when the client opted into `responseMeta`.
+    #[serde(rename = "_meta", default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -97,6 +128,19 @@ pub struct ResponseError {
     pub data: Option<serde_json::Value>,
 }
 
+/// Error codes reserved by the JSON-RPC 2.0 spec. Errors specific to this
+/// server use code 1.
+#[derive(Clone, Copy, Debug)]
+pub enum ErrorCode {
+    ParseError = -32700,
+    InvalidRequest = -32600,
+    MethodNotFound = -32601,
+    InvalidParams = -32602,
+    InternalError = -32603,
+    /// LSP's code for a request the client cancelled.
+    RequestCancelled = -32800,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Notification {
     pub method: String,
@@ -105,22 +149,48 @@ pub struct Notification {
     pub params: serde_json::Value,
 }
 
+/// How strictly incoming messages are checked against the JSON-RPC 2.0 spec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadMode {
+    /// Report protocol deviations on stderr and keep going.
+    #[default]
+    Lenient,
+    /// Reject protocol deviations with an `InvalidData` error.
+    Strict,
+}
+
 impl Message {
     pub fn read(r: &mut impl BufRead) -> io::Result<Option<Message>> {
-        Message::_read(r)
+        Message::_read(r, ReadMode::default())
     }
-    fn _read(r: &mut dyn BufRead) -> io::Result<Option<Message>> {
-        let text = match read_msg_text(r)? {
-            None => return Ok(None),
-            Some(text) => text,
+    pub fn read_with_mode(r: &mut impl BufRead, mode: ReadMode) -> io::Result<Option<Message>> {
+        Message::_read(r, mode)
+    }
+    fn _read(r: &mut dyn BufRead, mode: ReadMode) -> io::Result<Option<Message>> {
+        let text = loop {
+            match read_msg_text(r, mode, max_message_size())? {
+                None => return Ok(None),
+                // Some clients send empty frames as keep-alives.
+                Some(text) if text.is_empty() => log::debug!("skipping empty message"),
+                Some(text) => break text,
+            }
         };
-        let msg = serde_json::from_str(&text)?;
+        // `Message` is untagged, so the version has to be checked on the raw
+        // value before it is dispatched to a variant.
+        let value: serde_json::Value = serde_json::from_str(&text)?;
+        check_jsonrpc_version(&value, mode)?;
+        let msg = serde_json::from_value(value)?;
         Ok(Some(msg))
     }
     pub fn write(self, w: &mut impl Write) -> io::Result<()> {
-        self._write(w)
+        self._write(w, false)
+    }
+    /// Like `write`, but gzip-compresses the body if `compress` is set and the
+    /// message is large enough for it to pay off.
+    pub fn write_with_compression(self, w: &mut impl Write, compress: bool) -> io::Result<()> {
+        self._write(w, compress)
     }
-    fn _write(self, w: &mut dyn Write) -> io::Result<()> {
+    fn _write(self, w: &mut dyn Write, compress: bool) -> io::Result<()> {
         #[derive(Serialize)]
         struct JsonRpc {
             jsonrpc: &'static str,
@@ -128,17 +198,31 @@ impl Message {
             msg: Message,
         }
         let text = serde_json::to_string(&JsonRpc { jsonrpc: "2.0", msg: self })?;
-        write_msg_text(w, &text)
+        write_msg_text(w, &text, compress)
     }
 }
 
 impl Response {
     pub fn new_ok<R: Serialize>(id: RequestId, result: R) -> Response {
-        Response { id, result: Some(serde_json::to_value(result).unwrap()), error: None }
+        let result = Some(serde_json::to_value(result).unwrap());
+        Response { id, result, error: None, meta: None }
     }
     pub fn new_err(id: RequestId, code: i32, message: String) -> Response {
         let error = ResponseError { code, message, data: None };
-        Response { id, result: None, error: Some(error) }
+        Response { id, result: None, error: Some(error), meta: None }
+    }
+    /// The deserialized result, or the error the peer responded with. A
+    /// result that doesn't deserialize as `R` becomes a `ParseError`; a
+    /// response with neither field reads as a `null` result.
+    pub fn into_result<R: DeserializeOwned>(self) -> Result<R, ResponseError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        serde_json::from_value(self.result.unwrap_or_default()).map_err(|err| ResponseError {
+            code: ErrorCode::ParseError as i32,
+            message: format!("invalid result: {err}"),
+            data: None,
+        })
     }
 }
 
@@ -180,12 +264,77 @@ impl Notification {
             Err(error) => Err(ExtractError::JsonError { method: self.method, error }),
         }
     }
-    pub(crate) fn is_exit(&self) -> bool {
-        self.method == "exit"
+    /// Which lifecycle event the notification signals, if any. The reader
+    /// thread and the main loop both go by this.
+    pub fn is_lifecycle(&self) -> Option<Lifecycle> {
+        match self.method.as_str() {
+            "exit" => Some(Lifecycle::Exit),
+            "initialized" => Some(Lifecycle::Initialized),
+            method if exit_aliases().iter().any(|alias| alias == method) => Some(Lifecycle::Exit),
+            _ => None,
+        }
+    }
+}
+
+/// Notifications that change the state of the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lifecycle {
+    /// The client has processed the `initialize` response.
+    Initialized,
+    /// The server should stop reading and exit.
+    Exit,
+}
+
+/// Notifications treated like `exit`, for hosts that send their own, such
+/// as `$/cancelAndShutdown`. Read once from the comma-separated
+/// `AST_RS_EXIT_NOTIFICATIONS`.
+fn exit_aliases() -> &'static [String] {
+    static ALIASES: OnceLock<Vec<String>> = OnceLock::new();
+    ALIASES.get_or_init(|| {
+        parse_exit_aliases(&env::var("AST_RS_EXIT_NOTIFICATIONS").unwrap_or_default())
+    })
+}
+
+fn parse_exit_aliases(aliases: &str) -> Vec<String> {
+    aliases.split(',').map(str::trim).filter(|a| !a.is_empty()).map(str::to_string).collect()
+}
+
+fn check_jsonrpc_version(value: &serde_json::Value, mode: ReadMode) -> io::Result<()> {
+    let problem = match value.get("jsonrpc") {
+        Some(serde_json::Value::String(version)) if version == "2.0" => return Ok(()),
+        Some(version) => format!("unsupported jsonrpc version: {version}"),
+        None => "missing jsonrpc version".to_string(),
+    };
+    match mode {
+        ReadMode::Lenient => {
+            log::warn!("{problem}");
+            Ok(())
+        }
+        ReadMode::Strict => Err(io::Error::new(io::ErrorKind::InvalidData, problem)),
     }
 }
 
-fn read_msg_text(inp: &mut dyn BufRead) -> io::Result<Option<String>> {
+/// Messages, and gzip bodies once decompressed, longer than this are
+/// rejected unless `AST_RS_MAX_MESSAGE_SIZE` says otherwise.
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+
+/// The byte limit on incoming messages, read once from
+/// `AST_RS_MAX_MESSAGE_SIZE`.
+fn max_message_size() -> usize {
+    static MAX: OnceLock<usize> = OnceLock::new();
+    *MAX.get_or_init(|| {
+        env::var("AST_RS_MAX_MESSAGE_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE)
+    })
+}
+
+fn read_msg_text(
+    inp: &mut dyn BufRead,
+    mode: ReadMode,
+    max_size: usize,
+) -> io::Result<Option<String>> {
     fn invalid_data(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
         io::Error::new(io::ErrorKind::InvalidData, error)
     }
@@ -194,12 +343,32 @@ fn read_msg_text(inp: &mut dyn BufRead) -> io::Result<Option<String>> {
     }
 
     let mut size = None;
+    let mut gzip = false;
     let mut buf = String::new();
+    let mut first_line = true;
     loop {
         buf.clear();
         if inp.read_line(&mut buf)? == 0 {
             return Ok(None);
         }
+        // Naive clients sometimes send a byte order mark or stray whitespace
+        // ahead of the headers.
+        if first_line {
+            if let Some(rest) = buf.strip_prefix('\u{feff}') {
+                if mode == ReadMode::Strict {
+                    return Err(invalid_data!("message starts with a UTF-8 byte order mark"));
+                }
+                log::warn!("ignoring UTF-8 byte order mark before headers");
+                buf = rest.to_string();
+            }
+            if mode == ReadMode::Lenient {
+                if buf.trim().is_empty() {
+                    continue;
+                }
+                buf = buf.trim_start().to_string();
+            }
+            first_line = false;
+        }
         if !buf.ends_with("\r\n") {
             return Err(invalid_data!("malformed header: {:?}", buf));
         }
@@ -212,22 +381,220 @@ fn read_msg_text(inp: &mut dyn BufRead) -> io::Result<Option<String>> {
         let header_value =
             parts.next().ok_or_else(|| invalid_data!("malformed header: {:?}", buf))?;
         if header_name == "Content-Length" {
+            // Honoring either of two lengths could let a crafted message
+            // smuggle a second one past a proxy that honors the other.
+            if size.is_some() {
+                return Err(invalid_data!("duplicate Content-Length header"));
+            }
             size = Some(header_value.parse::<usize>().map_err(invalid_data)?);
         }
+        if header_name == "Content-Encoding" {
+            match header_value {
+                "gzip" => gzip = true,
+                _ => return Err(invalid_data!("unsupported Content-Encoding: {:?}", header_value)),
+            }
+        }
     }
     let size: usize = size.ok_or_else(|| invalid_data!("no Content-Length"))?;
+    if size > max_size {
+        return Err(invalid_data!("message of {size} bytes exceeds the maximum of {max_size}"));
+    }
     let mut buf = buf.into_bytes();
     buf.resize(size, 0);
     inp.read_exact(&mut buf)?;
+    if gzip {
+        let mut decoded = Vec::new();
+        // A small body can inflate to any size, so stop one byte past the
+        // limit.
+        GzDecoder::new(buf.as_slice()).take(max_size as u64 + 1).read_to_end(&mut decoded)?;
+        if decoded.len() > max_size {
+            return Err(invalid_data!("decompressed message exceeds {max_size} bytes"));
+        }
+        buf = decoded;
+    }
     let buf = String::from_utf8(buf).map_err(invalid_data)?;
     // log::debug!("< {}", buf);
     Ok(Some(buf))
 }
 
-fn write_msg_text(out: &mut dyn Write, msg: &str) -> io::Result<()> {
+/// Bodies shorter than this are never compressed; the gzip overhead isn't
+/// worth it for typical single-node responses.
+const GZIP_THRESHOLD: usize = 16 * 1024;
+
+fn write_msg_text(out: &mut dyn Write, msg: &str, compress: bool) -> io::Result<()> {
     // log::debug!("> {}", msg);
-    write!(out, "Content-Length: {}\r\n\r\n", msg.len())?;
-    out.write_all(msg.as_bytes())?;
+    if compress && msg.len() >= GZIP_THRESHOLD {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(msg.as_bytes())?;
+        let body = encoder.finish()?;
+        write!(out, "Content-Length: {}\r\nContent-Encoding: gzip\r\n\r\n", body.len())?;
+        out.write_all(&body)?;
+    } else {
+        write!(out, "Content-Length: {}\r\n\r\n", msg.len())?;
+        out.write_all(msg.as_bytes())?;
+    }
     out.flush()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `body` framed with a `Content-Length` header.
+    fn frame(body: &str) -> Vec<u8> {
+        format!("Content-Length: {}\r\n\r\n{body}", body.len()).into_bytes()
+    }
+
+    fn read(bytes: &[u8], mode: ReadMode) -> io::Result<Option<Message>> {
+        Message::read_with_mode(&mut io::Cursor::new(bytes), mode)
+    }
+
+    #[test]
+    fn strict_mode_accepts_jsonrpc_2_0() {
+        let msg = read(&frame(r#"{"jsonrpc":"2.0","id":1,"method":"Health"}"#), ReadMode::Strict);
+        assert!(matches!(msg, Ok(Some(Message::Request(req))) if req.method == "Health"));
+    }
+
+    #[test]
+    fn strict_mode_rejects_a_missing_or_other_jsonrpc_version() {
+        let bodies =
+            [r#"{"id":1,"method":"Health"}"#, r#"{"jsonrpc":"1.0","id":1,"method":"Health"}"#];
+        for body in bodies {
+            let err = read(&frame(body), ReadMode::Strict).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{body}");
+        }
+    }
+
+    #[test]
+    fn lenient_mode_reads_messages_without_a_jsonrpc_version() {
+        let msg = read(&frame(r#"{"id":1,"method":"Health"}"#), ReadMode::Lenient);
+        assert!(matches!(msg, Ok(Some(Message::Request(_)))));
+    }
+
+    fn large_notification() -> Message {
+        Message::Notification(Notification::new("log".to_string(), "a".repeat(GZIP_THRESHOLD)))
+    }
+
+    #[test]
+    fn compressed_messages_read_back() {
+        let mut bytes = Vec::new();
+        large_notification().write_with_compression(&mut bytes, true).unwrap();
+        assert!(bytes.len() < GZIP_THRESHOLD);
+        let header = b"Content-Encoding: gzip\r\n\r\n";
+        assert!(bytes.windows(header.len()).any(|window| window == header));
+        match read(&bytes, ReadMode::Strict).unwrap() {
+            Some(Message::Notification(not)) => assert_eq!(not.params, "a".repeat(GZIP_THRESHOLD)),
+            msg => panic!("expected the notification, got {msg:?}"),
+        }
+    }
+
+    #[test]
+    fn small_messages_are_not_compressed() {
+        let mut bytes = Vec::new();
+        let msg = Message::Notification(Notification::new("log".to_string(), "a"));
+        msg.write_with_compression(&mut bytes, true).unwrap();
+        assert!(bytes.starts_with(b"Content-Length: "));
+        assert!(!String::from_utf8(bytes).unwrap().contains("Content-Encoding"));
+    }
+
+    #[test]
+    fn decompressed_size_is_capped() {
+        let mut bytes = Vec::new();
+        large_notification().write_with_compression(&mut bytes, true).unwrap();
+        let err = read_msg_text(&mut io::Cursor::new(&bytes), ReadMode::Strict, 1024).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let text = read_msg_text(&mut io::Cursor::new(&bytes), ReadMode::Strict, 1 << 20);
+        assert!(text.unwrap().unwrap().len() > GZIP_THRESHOLD);
+    }
+
+    #[test]
+    fn request_id_gen_hands_out_unique_consecutive_ids() {
+        let ids = RequestIdGen::new();
+        assert_eq!(ids.next(), RequestId::from(1));
+        let mut seen: Vec<RequestId> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| (0..50).map(|_| ids.next()).collect::<Vec<_>>()))
+                .collect();
+            threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect()
+        });
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), 200);
+        assert_eq!(ids.next(), RequestId::from(202));
+    }
+
+    #[test]
+    fn lenient_mode_skips_a_byte_order_mark_and_blank_lines_before_headers() {
+        let body = r#"{"jsonrpc":"2.0","id":1,"method":"Health"}"#;
+        for prefix in ["\u{feff}", "\r\n\r\n", "\u{feff}\n  "] {
+            let mut bytes = prefix.as_bytes().to_vec();
+            bytes.extend(frame(body));
+            let msg = read(&bytes, ReadMode::Lenient);
+            assert!(matches!(msg, Ok(Some(Message::Request(_)))), "{prefix:?}: {msg:?}");
+        }
+    }
+
+    #[test]
+    fn strict_mode_rejects_a_byte_order_mark() {
+        let mut bytes = "\u{feff}".as_bytes().to_vec();
+        bytes.extend(frame(r#"{"jsonrpc":"2.0","id":1,"method":"Health"}"#));
+        let err = read(&bytes, ReadMode::Strict).unwrap_err();
+        assert!(err.to_string().contains("byte order mark"), "{err}");
+    }
+
+    #[test]
+    fn empty_messages_are_skipped() {
+        let mut bytes = frame("");
+        bytes.extend(frame(r#"{"jsonrpc":"2.0","id":1,"method":"Health"}"#));
+        bytes.extend(frame(""));
+        let mut input = io::Cursor::new(bytes);
+        let msg = Message::read_with_mode(&mut input, ReadMode::Strict).unwrap();
+        assert!(matches!(msg, Some(Message::Request(req)) if req.method == "Health"));
+        assert!(Message::read_with_mode(&mut input, ReadMode::Strict).unwrap().is_none());
+    }
+
+    #[test]
+    fn a_repeated_content_length_header_is_rejected() {
+        let body = r#"{"jsonrpc":"2.0","id":1,"method":"Health"}"#;
+        let len = body.len();
+        for mode in [ReadMode::Strict, ReadMode::Lenient] {
+            let bytes = format!("Content-Length: {len}\r\nContent-Length: {len}\r\n\r\n{body}");
+            let err = read(bytes.as_bytes(), mode).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(err.to_string(), "duplicate Content-Length header");
+        }
+    }
+
+    #[test]
+    fn into_result_preserves_the_peers_error() {
+        let id = RequestId::from(1);
+        let mut resp = Response::new_err(id.clone(), ErrorCode::InvalidParams as i32, "bad".into());
+        resp.error.as_mut().unwrap().data = Some(serde_json::json!({ "field": "code" }));
+        let err = resp.into_result::<String>().unwrap_err();
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::InvalidParams as i32, "bad"));
+        assert_eq!(err.data, Some(serde_json::json!({ "field": "code" })));
+
+        let err = Response::new_ok(id.clone(), 5).into_result::<String>().unwrap_err();
+        assert_eq!(err.code, ErrorCode::ParseError as i32);
+        assert!(err.message.starts_with("invalid result: "), "{}", err.message);
+
+        let empty = Response { id, result: None, error: None, meta: None };
+        assert_eq!(empty.into_result::<Option<String>>().unwrap(), None);
+    }
+
+    #[test]
+    fn is_lifecycle_recognizes_exit_and_initialized() {
+        let lifecycle = |method: &str| Notification::new(method.to_string(), ()).is_lifecycle();
+        assert_eq!(lifecycle("exit"), Some(Lifecycle::Exit));
+        assert_eq!(lifecycle("initialized"), Some(Lifecycle::Initialized));
+        assert_eq!(lifecycle("$/progress"), None);
+    }
+
+    #[test]
+    fn exit_aliases_are_a_comma_separated_list() {
+        let aliases = parse_exit_aliases(" $/cancelAndShutdown, ,bye ");
+        assert_eq!(aliases, ["$/cancelAndShutdown", "bye"]);
+        assert!(parse_exit_aliases("").is_empty());
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+use tree_sitter::Parser;
+
+use crate::language::Language;
+
+/// Parsers shared between the workers.
+///
+/// `tree_sitter::Parser` is `Send` but not `Sync`: it may move between
+/// threads, but `set_language` and `parse` take `&mut self`, so two threads
+/// must never use one at the same time. The pool hands each parser to one
+/// thread at a time and takes it back when the `PooledParser` is dropped.
+#[derive(Default)]
+pub struct ParserPool {
+    /// Idle parsers, with the language each was last checked out for.
+    idle: Mutex<Vec<(Option<Language>, Parser)>>,
+}
+
+impl ParserPool {
+    pub fn new() -> ParserPool {
+        ParserPool::default()
+    }
+
+    /// Takes an idle parser, preferring one already set to `language`, or
+    /// creates one if none is idle. The parser is set to `language` if given.
+    pub fn checkout(&self, language: Option<Language>) -> PooledParser<'_> {
+        let parser = {
+            let mut idle = self.idle.lock().unwrap();
+            let matching = idle
+                .iter()
+                .position(|(idle_language, _)| language.is_some() && *idle_language == language);
+            match matching {
+                Some(i) => Some(idle.swap_remove(i)),
+                None => idle.pop(),
+            }
+        };
+        let (mut current, mut parser) = parser.unwrap_or_else(|| (None, Parser::new()));
+        if let Some(language) = language.filter(|language| current != Some(*language)) {
+            current = parser.set_language(language.to_tree_sitter()).ok().map(|()| language);
+        }
+        PooledParser { pool: self, language: current, parser: Some(parser) }
+    }
+
+    /// Makes sure an idle parser is set to `language`, adding one if none
+    /// is, so the next checkout for it needs no `set_language`. Returns
+    /// `false` if the grammar doesn't load.
+    pub fn warm(&self, language: Language) -> bool {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.iter().any(|(idle_language, _)| *idle_language == Some(language)) {
+            return true;
+        }
+        let mut parser = Parser::new();
+        if parser.set_language(language.to_tree_sitter()).is_err() {
+            return false;
+        }
+        idle.push((Some(language), parser));
+        true
+    }
+}
+
+/// A parser checked out of a `ParserPool`, returned to it on drop.
+///
+/// The language it is returned under is only a hint for the next checkout:
+/// handlers set the language on every parse anyway.
+pub struct PooledParser<'a> {
+    pool: &'a ParserPool,
+    language: Option<Language>,
+    parser: Option<Parser>,
+}
+
+impl PooledParser<'_> {
+    /// The language the parser is set to, if it was checked out for one and
+    /// the grammar loaded.
+    #[cfg(test)]
+    pub fn language(&self) -> Option<Language> {
+        self.language
+    }
+}
+
+impl Deref for PooledParser<'_> {
+    type Target = Parser;
+
+    fn deref(&self) -> &Parser {
+        self.parser.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledParser<'_> {
+    fn deref_mut(&mut self) -> &mut Parser {
+        self.parser.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledParser<'_> {
+    fn drop(&mut self) {
+        if let Some(parser) = self.parser.take() {
+            self.pool.idle.lock().unwrap().push((self.language, parser));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn checkout_prefers_a_parser_already_set_to_the_language() {
+        let pool = ParserPool::new();
+        let python = pool.checkout(Some(Language::Python));
+        let javascript = pool.checkout(Some(Language::JavaScript));
+        drop(python);
+        drop(javascript);
+        let parser = pool.checkout(Some(Language::Python));
+        assert_eq!(parser.language(), Some(Language::Python));
+        let idle: Vec<_> =
+            pool.idle.lock().unwrap().iter().map(|(language, _)| *language).collect();
+        assert_eq!(idle, [Some(Language::JavaScript)]);
+        drop(parser);
+        assert_eq!(pool.idle.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn concurrent_checkouts_parse_each_language_correctly() {
+        let cases = [
+            (Language::Python, "def f():\n    return 1\n", "module"),
+            (Language::JavaScript, "function f() { return 1; }", "program"),
+            (Language::Go, "package main\n\nfunc f() int { return 1 }\n", "source_file"),
+        ];
+        let pool = ParserPool::new();
+        thread::scope(|scope| {
+            for thread_index in 0..8 {
+                let (pool, cases) = (&pool, &cases);
+                scope.spawn(move || {
+                    for i in 0..50 {
+                        let (language, code, root_kind) = cases[(thread_index + i) % cases.len()];
+                        let mut parser = pool.checkout(Some(language));
+                        assert_eq!(parser.language(), Some(language));
+                        let tree = parser.parse(code, None).unwrap();
+                        assert_eq!(tree.root_node().kind(), root_kind);
+                        assert!(!tree.root_node().has_error());
+                    }
+                });
+            }
+        });
+        assert!(pool.idle.lock().unwrap().len() <= 8);
+    }
+
+    #[test]
+    fn warm_adds_one_idle_parser_per_language() {
+        let pool = ParserPool::new();
+        assert!(pool.warm(Language::Python));
+        assert!(pool.warm(Language::Python));
+        assert!(pool.warm(Language::JavaScript));
+        let idle: Vec<_> =
+            pool.idle.lock().unwrap().iter().map(|(language, _)| *language).collect();
+        assert_eq!(idle, [Some(Language::Python), Some(Language::JavaScript)]);
+    }
+}
@@ -0,0 +1,308 @@
+use std::iter;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tree_sitter::Point;
+
+use crate::describe::schema;
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Position {
+    pub line: usize,
+    pub character: usize,
+    /// The byte offset of the position, for clients that track it anyway.
+    /// Where given, lookups use it instead of `line`/`character`, which sidesteps
+    /// converting them and any question of their encoding.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub byte: Option<usize>,
+}
+
+schema!(params Position { line, character, byte? });
+
+/// Tree-sitter points count columns in bytes, so these conversions only hold
+/// for UTF-8 positions; `Position::to_point` and the `TryFrom` below take
+/// the text and encoding for the others.
+impl From<Point> for Position {
+    fn from(point: Point) -> Position {
+        Position { line: point.row, character: point.column, byte: None }
+    }
+}
+
+impl From<&Position> for Point {
+    fn from(position: &Position) -> Point {
+        Point { row: position.line, column: position.character }
+    }
+}
+
+impl From<Position> for Point {
+    fn from(position: Position) -> Point {
+        Point::from(&position)
+    }
+}
+
+/// The error of the encoding-aware conversions: the position or point is past the
+/// end of its line, or inside a character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutsideText;
+
+impl Position {
+    /// The point of the position, counted in `encoding` units, in the text of
+    /// the index. A method rather than a `TryFrom` impl, which the orphan
+    /// rule forbids for tree-sitter's `Point`.
+    pub fn to_point(
+        &self,
+        index: &LineIndex,
+        encoding: PositionEncoding,
+    ) -> Result<Point, OutsideText> {
+        index.point(self, encoding).ok_or(OutsideText)
+    }
+}
+
+/// `point` in the text of the index, counted in `encoding` units.
+impl TryFrom<(Point, &LineIndex<'_>, PositionEncoding)> for Position {
+    type Error = OutsideText;
+
+    fn try_from(
+        (point, index, encoding): (Point, &LineIndex<'_>, PositionEncoding),
+    ) -> Result<Position, OutsideText> {
+        index.position(point, encoding).ok_or(OutsideText)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Range {
+    pub start: Position,
+    pub end: Position,
+}
+
+schema!(params Range { start: Position, end: Position });
+
+/// The unit `Position::character` is counted in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PositionEncoding {
+    /// Bytes, which is what tree-sitter reports natively.
+    #[default]
+    #[serde(rename = "utf-8")]
+    Utf8,
+    /// UTF-16 code units, as used by LSP clients.
+    #[serde(rename = "utf-16")]
+    Utf16,
+    /// Unicode codepoints, as some web tooling counts them.
+    #[serde(rename = "utf-32")]
+    Utf32,
+}
+
+impl PositionEncoding {
+    /// How many units `c` counts as.
+    fn len(self, c: char) -> usize {
+        match self {
+            PositionEncoding::Utf8 => c.len_utf8(),
+            PositionEncoding::Utf16 => c.len_utf16(),
+            PositionEncoding::Utf32 => 1,
+        }
+    }
+}
+
+/// The byte offset of every line start in a text, for converting between
+/// byte offsets and line/character positions.
+pub struct LineIndex<'a> {
+    text: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    pub fn new(text: &'a str) -> LineIndex<'a> {
+        let mut line_starts = vec![0];
+        line_starts.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+        LineIndex { text, line_starts }
+    }
+
+    /// Returns `None` if `offset` is past the end of the text or inside a
+    /// multibyte character.
+    pub fn offset_to_position(
+        &self,
+        offset: usize,
+        encoding: PositionEncoding,
+    ) -> Option<Position> {
+        if !self.text.is_char_boundary(offset) {
+            return None;
+        }
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let line_text = &self.text[self.line_starts[line]..offset];
+        let character = match encoding {
+            PositionEncoding::Utf8 => line_text.len(),
+            PositionEncoding::Utf16 => line_text.encode_utf16().count(),
+            PositionEncoding::Utf32 => line_text.chars().count(),
+        };
+        Some(Position { line, character, byte: None })
+    }
+
+    /// Returns `None` if `position` is past the end of its line or inside a
+    /// multibyte character.
+    pub fn position_to_offset(
+        &self,
+        position: &Position,
+        encoding: PositionEncoding,
+    ) -> Option<usize> {
+        let line_start = *self.line_starts.get(position.line)?;
+        let line_end = match self.line_starts.get(position.line + 1) {
+            Some(next_start) => next_start - 1,
+            None => self.text.len(),
+        };
+        let line_text = &self.text[line_start..line_end];
+        let column = match encoding {
+            PositionEncoding::Utf8 => position.character,
+            encoding => {
+                // The extra item stands for the end of the line, which is a
+                // valid position too.
+                let ends = line_text.char_indices().chain(iter::once((line_text.len(), '\n')));
+                let mut units = 0;
+                let mut column = None;
+                for (i, c) in ends {
+                    if units >= position.character {
+                        // Landing past `character` means it pointed between
+                        // the two halves of a surrogate pair.
+                        column = Some(i).filter(|_| units == position.character);
+                        break;
+                    }
+                    units += encoding.len(c);
+                }
+                column?
+            }
+        };
+        if column > line_text.len() || !line_text.is_char_boundary(column) {
+            return None;
+        }
+        Some(line_start + column)
+    }
+
+    /// The tree-sitter point for `position` counted in `encoding` units.
+    /// Returns `None` where `position_to_offset` does.
+    pub fn point(&self, position: &Position, encoding: PositionEncoding) -> Option<Point> {
+        let offset = self.position_to_offset(position, encoding)?;
+        Some(Point { row: position.line, column: offset - self.line_starts[position.line] })
+    }
+
+    /// The position of tree-sitter point `point` in `encoding` units. Returns
+    /// `None` if the point is past the end of its line or inside a multibyte
+    /// character.
+    pub fn position(&self, point: Point, encoding: PositionEncoding) -> Option<Position> {
+        let line_start = *self.line_starts.get(point.row)?;
+        let line_end = match self.line_starts.get(point.row + 1) {
+            Some(next_start) => next_start - 1,
+            None => self.text.len(),
+        };
+        if point.column > line_end - line_start {
+            return None;
+        }
+        self.offset_to_position(line_start + point.column, encoding)
+    }
+
+    /// Re-expresses `position` in `to` units. Returns `None` where either
+    /// conversion does.
+    pub fn convert(
+        &self,
+        position: &Position,
+        from: PositionEncoding,
+        to: PositionEncoding,
+    ) -> Option<Position> {
+        let point = position.to_point(self, from).ok()?;
+        Position::try_from((point, self, to)).ok()
+    }
+}
+
+/// Converts the `character` of every `{ line, character }` object in `value`
+/// from `from` to `to` units, so handlers can work in byte columns whatever
+/// the client negotiated. Positions that don't fit `index` are left alone.
+pub fn convert_positions(
+    value: &mut Value,
+    index: &LineIndex,
+    from: PositionEncoding,
+    to: PositionEncoding,
+) {
+    match value {
+        Value::Object(fields) => {
+            let line = fields.get("line").and_then(Value::as_u64);
+            let character = fields.get("character").and_then(Value::as_u64);
+            if let (Some(line), Some(character)) = (line, character) {
+                let position =
+                    Position { line: line as usize, character: character as usize, byte: None };
+                if let Some(converted) = index.convert(&position, from, to) {
+                    fields.insert("character".to_string(), converted.character.into());
+                }
+                return;
+            }
+            for field in fields.values_mut() {
+                convert_positions(field, index, from, to);
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                convert_positions(item, index, from, to);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn position_and_point_convert_field_by_field() {
+        let point = Point { row: 2, column: 7 };
+        let position = Position::from(point);
+        assert_eq!(position, Position { line: 2, character: 7, byte: None });
+        assert_eq!(Point::from(&position), point);
+        assert_eq!(Point::from(position), point);
+    }
+
+    #[test]
+    fn try_from_counts_columns_in_the_encoding() {
+        // `😀` is four bytes, two UTF-16 units and one codepoint.
+        let index = LineIndex::new("a\n😀b\n");
+        let point = Point { row: 1, column: 4 };
+        for (encoding, character) in [
+            (PositionEncoding::Utf8, 4),
+            (PositionEncoding::Utf16, 2),
+            (PositionEncoding::Utf32, 1),
+        ] {
+            let position = Position { line: 1, character, byte: None };
+            assert_eq!(position.to_point(&index, encoding), Ok(point));
+            assert_eq!(Position::try_from((point, &index, encoding)), Ok(position));
+        }
+    }
+
+    #[test]
+    fn try_from_rejects_positions_outside_the_text() {
+        let index = LineIndex::new("a\n😀b\n");
+        let utf16 = PositionEncoding::Utf16;
+        // Between the two halves of the surrogate pair.
+        let inside = Position { line: 1, character: 1, byte: None };
+        assert_eq!(inside.to_point(&index, utf16), Err(OutsideText));
+        let past_end = Position { line: 0, character: 2, byte: None };
+        assert_eq!(past_end.to_point(&index, utf16), Err(OutsideText));
+        let inside = Point { row: 1, column: 2 };
+        assert_eq!(Position::try_from((inside, &index, utf16)), Err(OutsideText));
+        let past_last_line = Point { row: 5, column: 0 };
+        assert_eq!(Position::try_from((past_last_line, &index, utf16)), Err(OutsideText));
+    }
+
+    #[test]
+    fn convert_positions_rewrites_nested_positions() {
+        let index = LineIndex::new("😀x");
+        let mut value = serde_json::json!({
+            "cursorPosition": { "line": 0, "character": 2 },
+            "ranges": [{
+                "start": { "line": 0, "character": 0 },
+                "end": { "line": 0, "character": 3 },
+            }],
+        });
+        convert_positions(&mut value, &index, PositionEncoding::Utf16, PositionEncoding::Utf8);
+        assert_eq!(value["cursorPosition"]["character"], 4);
+        assert_eq!(value["ranges"][0]["end"]["character"], 5);
+    }
+}
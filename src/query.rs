@@ -0,0 +1,365 @@
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+use tree_sitter::{InputEdit, Node, Query, QueryCursor, QueryError, Tree};
+
+use crate::ast;
+use crate::describe::schema;
+use crate::handlers::error;
+use crate::language::Language;
+use crate::msg::ResponseError;
+use crate::position::{LineIndex, Position, PositionEncoding};
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Capture {
+    /// The capture name without the leading `@`.
+    pub name: String,
+    pub capture_index: u32,
+    pub pattern_index: usize,
+    /// Groups the captures of one match. Ids are assigned in document order
+    /// of the matches, so they are stable across runs.
+    pub match_id: usize,
+    pub kind: String,
+    pub text: String,
+    pub start_point: Position,
+    pub end_point: Position,
+}
+
+schema!(result Capture {
+    name, capture_index, pattern_index, match_id, kind, text, start_point, end_point
+});
+
+pub fn compile(language: Language, source: &str) -> Result<Query, ResponseError> {
+    Query::new(language.to_tree_sitter(), source).map_err(|err: QueryError| {
+        error(&format!("invalid query at {}:{}: {}", err.row + 1, err.column + 1, err.message))
+    })
+}
+
+/// One match of a query, kept between `RunQuery` calls on the same document
+/// so `rerun` can reuse it.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct QueryMatch {
+    pattern_index: usize,
+    /// Each capture with the byte range of its node. `match_id` is assigned
+    /// by `captures`.
+    captures: Vec<(Range<usize>, Capture)>,
+    /// The bytes of the ancestors of the captured nodes the pattern can be
+    /// rooted at, see `root_depths`. `None` for a pattern without captures.
+    enclosing: Option<Range<usize>>,
+}
+
+impl QueryMatch {
+    /// The bytes the captures cover, `None` for a pattern without captures.
+    fn span(&self) -> Option<Range<usize>> {
+        let start = self.captures.iter().map(|(range, _)| range.start).min()?;
+        let end = self.captures.iter().map(|(range, _)| range.end).max()?;
+        Some(start..end)
+    }
+
+    /// Document order, with ties broken by the captures so the order
+    /// doesn't depend on the order `QueryCursor` found the matches in.
+    fn sort_key(&self) -> (Option<usize>, usize, Vec<(usize, usize, u32)>) {
+        let captures = self
+            .captures
+            .iter()
+            .map(|(range, capture)| (range.start, range.end, capture.capture_index))
+            .collect();
+        (self.span().map(|span| span.start), self.pattern_index, captures)
+    }
+}
+
+/// Runs `query` over `root`, returning the captures sorted by
+/// `(start_byte, end_byte, capture_index)`.
+///
+/// `QueryCursor` yields matches in the order they finish, which depends on
+/// how patterns overlap, so the order is normalized here.
+pub fn run(query: &Query, root: Node, source: &[u8]) -> Result<Vec<Capture>, ResponseError> {
+    Ok(captures(&matches(query, &[], root, source, None)?))
+}
+
+/// By pattern and capture index, how many levels above a node it captures
+/// a match of the pattern can be rooted at most: the parentheses and
+/// brackets open where `source`, the text `query` was compiled from, names
+/// the capture. Patterns don't report their root node, and `rerun` needs to
+/// know which matches an edit can reach.
+pub fn root_depths(query: &Query, source: &str) -> Vec<Vec<usize>> {
+    let mut depths = vec![vec![0; query.capture_names().len()]; query.pattern_count()];
+    for (pattern, depths) in depths.iter_mut().enumerate() {
+        let end = if pattern + 1 < query.pattern_count() {
+            query.start_byte_for_pattern(pattern + 1)
+        } else {
+            source.len()
+        };
+        let text = source.get(query.start_byte_for_pattern(pattern)..end).unwrap_or("");
+        let mut open = 0usize;
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '(' | '[' => open += 1,
+                ')' | ']' => open = open.saturating_sub(1),
+                '"' => {
+                    while let Some(c) = chars.next() {
+                        match c {
+                            '\\' => {
+                                chars.next();
+                            }
+                            '"' => break,
+                            _ => {}
+                        }
+                    }
+                }
+                ';' => {
+                    for c in chars.by_ref() {
+                        if c == '\n' {
+                            break;
+                        }
+                    }
+                }
+                '@' => {
+                    let mut name = String::new();
+                    while let Some(&c) = chars.peek() {
+                        if !(c.is_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+                            break;
+                        }
+                        name.push(c);
+                        chars.next();
+                    }
+                    if let Some(index) = query.capture_index_for_name(&name) {
+                        let depth = &mut depths[index as usize];
+                        *depth = (*depth).max(open);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    depths
+}
+
+/// The matches of `query` over `root`, or only those intersecting `range`.
+/// `depths` is what `root_depths` returns for `query`, or empty if the
+/// matches won't be `rerun`.
+pub fn matches(
+    query: &Query,
+    depths: &[Vec<usize>],
+    root: Node,
+    source: &[u8],
+    range: Option<Range<usize>>,
+) -> Result<Vec<QueryMatch>, ResponseError> {
+    let mut cursor = QueryCursor::new();
+    if let Some(range) = range {
+        cursor.set_byte_range(range);
+    }
+    let mut matches = Vec::new();
+    for m in cursor.matches(query, root, source) {
+        let mut captures = Vec::new();
+        let mut enclosing: Option<Range<usize>> = None;
+        for c in m.captures {
+            let node = c.node;
+            let depth = depths
+                .get(m.pattern_index)
+                .and_then(|depths| depths.get(c.index as usize))
+                .copied()
+                .unwrap_or(0);
+            let mut ancestor = node;
+            for _ in 0..depth {
+                match ancestor.parent() {
+                    Some(parent) => ancestor = parent,
+                    None => break,
+                }
+            }
+            let range = ancestor.byte_range();
+            enclosing = Some(match enclosing {
+                Some(enclosing) => enclosing.start.min(range.start)..enclosing.end.max(range.end),
+                None => range,
+            });
+            captures.push((
+                node.byte_range(),
+                Capture {
+                    name: query.capture_names()[c.index as usize].clone(),
+                    capture_index: c.index,
+                    pattern_index: m.pattern_index,
+                    match_id: 0,
+                    kind: node.kind().to_string(),
+                    text: ast::node_text(node, source)?.to_string(),
+                    start_point: node.start_position().into(),
+                    end_point: node.end_position().into(),
+                },
+            ));
+        }
+        matches.push(QueryMatch { pattern_index: m.pattern_index, captures, enclosing });
+    }
+    Ok(matches)
+}
+
+/// Numbers `matches` in document order and flattens them into their
+/// captures, sorted as `run` returns them.
+pub fn captures(matches: &[QueryMatch]) -> Vec<Capture> {
+    let mut ordered: Vec<&QueryMatch> = matches.iter().collect();
+    ordered.sort_by_key(|m| m.sort_key());
+
+    let mut captures = Vec::new();
+    for (match_id, m) in ordered.into_iter().enumerate() {
+        for (range, capture) in &m.captures {
+            captures.push((range.clone(), Capture { match_id, ..capture.clone() }));
+        }
+    }
+    captures.sort_by_key(|(range, capture)| (range.start, range.end, capture.capture_index));
+    captures.into_iter().map(|(_, capture)| capture).collect()
+}
+
+/// The matches of `query` over `tree`, which `edit` turned `old_tree` into,
+/// given the matches over `old_tree` before the edit, found with `depths`.
+///
+/// Only the parent of the smallest node around `edit` and the tree's changed
+/// ranges is queried again; the old matches whose enclosing nodes lie
+/// outside it are shifted past the edit. When a match's enclosing nodes
+/// straddle that region, the whole tree is queried.
+pub fn rerun(
+    query: &Query,
+    depths: &[Vec<usize>],
+    old: Vec<QueryMatch>,
+    edit: &InputEdit,
+    old_tree: &Tree,
+    tree: &Tree,
+    source: &str,
+) -> Result<Vec<QueryMatch>, ResponseError> {
+    let root = tree.root_node();
+    let (mut dirty_start, mut dirty_end) = (edit.start_byte, edit.new_end_byte);
+    for range in old_tree.changed_ranges(tree) {
+        dirty_start = dirty_start.min(range.start_byte);
+        dirty_end = dirty_end.max(range.end_byte);
+    }
+    // The parent, so that patterns relating the changed node to its siblings
+    // match again too.
+    let region = root
+        .descendant_for_byte_range(dirty_start, dirty_end)
+        .and_then(|node| node.parent())
+        .map_or(root.byte_range(), |node| node.byte_range());
+    let full = || matches(query, depths, root, source.as_bytes(), None);
+    if region == root.byte_range() {
+        return full();
+    }
+
+    let index = LineIndex::new(source);
+    let mut result = Vec::new();
+    for mut m in old {
+        let enclosing = match &m.enclosing {
+            Some(enclosing) => enclosing.clone(),
+            None => return full(),
+        };
+        // Ends inside the edit are inside the region too.
+        let moved = shift_start(enclosing.start, edit).unwrap_or(edit.start_byte)
+            ..shift_end(enclosing.end, edit).unwrap_or(edit.new_end_byte);
+        match locate(&moved, &region) {
+            Location::Outside => m.enclosing = Some(moved),
+            Location::Inside => continue,
+            Location::Across => return full(),
+        }
+        for (range, capture) in &mut m.captures {
+            let (start, end) = match (shift_start(range.start, edit), shift_end(range.end, edit)) {
+                (Some(start), Some(end)) if start <= end => (start, end),
+                _ => return full(),
+            };
+            let points = (
+                index.offset_to_position(start, PositionEncoding::Utf8),
+                index.offset_to_position(end, PositionEncoding::Utf8),
+            );
+            match points {
+                (Some(start_point), Some(end_point)) => {
+                    capture.start_point = start_point;
+                    capture.end_point = end_point;
+                }
+                _ => return full(),
+            }
+            *range = start..end;
+        }
+        result.push(m);
+    }
+    for m in matches(query, depths, root, source.as_bytes(), Some(region.clone()))? {
+        let enclosing = match &m.enclosing {
+            Some(enclosing) => enclosing.clone(),
+            None => return full(),
+        };
+        match locate(&enclosing, &region) {
+            // Kept from the old matches above.
+            Location::Outside => {}
+            Location::Inside => result.push(m),
+            Location::Across => return full(),
+        }
+    }
+    Ok(result)
+}
+
+enum Location {
+    Outside,
+    Inside,
+    Across,
+}
+
+fn locate(span: &Range<usize>, region: &Range<usize>) -> Location {
+    if span.end <= region.start || span.start >= region.end {
+        Location::Outside
+    } else if region.start <= span.start && span.end <= region.end {
+        Location::Inside
+    } else {
+        Location::Across
+    }
+}
+
+/// Where a node starting at `byte` before `edit` starts after it, `None` if
+/// the edit replaced that byte. Text inserted at `byte` goes before the node.
+fn shift_start(byte: usize, edit: &InputEdit) -> Option<usize> {
+    if byte >= edit.old_end_byte {
+        Some(byte - edit.old_end_byte + edit.new_end_byte)
+    } else if byte < edit.start_byte {
+        Some(byte)
+    } else {
+        None
+    }
+}
+
+/// Like `shift_start`, for the end of a node, which text inserted at `byte`
+/// goes after.
+fn shift_end(byte: usize, edit: &InputEdit) -> Option<usize> {
+    if byte <= edit.start_byte {
+        Some(byte)
+    } else if byte > edit.old_end_byte {
+        Some(byte - edit.old_end_byte + edit.new_end_byte)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_depths_counts_the_parentheses_open_at_each_capture() {
+        let source = "(call_expression function: (identifier) @f) @call\n\
+                      ; (identifier) @commented\n\
+                      [(string) (number)] @literal";
+        let query = compile(Language::JavaScript, source).unwrap();
+        assert_eq!(root_depths(&query, source), vec![vec![1, 0, 0], vec![0, 0, 0]]);
+    }
+
+    fn run_js(query: &str, code: &str) -> Vec<Capture> {
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(Language::JavaScript.to_tree_sitter()).unwrap();
+        let tree = parser.parse(code, None).unwrap();
+        run(&compile(Language::JavaScript, query).unwrap(), tree.root_node(), code.as_bytes())
+            .unwrap()
+    }
+
+    #[test]
+    fn run_orders_captures_by_position_and_numbers_matches_in_document_order() {
+        let captures = run_js("(identifier) @id (call_expression) @call", "foo(bar);");
+        let captures: Vec<_> = captures
+            .iter()
+            .map(|capture| (capture.name.as_str(), capture.text.as_str(), capture.match_id))
+            .collect();
+        assert_eq!(captures, vec![("id", "foo", 0), ("call", "foo(bar)", 1), ("id", "bar", 2)]);
+    }
+}
@@ -0,0 +1,84 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::handlers;
+use crate::msg::{RequestId, Response};
+use crate::server::into_response;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimit {
+    /// Sustained requests per second.
+    pub per_second: u32,
+    /// Requests that may arrive at once after a quiet period.
+    pub burst: u32,
+}
+
+/// A token bucket enforcing a `RateLimit` on one connection.
+pub struct RateLimiter {
+    limit: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> RateLimiter {
+        RateLimiter { limit, tokens: f64::from(limit.burst), refilled_at: Instant::now() }
+    }
+
+    /// Takes a token for a request, or returns how long until one is free.
+    pub fn try_acquire(&mut self) -> Result<(), Duration> {
+        let now = Instant::now();
+        let per_second = f64::from(self.limit.per_second);
+        let refill = now.duration_since(self.refilled_at).as_secs_f64() * per_second;
+        self.tokens = (self.tokens + refill).min(f64::from(self.limit.burst));
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / per_second))
+        }
+    }
+}
+
+/// The response to a request over the limit, telling the client when to
+/// retry.
+pub fn rate_limited(id: RequestId, retry_after: Duration) -> Response {
+    let mut error = handlers::error("rate limited");
+    error.data = Some(json!({ "retryAfterMs": retry_after.as_millis() as u64 }));
+    into_response(id, Err(error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_allows_a_burst_then_says_when_to_retry() {
+        let mut limiter = RateLimiter::new(RateLimit { per_second: 1, burst: 2 });
+        assert_eq!(limiter.try_acquire(), Ok(()));
+        assert_eq!(limiter.try_acquire(), Ok(()));
+        let retry_after = limiter.try_acquire().unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn rate_limiter_refills_over_time() {
+        let mut limiter = RateLimiter::new(RateLimit { per_second: 100, burst: 1 });
+        assert_eq!(limiter.try_acquire(), Ok(()));
+        assert!(limiter.try_acquire().is_err());
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(limiter.try_acquire(), Ok(()));
+    }
+
+    #[test]
+    fn rate_limited_tells_the_client_when_to_retry() {
+        let resp = rate_limited(RequestId::from(1), Duration::from_millis(250));
+        let error = resp.error.unwrap();
+        assert_eq!(error.message, "rate limited");
+        assert_eq!(error.data, Some(json!({ "retryAfterMs": 250 })));
+    }
+}
@@ -0,0 +1,168 @@
+use tree_sitter::Node;
+
+use crate::ast;
+use crate::language::LanguageInfo;
+use crate::position::{LineIndex, PositionEncoding};
+
+/// The legend for `tokenType` in the encoded tokens, in index order.
+pub const TOKEN_TYPES: &[&str] = &[
+    "keyword", "comment", "string", "number", "regexp", "type", "function", "property", "variable",
+];
+
+#[derive(Debug, Clone, Copy)]
+enum TokenType {
+    Keyword,
+    Comment,
+    String,
+    Number,
+    Regexp,
+    Type,
+    Function,
+    Property,
+    Variable,
+}
+
+/// The default mapping from node kinds to token types, driven by the
+/// language's comment and literal kinds. Nodes it returns `None` for, such
+/// as punctuation, get no token.
+fn token_type(info: &LanguageInfo, node: Node, field_name: Option<&str>) -> Option<TokenType> {
+    let kind = node.kind();
+    if info.comment_kinds.contains(&kind) {
+        return Some(TokenType::Comment);
+    }
+    if info.literal_kinds.contains(&kind) {
+        return Some(if kind.contains("regex") {
+            TokenType::Regexp
+        } else if ["string", "char", "rune"].iter().any(|text| kind.contains(text)) {
+            TokenType::String
+        } else {
+            TokenType::Number
+        });
+    }
+    if ast::is_keyword(node) {
+        return Some(TokenType::Keyword);
+    }
+    if !node.is_named() || node.child_count() > 0 {
+        return None;
+    }
+    if kind.contains("type") {
+        Some(TokenType::Type)
+    } else if kind.contains("field_identifier") || kind.contains("property_identifier") {
+        Some(TokenType::Property)
+    } else if ast::is_identifier(node) {
+        let parent_kind = node.parent().map_or("", |parent| parent.kind());
+        let names_function = field_name == Some("name")
+            && (parent_kind.contains("function") || parent_kind.contains("method"));
+        if names_function || field_name == Some("function") {
+            Some(TokenType::Function)
+        } else {
+            Some(TokenType::Variable)
+        }
+    } else {
+        None
+    }
+}
+
+/// Encodes the tokens on lines `start_line..=end_line` in LSP's relative
+/// format: five integers per token, `deltaLine`, `deltaStartChar`, `length`,
+/// `tokenType` and `tokenModifiers`, with columns and lengths counted in
+/// `encoding` units. Tokens spanning several lines are split per line.
+pub fn encode(
+    root: Node,
+    source: &str,
+    info: &LanguageInfo,
+    start_line: usize,
+    end_line: usize,
+    encoding: PositionEncoding,
+) -> Vec<u32> {
+    let index = LineIndex::new(source);
+    let mut data = Vec::new();
+    let (mut prev_line, mut prev_char) = (0, 0);
+    let mut emit = |start: usize, end: usize, token_type: TokenType| {
+        let (from, to) = match (
+            index.offset_to_position(start, encoding),
+            index.offset_to_position(end, encoding),
+        ) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return,
+        };
+        if from.line < start_line || from.line > end_line || to.character <= from.character {
+            return;
+        }
+        let delta_line = from.line - prev_line;
+        let delta_char = if delta_line == 0 { from.character - prev_char } else { from.character };
+        data.extend([
+            delta_line as u32,
+            delta_char as u32,
+            (to.character - from.character) as u32,
+            token_type as u32,
+            0,
+        ]);
+        prev_line = from.line;
+        prev_char = from.character;
+    };
+
+    let mut cursor = root.walk();
+    loop {
+        let node = cursor.node();
+        let in_range =
+            node.end_position().row >= start_line && node.start_position().row <= end_line;
+        let token_type = token_type(info, node, cursor.field_name()).filter(|_| in_range);
+        if let Some(token_type) = token_type {
+            // One token per line of the node, leaving out the line breaks.
+            let mut start = node.start_byte();
+            for line in source[start..node.end_byte()].split_inclusive('\n') {
+                let text = line.trim_end_matches(['\n', '\r']);
+                emit(start, start + text.len(), token_type);
+                start += line.len();
+            }
+        }
+        // Tokens cover their whole subtree, e.g. a string's escapes.
+        if in_range && token_type.is_none() && cursor.goto_first_child() {
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                return data;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tree_sitter::Parser;
+
+    use super::*;
+    use crate::language::Language;
+
+    fn encode_python(code: &str, start_line: usize, end_line: usize) -> Vec<u32> {
+        let mut parser = Parser::new();
+        parser.set_language(Language::Python.to_tree_sitter()).unwrap();
+        let tree = parser.parse(code, None).unwrap();
+        let info = Language::Python.info();
+        encode(tree.root_node(), code, info, start_line, end_line, PositionEncoding::Utf8)
+    }
+
+    #[test]
+    fn encode_emits_relative_tokens_for_the_line_range() {
+        let code = "def f(x):\n    return 'a'  # c\n";
+        #[rustfmt::skip]
+        let line_1 = [
+            1, 4, 6, TokenType::Keyword as u32, 0,
+            0, 7, 3, TokenType::String as u32, 0,
+            0, 5, 3, TokenType::Comment as u32, 0,
+        ];
+        #[rustfmt::skip]
+        let line_0 = [
+            0, 0, 3, TokenType::Keyword as u32, 0,
+            0, 4, 1, TokenType::Function as u32, 0,
+            0, 2, 1, TokenType::Variable as u32, 0,
+        ];
+        assert_eq!(encode_python(code, 1, 1), line_1);
+        let mut both = line_0.to_vec();
+        both.extend(line_1);
+        assert_eq!(encode_python(code, 0, 1), both);
+        assert_eq!(TOKEN_TYPES[TokenType::Comment as usize], "comment");
+    }
+}
@@ -0,0 +1,1168 @@
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    env, fs,
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tree_sitter::Parser;
+
+use crate::cache::{QueryCache, TreeCache};
+use crate::describe::{self, schema, Strict};
+use crate::handlers;
+use crate::language::Language;
+use crate::msg::{ErrorCode, Request, RequestId, Response, ResponseError};
+use crate::parser_pool::ParserPool;
+use crate::position::{self, LineIndex, PositionEncoding};
+use crate::rate_limit::RateLimit;
+
+/// Settings read once at startup.
+#[derive(Debug, Clone)]
+pub struct AstServerConfig {
+    /// Prefixes log output and is reported in `initialize`, to tell several
+    /// embedded instances apart.
+    pub server_name: String,
+    /// Limit on the approximate number of bytes of request and response data
+    /// in flight, see `MemoryBudget`. `None` means unlimited.
+    pub memory_budget: Option<usize>,
+    /// How deeply nested a tree may be before methods that return it nested,
+    /// such as `GetErrorRecoveryTree`, fail instead. Serializing the response
+    /// recurses, so this also bounds stack use.
+    pub max_depth: usize,
+    /// Threads handling requests, each with its own parser.
+    pub workers: usize,
+    /// How many requests for each of these languages may run at once, to
+    /// bound the memory of heavy grammars. Other languages are only limited
+    /// by `workers`.
+    pub language_permits: BTreeMap<Language, usize>,
+    /// Requests that may wait for a free worker before `queue_policy` applies.
+    pub queue_depth: usize,
+    pub queue_policy: QueuePolicy,
+    /// Limit on the requests each connection may send. `None` means
+    /// unlimited.
+    pub rate_limit: Option<RateLimit>,
+    /// Reject params with fields the method doesn't know, such as a
+    /// misspelled `cursorPos`, instead of ignoring them.
+    pub strict_params: bool,
+    /// Exit after this long without a message, a request in flight or a
+    /// response, in case the client died without shutting the server down.
+    /// `None` waits forever.
+    pub idle_timeout: Option<Duration>,
+    /// Directories a request's `filePath` may point into, see
+    /// `read_file_paths`. Empty disables `filePath`.
+    pub allowed_paths: Vec<PathBuf>,
+}
+
+/// What to do with a request that arrives while the queue is full.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Stop reading new messages until a worker frees up.
+    #[default]
+    Block,
+    /// Answer right away with a "server busy" error.
+    Reject,
+}
+
+impl Default for AstServerConfig {
+    fn default() -> AstServerConfig {
+        AstServerConfig {
+            server_name: "ast-rs".to_string(),
+            memory_budget: None,
+            max_depth: 1_000,
+            workers: thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            language_permits: BTreeMap::new(),
+            queue_depth: 64,
+            queue_policy: QueuePolicy::default(),
+            rate_limit: None,
+            strict_params: false,
+            idle_timeout: None,
+            allowed_paths: Vec::new(),
+        }
+    }
+}
+
+impl AstServerConfig {
+    /// Reads the config from `AST_RS_*` environment variables, falling back to
+    /// the defaults for unset or malformed values.
+    pub fn from_env() -> AstServerConfig {
+        let default = AstServerConfig::default();
+        AstServerConfig {
+            server_name: env::var("AST_RS_SERVER_NAME").unwrap_or(default.server_name),
+            memory_budget: env::var("AST_RS_MEMORY_BUDGET").ok().and_then(|v| v.parse().ok()),
+            max_depth: env::var("AST_RS_MAX_DEPTH")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default.max_depth),
+            workers: env::var("AST_RS_WORKERS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|workers| *workers > 0)
+                .unwrap_or(default.workers),
+            // A comma-separated list such as `cpp=2,typescript=2`.
+            language_permits: env::var("AST_RS_LANGUAGE_PERMITS")
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|entry| {
+                    let (language, permits) = entry.split_once('=')?;
+                    Some((language.trim().parse().ok()?, permits.trim().parse().ok()?))
+                })
+                .filter(|(_, permits)| *permits > 0)
+                .collect(),
+            queue_depth: env::var("AST_RS_QUEUE_DEPTH")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default.queue_depth),
+            queue_policy: match env::var("AST_RS_QUEUE_POLICY").as_deref() {
+                Ok("reject") => QueuePolicy::Reject,
+                Ok("block") => QueuePolicy::Block,
+                _ => default.queue_policy,
+            },
+            rate_limit: env::var("AST_RS_RATE_LIMIT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|per_second| *per_second > 0)
+                .map(|per_second| RateLimit {
+                    per_second,
+                    burst: env::var("AST_RS_RATE_BURST")
+                        .ok()
+                        .and_then(|v| v.parse().ok())
+                        .unwrap_or(per_second),
+                }),
+            strict_params: env::var_os("AST_RS_STRICT_PARAMS").is_some(),
+            idle_timeout: env::var("AST_RS_IDLE_TIMEOUT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|seconds| *seconds > 0)
+                .map(Duration::from_secs),
+            // Canonical, so they compare against canonical request paths.
+            allowed_paths: env::var_os("AST_RS_ALLOWED_PATHS")
+                .map(|paths| env::split_paths(&paths).collect::<Vec<_>>())
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|path| fs::canonicalize(path).ok())
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Stats {
+    pub requests: u64,
+    pub memory_budget: Option<usize>,
+    pub memory_in_use: usize,
+    /// Requests waiting for a worker.
+    pub queue_depth: usize,
+}
+
+schema!(result Stats { requests, memory_budget, memory_in_use, queue_depth });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResetResult {
+    /// Cache entries dropped.
+    pub evicted: usize,
+}
+
+schema!(result ResetResult { evicted });
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelAllResult {
+    /// Requests newly cancelled, queued and running ones alike.
+    pub cancelled: usize,
+}
+
+schema!(result CancelAllResult { cancelled });
+
+/// Keeps a burst of huge parses from exhausting memory on a shared server.
+///
+/// The accounting is approximate: a request is charged for the files it
+/// reads and the size of its params up front, and for the size of its
+/// result once it is computed, until the result is handed to the writer. Not
+/// for what the parser and the tree allocate in between.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: Option<usize>,
+    in_use: AtomicUsize,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<usize>) -> MemoryBudget {
+        MemoryBudget { limit, in_use: AtomicUsize::new(0) }
+    }
+
+    pub fn in_use(&self) -> usize {
+        self.in_use.load(Ordering::SeqCst)
+    }
+
+    /// An empty reservation, to charge bytes to as they come up.
+    pub fn reserve(&self) -> Reservation<'_> {
+        Reservation { budget: self, bytes: 0 }
+    }
+}
+
+/// Bytes charged against a `MemoryBudget`, given back on drop.
+#[derive(Debug)]
+pub struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: usize,
+}
+
+impl Reservation<'_> {
+    /// Charges `bytes` more if they fit in what is left of the budget.
+    /// Returns `false`, charging nothing, if they don't.
+    pub fn try_grow(&mut self, bytes: usize) -> bool {
+        let limit = self.budget.limit.unwrap_or(usize::MAX);
+        let grown = self.budget.in_use.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |in_use| {
+            in_use.checked_add(bytes).filter(|total| *total <= limit)
+        });
+        if grown.is_ok() {
+            self.bytes += bytes;
+        }
+        grown.is_ok()
+    }
+
+    /// Charges `bytes` more. The work is already done at this point, so this
+    /// never fails, but it may push the total over the limit until dropped.
+    pub fn grow(&mut self, bytes: usize) {
+        self.budget.in_use.fetch_add(bytes, Ordering::SeqCst);
+        self.bytes += bytes;
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.budget.in_use.fetch_sub(self.bytes, Ordering::SeqCst);
+    }
+}
+
+/// Rough size of a JSON value once serialized.
+fn approximate_size(value: &serde_json::Value) -> usize {
+    match value {
+        serde_json::Value::String(s) => s.len() + 2,
+        serde_json::Value::Array(items) => items.iter().map(approximate_size).sum::<usize>() + 2,
+        serde_json::Value::Object(fields) => {
+            fields.iter().map(|(k, v)| k.len() + 3 + approximate_size(v)).sum::<usize>() + 2
+        }
+        _ => 8,
+    }
+}
+
+/// Replaces a `filePath` in `params`, or in any of its `files`, with a
+/// `code` holding the file's contents, so large local files needn't travel
+/// over the connection. The path must lie inside one of `allowed`, after
+/// resolving `..` and symlinks. With `stream` set, the file is left for the
+/// handler to read and `filePath` becomes the canonical path.
+///
+/// The files read are charged against `budget` before reading them, and the
+/// returned reservation holds them for the request.
+fn read_file_paths<'a>(
+    params: &mut serde_json::Value,
+    allowed: &[PathBuf],
+    stream: bool,
+    budget: &'a MemoryBudget,
+) -> Result<Reservation<'a>, ResponseError> {
+    let mut reservation = budget.reserve();
+    for file in files(params) {
+        let fields = match file.as_object_mut() {
+            Some(fields) => fields,
+            None => continue,
+        };
+        let path = match fields.remove("filePath") {
+            Some(serde_json::Value::String(path)) => path,
+            Some(_) => return Err(handlers::error("filePath must be a string")),
+            None => continue,
+        };
+        if fields.contains_key("code") {
+            return Err(handlers::error("code and filePath are mutually exclusive"));
+        }
+        if allowed.is_empty() {
+            return Err(handlers::error("filePath is disabled on this server"));
+        }
+        let canonical = fs::canonicalize(&path)
+            .map_err(|err| handlers::error(&format!("cannot read {path}: {err}")))?;
+        if !allowed.iter().any(|dir| canonical.starts_with(dir)) {
+            return Err(handlers::error(&format!("{path} is outside the allowed directories")));
+        }
+        if stream {
+            let canonical = canonical
+                .to_str()
+                .ok_or_else(|| handlers::error(&format!("{path} resolves to a non-UTF-8 path")))?;
+            fields.insert("filePath".to_string(), canonical.into());
+            continue;
+        }
+        // Sized up first, so a file too big for the budget is never read.
+        let len = fs::metadata(&canonical)
+            .map_err(|err| handlers::error(&format!("cannot read {path}: {err}")))?
+            .len();
+        if !reservation.try_grow(usize::try_from(len).unwrap_or(usize::MAX)) {
+            return Err(handlers::error("server busy"));
+        }
+        let code = fs::read_to_string(&canonical)
+            .map_err(|err| handlers::error(&format!("cannot read {path}: {err}")))?;
+        fields.insert("code".to_string(), serde_json::Value::String(code));
+    }
+    Ok(reservation)
+}
+
+/// Handles the `normalizeNewlines` param every method accepts. When set,
+/// `\r\n` and lone `\r` in the `code` of `params`, or of any of its `files`,
+/// become `\n` before parsing, so all positions and offsets, in the params
+/// and the result alike, refer to the LF view of the code. Off by default,
+/// so positions match the code as sent, where the `\r` of a CRLF ending
+/// counts as part of its line. Streamed files are parsed as they are on disk.
+fn normalize_newlines(params: &mut serde_json::Value) {
+    let enabled = match params.as_object_mut() {
+        Some(fields) => fields.remove("normalizeNewlines") == Some(true.into()),
+        None => false,
+    };
+    if !enabled {
+        return;
+    }
+    for file in files(params) {
+        if let Some(serde_json::Value::String(code)) = file.get_mut("code") {
+            if code.contains('\r') {
+                *code = code.replace("\r\n", "\n").replace('\r', "\n");
+            }
+        }
+    }
+}
+
+/// Handles the `replaceNulBytes` param every method accepts. When set, NUL
+/// bytes in the code fields of `params`, or of any of its `files`, become
+/// spaces, which keeps every offset. Otherwise code with a NUL byte is
+/// rejected, see `handlers::parse`.
+fn replace_nul_bytes(params: &mut serde_json::Value) {
+    let enabled = match params.as_object_mut() {
+        Some(fields) => fields.remove("replaceNulBytes") == Some(true.into()),
+        None => false,
+    };
+    if !enabled {
+        return;
+    }
+    for file in files(params) {
+        // `GetAstDiffSummary` takes two versions instead of `code`.
+        for field in ["code", "oldCode", "newCode"] {
+            if let Some(serde_json::Value::String(code)) = file.get_mut(field) {
+                if code.contains('\0') {
+                    *code = code.replace('\0', " ");
+                }
+            }
+        }
+    }
+}
+
+/// The objects in `params` that can carry `code`: the entries of `files`
+/// if there are any, otherwise `params` itself.
+fn files(params: &mut serde_json::Value) -> Vec<&mut serde_json::Value> {
+    if params["files"].is_array() {
+        params["files"].as_array_mut().unwrap().iter_mut().collect()
+    } else {
+        vec![params]
+    }
+}
+
+/// Whether the server still takes requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerState {
+    Running,
+    /// `shutdown` was received; only `exit` is expected from now on.
+    ShuttingDown,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResponseMeta {
+    pub server_version: String,
+    /// The tree-sitter ABI version of each grammar.
+    pub grammar_versions: BTreeMap<String, usize>,
+}
+
+/// The `_meta` attached to responses, built on first use.
+fn response_meta() -> &'static serde_json::Value {
+    static META: OnceLock<serde_json::Value> = OnceLock::new();
+    META.get_or_init(|| {
+        let grammar_versions = Language::ALL
+            .iter()
+            .map(|language| (language.name().to_string(), language.to_tree_sitter().version()))
+            .collect();
+        let meta = ResponseMeta {
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            grammar_versions,
+        };
+        serde_json::to_value(meta).unwrap()
+    })
+}
+
+/// How many trees `ParseAstInRange` keeps for `prevFingerprint` lookups.
+const TREE_CACHE_CAPACITY: usize = 16;
+
+/// How many documents `RunQuery` keeps its last result for.
+const QUERY_CACHE_CAPACITY: usize = 16;
+
+/// State shared by every request the server handles.
+pub struct AstServer {
+    config: AstServerConfig,
+    budget: MemoryBudget,
+    requests: AtomicU64,
+    queued: AtomicUsize,
+    /// Negotiated in `initialize`; handlers always work in UTF-8.
+    position_encoding: Mutex<PositionEncoding>,
+    state: Mutex<ServerState>,
+    trees: TreeCache,
+    queries: QueryCache,
+    /// Whether responses carry `_meta`, negotiated in `initialize`.
+    response_meta: AtomicBool,
+    /// Ids of the requests queued or being handled, each with the flag that
+    /// cancels it.
+    in_flight: Mutex<HashMap<RequestId, Arc<AtomicUsize>>>,
+    /// When the last request in flight finished, see `idle_since`.
+    last_finished: Mutex<Instant>,
+    /// Requests the server sent to the client, by id, with where to deliver
+    /// the response once the main loop reads it.
+    pending: Mutex<HashMap<RequestId, Sender<Response>>>,
+    parsers: ParserPool,
+}
+
+impl AstServer {
+    pub fn new(config: AstServerConfig) -> AstServer {
+        let budget = MemoryBudget::new(config.memory_budget);
+        AstServer {
+            config,
+            budget,
+            requests: AtomicU64::new(0),
+            queued: AtomicUsize::new(0),
+            position_encoding: Mutex::new(PositionEncoding::default()),
+            state: Mutex::new(ServerState::Running),
+            trees: TreeCache::new(TREE_CACHE_CAPACITY),
+            queries: QueryCache::new(QUERY_CACHE_CAPACITY),
+            response_meta: AtomicBool::new(false),
+            in_flight: Mutex::new(HashMap::new()),
+            last_finished: Mutex::new(Instant::now()),
+            pending: Mutex::new(HashMap::new()),
+            parsers: ParserPool::new(),
+        }
+    }
+
+    pub fn config(&self) -> &AstServerConfig {
+        &self.config
+    }
+
+    pub fn parsers(&self) -> &ParserPool {
+        &self.parsers
+    }
+
+    pub fn position_encoding(&self) -> PositionEncoding {
+        *self.position_encoding.lock().unwrap()
+    }
+
+    pub fn set_position_encoding(&self, encoding: PositionEncoding) {
+        *self.position_encoding.lock().unwrap() = encoding;
+    }
+
+    pub fn set_response_meta(&self, enabled: bool) {
+        self.response_meta.store(enabled, Ordering::SeqCst);
+    }
+
+    pub fn state(&self) -> ServerState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Makes every later request fail with `shutting_down`.
+    pub fn shut_down(&self) {
+        *self.state.lock().unwrap() = ServerState::ShuttingDown;
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            requests: self.requests.load(Ordering::SeqCst),
+            memory_budget: self.config.memory_budget,
+            memory_in_use: self.budget.in_use(),
+            queue_depth: self.queued.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Drops per-parser state so a long-running daemon can start fresh, e.g.
+    /// on a workspace switch.
+    ///
+    /// Only the parser checked out for the request is reset; parsers carry
+    /// nothing over between requests, so the idle ones need no draining.
+    /// `evicted` counts the trees dropped from the `ParseAstInRange` cache
+    /// and the documents dropped from the `RunQuery` one.
+    pub fn reset(&self, parser: &mut Parser) -> ResetResult {
+        parser.reset();
+        ResetResult { evicted: self.trees.clear() + self.queries.clear() }
+    }
+
+    /// Counts a request into the queue; `dequeued` counts it out.
+    pub(crate) fn enqueued(&self) {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn dequeued(&self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Marks `id` as in flight until `finished`. Returns `false`, leaving
+    /// the set unchanged, if a request with that id already is.
+    pub(crate) fn started(&self, id: &RequestId) -> bool {
+        match self.in_flight.lock().unwrap().entry(id.clone()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(Arc::default());
+                true
+            }
+        }
+    }
+
+    /// The flag `cancel_all` sets for the request `id`, nonzero once it is
+    /// cancelled. `None` if the request isn't in flight.
+    pub(crate) fn cancellation_flag(&self, id: &RequestId) -> Option<Arc<AtomicUsize>> {
+        self.in_flight.lock().unwrap().get(id).cloned()
+    }
+
+    /// Cancels every request in flight, returning how many weren't already.
+    /// Each still gets its own `cancelled` response from its worker.
+    pub fn cancel_all(&self) -> CancelAllResult {
+        let in_flight = self.in_flight.lock().unwrap();
+        let cancelled = in_flight.values().filter(|flag| flag.swap(1, Ordering::SeqCst) == 0);
+        CancelAllResult { cancelled: cancelled.count() }
+    }
+
+    pub(crate) fn finished(&self, id: &RequestId) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        in_flight.remove(id);
+        *self.last_finished.lock().unwrap() = Instant::now();
+    }
+
+    /// Since when no request has been queued or handled, or `None` while
+    /// one is.
+    pub(crate) fn idle_since(&self) -> Option<Instant> {
+        let in_flight = self.in_flight.lock().unwrap();
+        in_flight.is_empty().then(|| *self.last_finished.lock().unwrap())
+    }
+
+    /// Registers a request sent to the client under `id`. The returned
+    /// receiver gets its response once the main loop hands it to `complete`.
+    /// Nothing in the server asks the client anything yet.
+    #[allow(dead_code)]
+    pub fn expect_response(&self, id: RequestId) -> Receiver<Response> {
+        let (sender, receiver) = bounded(1);
+        self.pending.lock().unwrap().insert(id, sender);
+        receiver
+    }
+
+    /// Delivers `resp` to the caller waiting on its id. Returns `false` if
+    /// no one is, e.g. because the id was never sent or already answered.
+    pub fn complete(&self, resp: Response) -> bool {
+        match self.pending.lock().unwrap().remove(&resp.id) {
+            // A waiter that gave up has dropped its receiver; the response
+            // still counts as claimed.
+            Some(waiter) => {
+                let _ = waiter.send(resp);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Answers `req`. The reservation charging the request and its result
+    /// against the memory budget comes back with the response, for the
+    /// caller to hold until the response is handed to the writer.
+    pub fn handle_request(
+        &self,
+        parser: &mut Parser,
+        mut req: Request,
+    ) -> (Response, Option<Reservation<'_>>) {
+        if self.state() == ServerState::ShuttingDown {
+            return (shutting_down(req.id), None);
+        }
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        // Only `ParseMany` can stream files instead of reading them whole.
+        let stream = req.method == "ParseMany" && req.params["stream"] == true;
+        let allowed = &self.config.allowed_paths;
+        let files = read_file_paths(&mut req.params, allowed, stream, &self.budget);
+        let mut reservation = match files {
+            Ok(reservation) => reservation,
+            Err(err) => return (into_response(req.id, Err(err)), None),
+        };
+        normalize_newlines(&mut req.params);
+        replace_nul_bytes(&mut req.params);
+        // The files read are charged already.
+        let rest = approximate_size(&req.params).saturating_sub(reservation.bytes);
+        if !reservation.try_grow(rest) {
+            log::warn!("rejecting {}: memory budget exhausted", req.method);
+            return (into_response(req.id, Err(handlers::error("server busy"))), None);
+        }
+        let id = req.id.clone();
+        let result = match self.position_encoding() {
+            PositionEncoding::Utf8 => self.route(parser, req),
+            encoding => self.route_with_encoding(parser, req, encoding),
+        };
+        if let Ok(value) = &result {
+            reservation.grow(approximate_size(value));
+        }
+        let mut response = into_response(id, result);
+        if self.response_meta.load(Ordering::SeqCst) {
+            response.meta = Some(response_meta().clone());
+        }
+        (response, Some(reservation))
+    }
+
+    /// Like `route`, converting the positions in the params from `encoding`
+    /// to UTF-8 and those in the result back, against the code they refer to.
+    fn route_with_encoding(
+        &self,
+        parser: &mut Parser,
+        mut req: Request,
+        encoding: PositionEncoding,
+    ) -> Result<serde_json::Value, ResponseError> {
+        let utf8 = PositionEncoding::Utf8;
+        let code_of = |value: &serde_json::Value| value["code"].as_str().map(str::to_string);
+        match req.method.as_str() {
+            // These convert positions themselves, by default in the negotiated
+            // encoding.
+            "OffsetToPosition" | "PositionToOffset" | "SemanticTokens" => {
+                if let Some(params) = req.params.as_object_mut() {
+                    params
+                        .entry("positionEncoding")
+                        .or_insert_with(|| serde_json::to_value(encoding).unwrap());
+                }
+                self.route(parser, req)
+            }
+            // Every file has its own code, and results come back in the same
+            // order. Only the results hold positions.
+            "ParseMany" => {
+                // Converting needs the code, which streaming never holds whole.
+                if req.params["stream"] == true {
+                    return Err(handlers::error("stream requires the utf-8 position encoding"));
+                }
+                let codes: Vec<Option<String>> = match req.params["files"].as_array() {
+                    Some(files) => files.iter().map(code_of).collect(),
+                    None => Vec::new(),
+                };
+                let mut result = self.route(parser, req)?;
+                if let Some(results) = result.as_array_mut() {
+                    for (item, code) in results.iter_mut().zip(codes) {
+                        if let Some(code) = code {
+                            let index = LineIndex::new(&code);
+                            position::convert_positions(item, &index, utf8, encoding);
+                        }
+                    }
+                }
+                Ok(result)
+            }
+            _ => {
+                let code = match code_of(&req.params) {
+                    Some(code) => code,
+                    None => return self.route(parser, req),
+                };
+                let index = LineIndex::new(&code);
+                position::convert_positions(&mut req.params, &index, encoding, utf8);
+                let mut result = self.route(parser, req)?;
+                position::convert_positions(&mut result, &index, utf8, encoding);
+                Ok(result)
+            }
+        }
+    }
+
+    /// Like the free `dispatch`, but in `strict_params` mode also rejects
+    /// fields of `params` that `P` doesn't have, at any depth.
+    fn dispatch<P, R>(
+        &self,
+        params: serde_json::Value,
+        handler: impl FnOnce(P) -> Result<R, ResponseError>,
+    ) -> Result<serde_json::Value, ResponseError>
+    where
+        P: DeserializeOwned + Strict,
+        R: Serialize,
+    {
+        if self.config.strict_params {
+            // After `P` has had its say, so only unknown fields are new errors.
+            let strict = params.clone();
+            return dispatch(params, |params: P| {
+                serde_json::from_value::<P::Known>(strict)
+                    .map_err(|err| handlers::error(&format!("invalid params: {err}")))?;
+                handler(params)
+            });
+        }
+        dispatch(params, handler)
+    }
+
+    fn route(&self, parser: &mut Parser, req: Request) -> Result<serde_json::Value, ResponseError> {
+        match req.method.as_str() {
+            "ParseAstInRange" => {
+                self.dispatch(req.params, |params| {
+                    handlers::parse_ast_in_range(parser, params, &self.trees)
+                })
+            }
+            "GetErrorRecoveryTree" => {
+                let max_depth = self.config.max_depth;
+                self.dispatch(req.params, |params| {
+                    handlers::get_error_recovery_tree(parser, params, max_depth)
+                })
+            }
+            "Disassemble" => {
+                self.dispatch(req.params, |params| handlers::disassemble(parser, params))
+            }
+            "OffsetToPosition" => self.dispatch(req.params, handlers::offset_to_position),
+            "PositionToOffset" => self.dispatch(req.params, handlers::position_to_offset),
+            "ResolveLocal" => {
+                self.dispatch(req.params, |params| handlers::resolve_local(parser, params))
+            }
+            "HighlightOccurrences" => {
+                self.dispatch(req.params, |params| handlers::highlight_occurrences(parser, params))
+            }
+            "GetComments" => {
+                self.dispatch(req.params, |params| handlers::get_comments(parser, params))
+            }
+            "GetNamedNodeAtEachLine" => {
+                self.dispatch(req.params, |params| {
+                    handlers::get_named_node_at_each_line(parser, params)
+                })
+            }
+            "ParseSubRange" => {
+                let max_depth = self.config.max_depth;
+                self.dispatch(req.params, |params| {
+                    handlers::parse_sub_range(parser, params, max_depth)
+                })
+            }
+            "GetKeywordAt" => {
+                self.dispatch(req.params, |params| handlers::get_keyword_at(parser, params))
+            }
+            "RunQuery" => self.dispatch(req.params, |params| {
+                handlers::run_query(parser, params, &self.queries)
+            }),
+            "ParseAndQuery" => {
+                self.dispatch(req.params, |params| handlers::parse_and_query(parser, params))
+            }
+            "GetTreeSExpForSubtree" => {
+                let max_depth = self.config.max_depth;
+                self.dispatch(req.params, |params| {
+                    handlers::get_tree_sexp_for_subtree(parser, params, max_depth)
+                })
+            }
+            "GetBracketMatch" => {
+                self.dispatch(req.params, |params| handlers::get_bracket_match(parser, params))
+            }
+            "IsLanguageSupported" => self.dispatch(req.params, handlers::is_language_supported),
+            "SelectionRangesForPositions" => self.dispatch(req.params, |params| {
+                handlers::selection_ranges_for_positions(parser, params)
+            }),
+            "ParseMany" => {
+                let max_depth = self.config.max_depth;
+                self.dispatch(req.params, |params| handlers::parse_many(parser, params, max_depth))
+            }
+            "GetNodeParentUntilKind" => {
+                self.dispatch(req.params, |params| {
+                    handlers::get_node_parent_until_kind(parser, params)
+                })
+            }
+            "GetAstFingerprint" => {
+                self.dispatch(req.params, |params| handlers::get_ast_fingerprint(parser, params))
+            }
+            "GetSExpHash" => {
+                self.dispatch(req.params, |params| handlers::get_sexp_hash(parser, params))
+            }
+            "GetLeafAtPosition" => {
+                self.dispatch(req.params, |params| handlers::get_leaf_at_position(parser, params))
+            }
+            "GetFields" => self.dispatch(req.params, |params| handlers::get_fields(parser, params)),
+            "GetAstForSelection" => {
+                self.dispatch(req.params, |params| handlers::get_ast_for_selection(parser, params))
+            }
+            "GetNextError" => {
+                self.dispatch(req.params, |params| handlers::get_next_error(parser, params))
+            }
+            "GetPrevError" => {
+                self.dispatch(req.params, |params| handlers::get_prev_error(parser, params))
+            }
+            "NodesForRanges" => {
+                self.dispatch(req.params, |params| handlers::nodes_for_ranges(parser, params))
+            }
+            "GetSiblingsInRange" => {
+                self.dispatch(req.params, |params| handlers::get_siblings_in_range(parser, params))
+            }
+            "GetWhitespaceSensitiveRange" => self.dispatch(req.params, |params| {
+                handlers::get_whitespace_sensitive_range(parser, params)
+            }),
+            "GetInjectionRegions" => {
+                self.dispatch(req.params, |params| handlers::get_injection_regions(parser, params))
+            }
+            "GetAstDiffSummary" => {
+                self.dispatch(req.params, |params| handlers::get_ast_diff_summary(parser, params))
+            }
+            "GetSExpForKinds" => {
+                let max_depth = self.config.max_depth;
+                self.dispatch(req.params, |params| {
+                    handlers::get_sexp_for_kinds(parser, params, max_depth)
+                })
+            }
+            "FindByText" => {
+                self.dispatch(req.params, |params| handlers::find_by_text(parser, params))
+            }
+            "GetAstAsDot" => {
+                self.dispatch(req.params, |params| handlers::get_ast_as_dot(parser, params))
+            }
+            "SemanticTokens" => {
+                self.dispatch(req.params, |params| handlers::semantic_tokens(parser, params))
+            }
+            "GetScopeMap" => self.dispatch(req.params, handlers::get_scope_map),
+            "GetSupertypes" => self.dispatch(req.params, handlers::get_supertypes),
+            "WarmUp" => {
+                let workers = self.config.workers;
+                self.dispatch(req.params, |params| {
+                    handlers::warm_up(&self.parsers, params, workers)
+                })
+            }
+            "ReloadGrammar" => self.dispatch(req.params, handlers::reload_grammar),
+            "Health" => Ok(serde_json::to_value(handlers::health(parser)).unwrap()),
+            "Stats" => Ok(serde_json::to_value(self.stats()).unwrap()),
+            "Reset" => Ok(serde_json::to_value(self.reset(parser)).unwrap()),
+            "describe" => Ok(serde_json::to_value(describe::catalog()).unwrap()),
+            _ => {
+                log::warn!("got invalid method: {}", req.method);
+                Err(ResponseError {
+                    code: ErrorCode::MethodNotFound as i32,
+                    message: "invalid method".to_string(),
+                    data: None,
+                })
+            }
+        }
+    }
+}
+
+/// The response to a request that arrives after `shutdown`.
+pub fn shutting_down(id: RequestId) -> Response {
+    Response::new_err(id, ErrorCode::InvalidRequest as i32, "server is shutting down".to_string())
+}
+
+/// The response to a request cancelled before it finished.
+pub fn cancelled(id: RequestId) -> Response {
+    Response::new_err(id, ErrorCode::RequestCancelled as i32, "request cancelled".to_string())
+}
+
+/// The response to a request whose handler panicked.
+pub fn internal_error(id: RequestId) -> Response {
+    Response::new_err(id, ErrorCode::InternalError as i32, "internal error".to_string())
+}
+
+/// The response to a request reusing the id of one still in flight.
+pub fn duplicate_request_id(id: RequestId) -> Response {
+    Response::new_err(id, ErrorCode::InvalidRequest as i32, "duplicate request id".to_string())
+}
+
+pub fn into_response(id: RequestId, result: Result<serde_json::Value, ResponseError>) -> Response {
+    match result {
+        Ok(result) => Response { id, result: Some(result), error: None, meta: None },
+        Err(error) => Response { id, result: None, error: Some(error), meta: None },
+    }
+}
+
+/// Deserializes `params`, runs `handler` on them and serializes its result.
+pub fn dispatch<P, R>(
+    params: serde_json::Value,
+    handler: impl FnOnce(P) -> Result<R, ResponseError>,
+) -> Result<serde_json::Value, ResponseError>
+where
+    P: DeserializeOwned,
+    R: Serialize,
+{
+    let params = serde_json::from_value(params)
+        .map_err(|err| handlers::error(&format!("invalid params: {err}")))?;
+    let result = handler(params)?;
+    Ok(serde_json::to_value(result).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// `server`'s response to `method` with `params`.
+    fn call(server: &AstServer, method: &str, params: serde_json::Value) -> Response {
+        let req = Request::new(RequestId::from(1), method.to_string(), params);
+        server.handle_request(&mut Parser::new(), req).0
+    }
+
+    fn result(resp: Response) -> serde_json::Value {
+        resp.into_result().unwrap()
+    }
+
+    /// A directory of its own under the system temp directory, holding
+    /// `name` with `contents`.
+    fn temp_file(test: &str, name: &str, contents: &str) -> (PathBuf, PathBuf) {
+        let dir = env::temp_dir().join(format!("ast-rs-{}-{test}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dir = fs::canonicalize(dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn read_file_paths_replaces_file_path_with_code() {
+        let (dir, path) = temp_file("read", "a.py", "x = 1\n");
+        let mut params = serde_json::json!({ "language": "python", "filePath": path });
+        read_file_paths(&mut params, &[dir], false, &MemoryBudget::new(None)).unwrap();
+        assert_eq!(params, serde_json::json!({ "language": "python", "code": "x = 1\n" }));
+    }
+
+    #[test]
+    fn read_file_paths_rejects_files_larger_than_the_budget_left() {
+        let (dir, path) = temp_file("budget", "a.py", "x = 1\n");
+        let budget = MemoryBudget::new(Some(8));
+        let mut reservation = budget.reserve();
+        assert!(reservation.try_grow(4));
+        let mut params = serde_json::json!({ "language": "python", "filePath": path });
+        let err = read_file_paths(&mut params, &[dir], false, &budget).unwrap_err();
+        assert_eq!(err.message, "server busy");
+        assert!(params.get("code").is_none());
+    }
+
+    #[test]
+    fn read_file_paths_holds_the_files_read_against_the_budget() {
+        let (dir, path) = temp_file("reserve", "a.py", "x = 1\n");
+        let budget = MemoryBudget::new(Some(8));
+        let read = |budget| {
+            let mut params = serde_json::json!({ "language": "python", "filePath": path });
+            read_file_paths(&mut params, std::slice::from_ref(&dir), false, budget)
+        };
+        let reservation = read(&budget).unwrap();
+        assert_eq!(budget.in_use(), 6);
+        assert_eq!(read(&budget).unwrap_err().message, "server busy");
+        drop(reservation);
+        assert_eq!(budget.in_use(), 0);
+    }
+
+    #[test]
+    fn read_file_paths_rejects_paths_outside_the_allowed_directories() {
+        let (_, path) = temp_file("outside", "a.py", "x = 1\n");
+        let (allowed, _) = temp_file("allowed", "b.py", "");
+        let mut params = serde_json::json!({ "language": "python", "filePath": path });
+        let budget = MemoryBudget::new(None);
+        let err = read_file_paths(&mut params, &[allowed], false, &budget).unwrap_err();
+        assert!(err.message.ends_with("is outside the allowed directories"), "{}", err.message);
+    }
+
+    #[test]
+    fn memory_budget_gives_bytes_back_when_reservations_drop() {
+        let budget = MemoryBudget::new(Some(10));
+        let mut first = budget.reserve();
+        assert!(first.try_grow(6));
+        assert!(!budget.reserve().try_grow(5));
+        assert!(budget.reserve().try_grow(4));
+        first.grow(6);
+        assert_eq!(budget.in_use(), 12);
+        assert!(!budget.reserve().try_grow(0));
+        drop(first);
+        assert_eq!(budget.in_use(), 0);
+        assert!(budget.reserve().try_grow(10));
+        assert!(MemoryBudget::new(None).reserve().try_grow(usize::MAX));
+    }
+
+    #[test]
+    fn reset_empties_the_caches() {
+        let server = AstServer::new(AstServerConfig::default());
+        let code = "let x = 1;\n";
+        let cursor = json!({ "line": 0, "character": 4 });
+        let params = json!({ "language": "javascript", "code": code, "cursorPosition": cursor });
+        result(call(&server, "ParseAstInRange", params));
+        let params = json!({
+            "language": "javascript",
+            "code": code,
+            "query": "(identifier) @id",
+            "documentUri": "file:///a.js",
+        });
+        result(call(&server, "RunQuery", params));
+
+        assert_eq!(result(call(&server, "Reset", json!(null))), json!({ "evicted": 2 }));
+        assert_eq!(result(call(&server, "Reset", json!(null))), json!({ "evicted": 0 }));
+    }
+
+    #[test]
+    fn responses_carry_meta_once_enabled() {
+        let server = AstServer::new(AstServerConfig::default());
+        assert_eq!(call(&server, "Health", json!(null)).meta, None);
+        server.set_response_meta(true);
+        let meta = call(&server, "Health", json!(null)).meta.unwrap();
+        assert_eq!(meta["serverVersion"], env!("CARGO_PKG_VERSION"));
+        assert!(meta["grammarVersions"]["python"].is_u64());
+        // Errors carry it too.
+        let resp = call(&server, "NoSuchMethod", json!(null));
+        assert!(resp.error.is_some());
+        assert_eq!(resp.meta.as_ref(), Some(&meta));
+    }
+
+    #[test]
+    fn strict_params_rejects_unknown_fields_at_any_depth() {
+        let config = AstServerConfig { strict_params: true, ..Default::default() };
+        let strict = AstServer::new(config);
+        let lenient = AstServer::new(AstServerConfig::default());
+        let parse = |server: &AstServer, cursor: serde_json::Value, extra: serde_json::Value| {
+            let mut params = json!({ "language": "python", "code": "x = 1\n" });
+            params["cursorPosition"] = cursor;
+            params.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+            call(server, "ParseAstInRange", params)
+        };
+        let cursor = json!({ "line": 0, "character": 0 });
+        // Fields flattened in from the options are known.
+        assert!(parse(&strict, cursor.clone(), json!({ "includeRoot": true })).error.is_none());
+        for (cursor, extra) in [
+            (cursor.clone(), json!({ "bogus": 1 })),
+            (json!({ "line": 0, "character": 0, "bogus": 1 }), json!({})),
+        ] {
+            let err = parse(&strict, cursor.clone(), extra.clone()).error.unwrap();
+            assert!(err.message.starts_with("invalid params: unknown field `bogus`"), "{err:?}");
+            assert!(parse(&lenient, cursor, extra).error.is_none());
+        }
+    }
+
+    #[test]
+    fn complete_delivers_responses_to_the_caller_waiting_on_their_id() {
+        let server = AstServer::new(AstServerConfig::default());
+        let waiter = server.expect_response(RequestId::from(5));
+        assert!(!server.complete(Response::new_ok(RequestId::from(6), ())));
+        assert!(server.complete(Response::new_ok(RequestId::from(5), "done")));
+        let resp = waiter.try_recv().unwrap();
+        assert_eq!(resp.into_result::<String>().unwrap(), "done");
+        // Each waiter gets one response.
+        assert!(!server.complete(Response::new_ok(RequestId::from(5), ())));
+    }
+
+    #[test]
+    fn file_path_stands_in_for_code_inside_the_allowed_directories() {
+        let (dir, path) = temp_file("request", "a.py", "x = 1  # one\n");
+        let config = AstServerConfig { allowed_paths: vec![dir], ..Default::default() };
+        let server = AstServer::new(config);
+        let params = json!({ "language": "python", "filePath": path });
+        let comments = result(call(&server, "GetComments", params.clone()));
+        assert_eq!(comments.as_array().unwrap().len(), 1);
+
+        let mut both = params.clone();
+        both["code"] = json!("");
+        let err = call(&server, "GetComments", both).error.unwrap();
+        assert_eq!(err.message, "code and filePath are mutually exclusive");
+        let default = AstServer::new(AstServerConfig::default());
+        let err = call(&default, "GetComments", params).error.unwrap();
+        assert_eq!(err.message, "filePath is disabled on this server");
+    }
+
+    #[test]
+    fn streamed_parse_many_matches_reading_the_file_whole() {
+        // Several chunks long, with an error in the last one.
+        let code = format!("{}y = )\n", "x = 1\n".repeat(20_000));
+        let (dir, path) = temp_file("stream", "big.py", &code);
+        let config = AstServerConfig { allowed_paths: vec![dir], ..Default::default() };
+        let server = AstServer::new(config);
+        let parse_many = |stream: bool| {
+            let file = json!({ "id": "big", "language": "python", "filePath": path });
+            let params = json!({ "files": [file], "mode": "errors", "stream": stream });
+            result(call(&server, "ParseMany", params))
+        };
+        let streamed = parse_many(true);
+        assert_eq!(streamed, parse_many(false));
+        assert!(streamed[0]["error"].is_null(), "{streamed}");
+        assert!(!streamed[0]["validation"].is_null(), "{streamed}");
+    }
+
+    #[test]
+    fn streamed_parse_many_needs_utf8_positions() {
+        let (dir, path) = temp_file("stream-encoding", "a.py", "x = 1\n");
+        let config = AstServerConfig { allowed_paths: vec![dir], ..Default::default() };
+        let server = AstServer::new(config);
+        server.set_position_encoding(PositionEncoding::Utf16);
+        let parse_many = |stream: bool| {
+            let file = json!({ "id": "a", "language": "python", "filePath": path });
+            call(&server, "ParseMany", json!({ "files": [file], "stream": stream }))
+        };
+        let err = parse_many(true).error.unwrap();
+        assert_eq!(err.message, "stream requires the utf-8 position encoding");
+        assert!(parse_many(false).error.is_none());
+    }
+
+    #[test]
+    fn normalize_newlines_rewrites_crlf_and_cr_when_asked() {
+        let mut params = json!({ "code": "a\r\nb\rc\n", "normalizeNewlines": true });
+        normalize_newlines(&mut params);
+        assert_eq!(params, json!({ "code": "a\nb\nc\n" }));
+
+        let file = json!({ "id": "a", "code": "a\r\n" });
+        let mut params = json!({ "files": [file.clone()], "normalizeNewlines": true });
+        normalize_newlines(&mut params);
+        assert_eq!(params["files"][0]["code"], "a\n");
+
+        let mut params = json!({ "code": "a\r\n", "normalizeNewlines": false });
+        normalize_newlines(&mut params);
+        assert_eq!(params, json!({ "code": "a\r\n" }));
+    }
+
+    #[test]
+    fn every_method_in_the_catalog_is_routed() {
+        let server = AstServer::new(AstServerConfig::default());
+        for method in describe::catalog().methods {
+            // Answered by the main loop, ahead of the queue.
+            if method.method == "CancelAll" {
+                continue;
+            }
+            let resp = call(&server, method.method, json!({}));
+            if let Some(err) = resp.error {
+                assert_ne!(err.code, ErrorCode::MethodNotFound as i32, "{}", method.method);
+            }
+        }
+    }
+
+    #[test]
+    fn replace_nul_bytes_turns_nul_into_spaces_when_asked() {
+        let mut params = json!({ "code": "a\0b", "replaceNulBytes": true });
+        replace_nul_bytes(&mut params);
+        assert_eq!(params, json!({ "code": "a b" }));
+
+        let mut params = json!({ "oldCode": "\0", "newCode": "a\0", "replaceNulBytes": true });
+        replace_nul_bytes(&mut params);
+        assert_eq!(params, json!({ "oldCode": " ", "newCode": "a " }));
+
+        let mut params = json!({ "files": [{ "code": "\0a" }], "replaceNulBytes": true });
+        replace_nul_bytes(&mut params);
+        assert_eq!(params["files"][0]["code"], " a");
+
+        let mut params = json!({ "code": "a\0b", "replaceNulBytes": false });
+        replace_nul_bytes(&mut params);
+        assert_eq!(params, json!({ "code": "a\0b" }));
+    }
+
+    #[test]
+    fn code_with_a_nul_byte_is_rejected_unless_replaced() {
+        let server = AstServer::new(AstServerConfig::default());
+        let params = json!({ "language": "python", "code": "x = 1\0# one\n" });
+        let err = call(&server, "GetComments", params.clone()).error.unwrap();
+        assert_eq!(err.message, "code has a NUL byte at offset 5; set replaceNulBytes to parse it");
+
+        let mut replaced = params;
+        replaced["replaceNulBytes"] = json!(true);
+        let comments = result(call(&server, "GetComments", replaced));
+        assert_eq!(comments.as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn streamed_files_with_a_nul_byte_are_rejected() {
+        let (dir, path) = temp_file("nul", "a.py", "x = 1\n\0y = 2\n");
+        let config = AstServerConfig { allowed_paths: vec![dir], ..Default::default() };
+        let server = AstServer::new(config);
+        let file = json!({ "id": "a", "language": "python", "filePath": path });
+        let params = json!({ "files": [file], "mode": "errors", "stream": true });
+        let streamed = result(call(&server, "ParseMany", params));
+        let err = streamed[0]["error"].to_string();
+        assert!(err.contains("code has a NUL byte at offset 6"), "{streamed}");
+    }
+}
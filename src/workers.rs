@@ -0,0 +1,500 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ops::{Deref, DerefMut},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use tree_sitter::Parser;
+
+use crate::handlers;
+use crate::language::Language;
+use crate::msg::{Message, Request, RequestId, Response};
+use crate::parser_pool::PooledParser;
+use crate::server::{
+    cancelled, duplicate_request_id, internal_error, into_response, AstServer, QueuePolicy,
+    Reservation,
+};
+
+/// Threads handling requests off a bounded queue, each checking a parser out
+/// of the server's `ParserPool` per request. Responses go straight to
+/// `responses`, so they may be sent in a different order than the requests
+/// arrived.
+pub struct WorkerPool {
+    server: Arc<AstServer>,
+    jobs: Sender<Request>,
+    /// Keeps the queue connected even with no workers taking from it, so
+    /// `submit` fills it rather than failing outright.
+    queue: Receiver<Request>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Starts the workers configured for `server`.
+    pub fn spawn(server: Arc<AstServer>, responses: Sender<Message>) -> WorkerPool {
+        let config = server.config();
+        let (jobs, queue) = bounded::<Request>(config.queue_depth);
+        let limits = Arc::new(LanguageLimits::new(config.language_permits.clone()));
+        let workers = (0..config.workers)
+            .map(|_| {
+                let server = Arc::clone(&server);
+                let queue = queue.clone();
+                let responses = responses.clone();
+                let limits = Arc::clone(&limits);
+                thread::spawn(move || {
+                    for req in queue {
+                        // A request left in its language's backlog is run by
+                        // the worker freeing the next permit.
+                        let mut next = limits.admit(req);
+                        while let Some(req) = next {
+                            server.dequeued();
+                            let language = request_language(&req);
+                            // Frees the id even if the handler panics.
+                            let in_flight = InFlight { server: &server, id: req.id.clone() };
+                            let (resp, reservation) = run(&server, req);
+                            next = limits.release(language);
+                            // Before sending, so the client may reuse the id
+                            // as soon as it has the response.
+                            drop(in_flight);
+                            // The connection's writer takes messages off a
+                            // rendezvous channel, so the response stays
+                            // charged to the budget until it has it.
+                            let sent = responses.send(resp.into());
+                            drop(reservation);
+                            if sent.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        WorkerPool { server, jobs, queue, workers }
+    }
+
+    /// Queues `req`. If the queue is full, this either waits for room or
+    /// returns the "server busy" response to send instead, depending on the
+    /// configured `QueuePolicy`.
+    ///
+    /// A request reusing the id of one still in flight is rejected.
+    pub fn submit(&self, req: Request) -> Option<Response> {
+        if !self.server.started(&req.id) {
+            log::warn!("rejecting {}: request id {} is already in flight", req.method, req.id);
+            return Some(duplicate_request_id(req.id));
+        }
+        self.server.enqueued();
+        let rejected = match self.server.config().queue_policy {
+            QueuePolicy::Block => self.jobs.send(req).err().map(|err| err.into_inner()),
+            QueuePolicy::Reject => match self.jobs.try_send(req) {
+                Ok(()) => None,
+                Err(TrySendError::Full(req) | TrySendError::Disconnected(req)) => Some(req),
+            },
+        };
+        let req = rejected?;
+        self.server.dequeued();
+        self.server.finished(&req.id);
+        log::warn!("rejecting {}: request queue is full", req.method);
+        Some(into_response(req.id, Err(handlers::error("server busy"))))
+    }
+
+    /// Waits for the queued requests to be answered and the workers to exit.
+    pub fn join(self) {
+        drop(self.jobs);
+        drop(self.queue);
+        for worker in self.workers {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Answers `req` with a parser checked out of the server's pool. A handler
+/// that panics gets an internal error response instead of taking the worker
+/// down with it. The reservation comes from `AstServer::handle_request`.
+fn run(server: &AstServer, req: Request) -> (Response, Option<Reservation<'_>>) {
+    let flag = server.cancellation_flag(&req.id).unwrap_or_default();
+    let is_cancelled = || flag.load(Ordering::SeqCst) != 0;
+    if is_cancelled() {
+        return (cancelled(req.id), None);
+    }
+    let mut parser = Cancellable::new(server.parsers().checkout(request_language(&req)), &flag);
+    let (id, method) = (req.id.clone(), req.method.clone());
+    let handled = panic::catch_unwind(AssertUnwindSafe(|| server.handle_request(&mut parser, req)));
+    let (resp, reservation) = match handled {
+        Ok(handled) => handled,
+        Err(_) => {
+            log::error!("{method} panicked");
+            // Whatever the parser was doing is best not resumed.
+            parser.reset();
+            return (internal_error(id), None);
+        }
+    };
+    if is_cancelled() {
+        // A cancelled parse would otherwise resume on the parser's next use.
+        parser.reset();
+        return (cancelled(resp.id), None);
+    }
+    (resp, reservation)
+}
+
+/// A request's hold on its id in the server's in-flight set, released on
+/// drop, so even a panicking handler can't leave the id taken.
+struct InFlight<'a> {
+    server: &'a AstServer,
+    id: RequestId,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.server.finished(&self.id);
+    }
+}
+
+/// A pooled parser watching a request's cancellation flag until dropped, so
+/// it goes back to the pool without the flag however the request ends.
+struct Cancellable<'a> {
+    parser: PooledParser<'a>,
+}
+
+impl<'a> Cancellable<'a> {
+    fn new(mut parser: PooledParser<'a>, flag: &'a AtomicUsize) -> Cancellable<'a> {
+        // SAFETY: `flag` outlives the borrow, and the flag is unset on drop,
+        // before the parser goes back to the pool.
+        unsafe { parser.set_cancellation_flag(Some(flag)) };
+        Cancellable { parser }
+    }
+}
+
+impl Deref for Cancellable<'_> {
+    type Target = Parser;
+
+    fn deref(&self) -> &Parser {
+        &self.parser
+    }
+}
+
+impl DerefMut for Cancellable<'_> {
+    fn deref_mut(&mut self) -> &mut Parser {
+        &mut self.parser
+    }
+}
+
+impl Drop for Cancellable<'_> {
+    fn drop(&mut self) {
+        unsafe { self.parser.set_cancellation_flag(None) };
+    }
+}
+
+/// Caps how many requests for each language run at once, see
+/// `AstServerConfig::language_permits`. A request past its language's cap
+/// waits in that language's backlog instead of holding a worker, so
+/// requests for other languages keep running, and it runs on the worker
+/// that frees the next permit.
+struct LanguageLimits {
+    permits: BTreeMap<Language, usize>,
+    capped: Mutex<BTreeMap<Language, Capped>>,
+}
+
+/// The requests of one capped language: how many are running, and the
+/// ones waiting for a permit, oldest first.
+#[derive(Default)]
+struct Capped {
+    running: usize,
+    backlog: VecDeque<Request>,
+}
+
+impl LanguageLimits {
+    fn new(permits: BTreeMap<Language, usize>) -> LanguageLimits {
+        LanguageLimits { permits, capped: Mutex::default() }
+    }
+
+    /// Returns `req` if it may run now, taking a permit for its language,
+    /// or `None` once it is in the backlog. Requests for languages without
+    /// a cap, or without a language, always run.
+    fn admit(&self, req: Request) -> Option<Request> {
+        let language = match request_language(&req) {
+            Some(language) if self.permits.contains_key(&language) => language,
+            _ => return Some(req),
+        };
+        let mut capped = self.capped.lock().unwrap();
+        let capped = capped.entry(language).or_default();
+        if capped.running < self.permits[&language] {
+            capped.running += 1;
+            Some(req)
+        } else {
+            capped.backlog.push_back(req);
+            None
+        }
+    }
+
+    /// Gives back the permit of a request for `language` that finished.
+    /// Returns the oldest request in the language's backlog, which takes
+    /// the permit over, for the caller to run next.
+    fn release(&self, language: Option<Language>) -> Option<Request> {
+        let language = language.filter(|language| self.permits.contains_key(language))?;
+        let mut capped = self.capped.lock().unwrap();
+        let capped = capped.get_mut(&language)?;
+        let next = capped.backlog.pop_front();
+        if next.is_none() {
+            capped.running -= 1;
+        }
+        next
+    }
+}
+
+/// The `language` param of `req`, so the worker can check out a parser
+/// already set to it.
+fn request_language(req: &Request) -> Option<Language> {
+    req.params.get("language")?.as_str()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crossbeam_channel::unbounded;
+    use serde_json::json;
+
+    use crate::msg::ErrorCode;
+    use crate::parser_pool::ParserPool;
+    use crate::server::AstServerConfig;
+
+    use super::*;
+
+    fn request(id: i32, language: &str) -> Request {
+        let params = json!({ "language": language, "code": "x = 1  # a\n" });
+        Request::new(RequestId::from(id), "GetComments".to_string(), params)
+    }
+
+    #[test]
+    fn submit_rejects_requests_past_a_full_queue_under_the_reject_policy() {
+        // Without workers nothing leaves the queue.
+        let config = AstServerConfig {
+            workers: 0,
+            queue_depth: 1,
+            queue_policy: QueuePolicy::Reject,
+            ..Default::default()
+        };
+        let (responses, _) = unbounded();
+        let pool = WorkerPool::spawn(Arc::new(AstServer::new(config)), responses);
+        assert!(pool.submit(request(1, "python")).is_none());
+        let resp = pool.submit(request(2, "python")).unwrap();
+        assert_eq!(resp.error.unwrap().message, "server busy");
+        pool.join();
+    }
+
+    #[test]
+    fn workers_answer_every_request_queued_under_the_block_policy() {
+        let config = AstServerConfig { workers: 2, queue_depth: 1, ..Default::default() };
+        let (responses, answered) = unbounded();
+        let pool = WorkerPool::spawn(Arc::new(AstServer::new(config)), responses);
+        for id in 1..=6 {
+            assert!(pool.submit(request(id, "python")).is_none());
+        }
+        pool.join();
+        let mut ids: Vec<RequestId> = answered
+            .try_iter()
+            .map(|msg| match msg {
+                Message::Response(resp) => {
+                    assert!(resp.error.is_none(), "{resp:?}");
+                    resp.id
+                }
+                msg => panic!("expected a response, got {msg:?}"),
+            })
+            .collect();
+        ids.sort();
+        assert_eq!(ids, (1..=6).map(RequestId::from).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn submit_accepts_an_id_only_once_while_it_is_in_flight() {
+        let config = AstServerConfig {
+            workers: 0,
+            queue_depth: 8,
+            queue_policy: QueuePolicy::Reject,
+            ..Default::default()
+        };
+        let (responses, _) = unbounded();
+        let pool = WorkerPool::spawn(Arc::new(AstServer::new(config)), responses);
+        let rejections: Vec<Option<Response>> = thread::scope(|scope| {
+            let submits: Vec<_> =
+                (0..8).map(|_| scope.spawn(|| pool.submit(request(7, "python")))).collect();
+            submits.into_iter().map(|submit| submit.join().unwrap()).collect()
+        });
+        assert_eq!(rejections.iter().filter(|resp| resp.is_none()).count(), 1);
+        for resp in rejections.into_iter().flatten() {
+            assert_eq!(resp.error.unwrap().message, "duplicate request id");
+        }
+        pool.join();
+    }
+
+    #[test]
+    fn ids_can_be_reused_once_answered() {
+        let config = AstServerConfig { workers: 1, ..Default::default() };
+        let (responses, answered) = unbounded();
+        let pool = WorkerPool::spawn(Arc::new(AstServer::new(config)), responses);
+        for _ in 0..2 {
+            assert!(pool.submit(request(1, "python")).is_none());
+            match answered.recv().unwrap() {
+                Message::Response(resp) => assert!(resp.error.is_none(), "{resp:?}"),
+                msg => panic!("expected a response, got {msg:?}"),
+            }
+        }
+        pool.join();
+    }
+
+    #[test]
+    fn cancel_all_answers_every_cancelled_request_with_request_cancelled() {
+        let config = AstServerConfig { workers: 1, ..Default::default() };
+        let server = Arc::new(AstServer::new(config));
+        let (responses, answered) = unbounded();
+        let pool = WorkerPool::spawn(Arc::clone(&server), responses);
+        for id in 1..=20 {
+            assert!(pool.submit(request(id, "python")).is_none());
+        }
+        let cancelled = server.cancel_all().cancelled;
+        assert_eq!(server.cancel_all().cancelled, 0);
+        pool.join();
+        let codes: Vec<Option<i32>> = answered
+            .try_iter()
+            .map(|msg| match msg {
+                Message::Response(resp) => resp.error.map(|err| err.code),
+                msg => panic!("expected a response, got {msg:?}"),
+            })
+            .collect();
+        assert_eq!(codes.len(), 20);
+        let request_cancelled = Some(ErrorCode::RequestCancelled as i32);
+        assert_eq!(codes.iter().filter(|code| **code == request_cancelled).count(), cancelled);
+        assert!(codes.iter().all(|code| code.is_none() || *code == request_cancelled));
+    }
+
+    #[test]
+    fn language_limits_defer_requests_past_the_cap() {
+        let limits = LanguageLimits::new(BTreeMap::from([(Language::Cpp, 2)]));
+        assert!(limits.admit(request(1, "python")).is_some());
+        assert!(limits.admit(request(2, "cobol")).is_some());
+        let admitted: Vec<bool> =
+            (3..=6).map(|id| limits.admit(request(id, "cpp")).is_some()).collect();
+        assert_eq!(admitted, [true, true, false, false]);
+        // The backlog takes the permits over in order.
+        let next = || limits.release(Some(Language::Cpp)).map(|req| req.id);
+        assert_eq!(next(), Some(RequestId::from(5)));
+        assert_eq!(next(), Some(RequestId::from(6)));
+        assert_eq!((next(), next()), (None, None));
+        assert_eq!(limits.capped.lock().unwrap()[&Language::Cpp].running, 0);
+        assert!(limits.release(Some(Language::Python)).is_none());
+    }
+
+    #[test]
+    fn language_limits_cap_concurrent_requests_per_language() {
+        let limits = LanguageLimits::new(BTreeMap::from([(Language::Cpp, 2)]));
+        let running = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        let ran = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..100 {
+                        let mut next = limits.admit(request(1, "cpp"));
+                        while next.is_some() {
+                            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                            most.fetch_max(now, Ordering::SeqCst);
+                            thread::yield_now();
+                            running.fetch_sub(1, Ordering::SeqCst);
+                            ran.fetch_add(1, Ordering::SeqCst);
+                            next = limits.release(Some(Language::Cpp));
+                        }
+                    }
+                });
+            }
+        });
+        assert!(most.load(Ordering::SeqCst) <= 2);
+        assert_eq!(ran.load(Ordering::SeqCst), 800);
+        assert_eq!(limits.capped.lock().unwrap()[&Language::Cpp].running, 0);
+    }
+
+    #[test]
+    fn capped_requests_wait_without_holding_a_worker() {
+        // Fewer workers than queued C++ requests, with one permit for C++.
+        let config = AstServerConfig {
+            workers: 2,
+            language_permits: BTreeMap::from([(Language::Cpp, 1)]),
+            ..Default::default()
+        };
+        let (responses, answered) = unbounded();
+        let pool = WorkerPool::spawn(Arc::new(AstServer::new(config)), responses);
+        let cpp = "int f() { return 1; } // a\n".repeat(5_000);
+        for id in 1..=4 {
+            let params = json!({ "language": "cpp", "code": cpp });
+            let req = Request::new(RequestId::from(id), "GetComments".to_string(), params);
+            assert!(pool.submit(req).is_none());
+        }
+        for id in 5..=6 {
+            assert!(pool.submit(request(id, "python")).is_none());
+        }
+        pool.join();
+        let order: Vec<RequestId> = answered
+            .try_iter()
+            .map(|msg| match msg {
+                Message::Response(resp) => resp.id,
+                msg => panic!("expected a response, got {msg:?}"),
+            })
+            .collect();
+        let position = |id: i32| order.iter().position(|answered| *answered == id.into()).unwrap();
+        // The Python requests ran beside the first C++ one, not after the
+        // C++ backlog.
+        let mut cpp: Vec<usize> = (1..=4).map(position).collect();
+        cpp.sort();
+        assert!(position(5) < cpp[1] && position(6) < cpp[1], "{order:?}");
+    }
+
+    #[test]
+    fn in_flight_frees_the_id_when_the_handler_panics() {
+        let server = AstServer::new(AstServerConfig::default());
+        let id = RequestId::from(1);
+        assert!(server.started(&id));
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _in_flight = InFlight { server: &server, id: id.clone() };
+            panic!("handler failed");
+        }));
+        assert!(panicked.is_err());
+        assert!(server.started(&id));
+    }
+
+    #[test]
+    fn cancellable_unsets_the_flag_when_the_handler_panics() {
+        let parsers = ParserPool::new();
+        let flag = AtomicUsize::new(1);
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _parser = Cancellable::new(parsers.checkout(Some(Language::Python)), &flag);
+            panic!("handler failed");
+        }));
+        assert!(panicked.is_err());
+        // Long enough for a parser still holding the set flag to notice it.
+        let code = "x = 1\n".repeat(200);
+        assert!(parsers.checkout(Some(Language::Python)).parse(&code, None).is_some());
+    }
+
+    #[test]
+    fn responses_stay_charged_to_the_budget_until_the_writer_takes_them() {
+        let server = Arc::new(AstServer::new(AstServerConfig { workers: 1, ..Default::default() }));
+        // A rendezvous channel, like the one to the connection's writer.
+        let (responses, answered) = bounded(0);
+        let pool = WorkerPool::spawn(Arc::clone(&server), responses);
+        assert!(pool.submit(request(1, "python")).is_none());
+        let started = Instant::now();
+        while server.stats().memory_in_use == 0 {
+            assert!(started.elapsed() < Duration::from_secs(5), "never charged");
+            thread::yield_now();
+        }
+        thread::sleep(Duration::from_millis(20));
+        assert!(server.stats().memory_in_use > 0);
+        assert!(matches!(answered.recv().unwrap(), Message::Response(_)));
+        pool.join();
+        assert_eq!(server.stats().memory_in_use, 0);
+    }
+}